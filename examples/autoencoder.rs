@@ -59,7 +59,7 @@ fn main() -> Result<(), String> {
     // if load {
     //     model.load(model_path).unwrap();
     // }
-    let mut optim = adam(&[&mut model.l1.weights, &mut model.l2.weights, &mut model.l3.weights, &mut model.l4.weights], 0.001);
+    let mut optim = adam([&mut model.l1.weights, &mut model.l2.weights, &mut model.l3.weights, &mut model.l4.weights], 0.001);
     let batch_size = 1;
     let (mut img_batched, _, _, _) = fetch_mnist(batch_size, false);
     let sdl_context = sdl2::init()?;
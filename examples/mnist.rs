@@ -10,14 +10,17 @@ pub fn main() {
     let training = true;
     let mut model = ConvNet::default();
     if training {
-        let optim = adam(&[&mut model.c1.weights, &mut model.c2.weights, &mut model.l1.weights], 0.001);
+        let optim = adam(model.parameters(), 0.001);
         let batch_size = 128;
         train(&model, optim, batch_size, 60000 / batch_size).unwrap();
+        model.save("mnist.safetensors").unwrap();
     } else {
+        model.load("mnist.safetensors", true).unwrap();
         eval(&model).unwrap();
     }
 }
 
+#[derive(Module)]
 pub struct ConvNet {
     pub c1: Conv2d,
     pub c2: Conv2d,
@@ -46,22 +49,8 @@ impl ConvNet {
         x = self.l1.call(&x).log_softmax();
         x
     }
-
-    // fn save(&self, path: &str) -> Result<(), safetensors::SafeTensorError> {
-    //     Tensor::to_safetensor(
-    //         &[("c1", &self.c1), ("c2", &self.c2), ("l1", &self.l1)],
-    //         path,
-    //     )?;
-    //     Ok(())
-    // }
-    //
-    // fn load(&mut self, path: &str) -> Result<(), safetensors::SafeTensorError> {
-    //     self.c1.from_file("c1", path)?;
-    //     self.c2.from_file("c2", path)?;
-    //     self.l1.from_file("l1", path)?;
-    //     Ok(())
-    // }
 }
+
 fn fetch_mnist_shuffled(
     batch_size: usize,
 ) -> (Vec<Vec<f32>>, Vec<Vec<f32>>, Vec<Vec<f32>>, Vec<Vec<f32>>) {
@@ -130,17 +119,25 @@ fn train<Optim: Optimizer>(
     let mut pb = tqdm!(total = epoch);
     pb.set_description(format!("loss: {:.2} accuracy {:.2}", 0, 0));
     pb.refresh()?;
-    for i in 0..epoch {
-        let s = std::time::Instant::now();
-        let x = Tensor::from(&*img_batched[i]).reshape([batch_size, 1, 28, 28]);
-        let y = Tensor::from(&*lbl_batched[i]).reshape([batch_size]);
-        let out = model.forward(&x);
-        let mut loss = out.sparse_categorical_crossentropy(&y) / batch_size as f32;
+    // After warming up, steps replay the recorded kernels instead of rebuilding the graph
+    let mut step = TensorJit::new(|t: &[Tensor]| {
+        let (x, y) = (&t[0], &t[1]);
+        let out = model.forward(x);
+        let mut loss = out.sparse_categorical_crossentropy(y) / batch_size as f32;
         optim.zero_grad();
         loss.backward();
         optim.step();
         let pred = out.detach().argmax(-1);
         let accuracy = (pred._eq(&y.detach())).mean([], false);
+        vec![loss, accuracy]
+    });
+    for i in 0..epoch {
+        let s = std::time::Instant::now();
+        let x = Tensor::from(&*img_batched[i]).reshape([batch_size, 1, 28, 28]);
+        let y = Tensor::from(&*lbl_batched[i]).reshape([batch_size]);
+        let [loss, accuracy] = &step.call(&[x, y])[..] else {
+            unreachable!()
+        };
         pb.set_description(format!(
             "loss: {:.2?} accuracy {:.2?}",
             loss.to_vec()[0],
@@ -155,6 +152,7 @@ fn train<Optim: Optimizer>(
 }
 
 fn eval(model: &ConvNet) -> Result<(), Box<dyn std::error::Error>> {
+    let _no_grad = Tensor::no_grad();
     let batch_size = 128;
     let (_, _, img_batched, lbl_batched) = fetch_mnist_shuffled(batch_size);
     let mut pb = tqdm!(total = 50);
@@ -164,8 +162,8 @@ fn eval(model: &ConvNet) -> Result<(), Box<dyn std::error::Error>> {
         let x = Tensor::from(&*img_batched[i]).reshape([batch_size, 1, 28, 28]);
         let y = Tensor::from(&*lbl_batched[i]).reshape([batch_size]);
         let out = model.forward(&x);
-        let pred = out.detach().argmax(-1);
-        let accuracy = (pred._eq(&y.detach())).mean([], false);
+        let pred = out.argmax(-1);
+        let accuracy = (pred._eq(&y)).mean([], false);
         pb.set_description(format!("eval accuracy {:.2?}", accuracy.to_vec()[0]));
         pb.update(1)?;
     }
@@ -14,6 +14,7 @@ pub fn main() {
     //     println!("couldn't find generator safetensor, ignoring...")
     // }
     if training {
+        Tensor::set_training(true);
         // if discriminator
         //     .load("./models/mnistgan_disc.safetensors")
         //     .is_err()
@@ -25,10 +26,10 @@ pub fn main() {
         let n_steps = 20;
         let (train_img, _, _, _) = fetch_mnist_shuffled(batch_size);
         let mut gen_optim =
-            adam_with(&[&mut generator.l1, &mut generator.l2, &mut generator.l4, &mut generator.l4], &[0.0002, 0.5]);
+            adam_with([&mut generator.l1, &mut generator.l2, &mut generator.l3, &mut generator.l4], &[0.0002, 0.5]);
 
         let mut disc_optim =
-            adam_with(&[&mut discriminator.l1, &mut discriminator.l2, &mut discriminator.l3, &mut discriminator.l4], &[0.0002, 0.5]);
+            adam_with([&mut discriminator.l1, &mut discriminator.l2, &mut discriminator.l3, &mut discriminator.l4], &[0.0002, 0.5]);
 
         let mut pb = tqdm!(total = epochs);
         pb.set_description(format!(
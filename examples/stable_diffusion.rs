@@ -62,9 +62,9 @@ impl ResnetBlock {
     fn new(in_channels: usize, out_channels: usize) -> Self {
         Self {
             norm1: GroupNorm::new(32, in_channels, None, None),
-            conv1: Conv2d::new(in_channels, out_channels, 3, None, [1], None, None, None),
+            conv1: Conv2d::new(in_channels, out_channels, 3, 1, [1], 1, None, None),
             norm2: GroupNorm::new(32, out_channels, None, None),
-            conv2: Conv2d::new(out_channels, out_channels, 3, None, [1], None, None, None),
+            conv2: Conv2d::new(out_channels, out_channels, 3, 1, [1], 1, None, None),
             nin_shortcut: if in_channels != out_channels {
                 Some(Conv2d::default(in_channels, out_channels, 1))
             } else {
@@ -127,16 +127,16 @@ impl Decoder {
             ];
             let mut upsample = vec![];
             if i != 0 {
-                upsample = vec![Conv2d::new(s.0, s.0, 3, None, [1], None, None, None)];
+                upsample = vec![Conv2d::new(s.0, s.0, 3, 1, [1], 1, None, None)];
             }
             arr.push((block, upsample))
         }
         Self {
-            conv_in: Conv2d::new(4, 512, 3, None, [1], None, None, None),
+            conv_in: Conv2d::new(4, 512, 3, 1, [1], 1, None, None),
             mid: Mid::new(512),
             up: arr,
             norm_out: GroupNorm::new(32, 128, None, None),
-            conv_out: Conv2d::new(128, 3, 3, None, [1], None, None, None),
+            conv_out: Conv2d::new(128, 3, 3, 1, [1], 1, None, None),
         }
     }
 
@@ -175,7 +175,7 @@ pub struct Encoder {
 impl Encoder {
     pub fn new() -> Self {
         let sz = [(128, 128), (128, 256), (256, 512), (512, 512)];
-        let conv_in = Conv2d::new(3, 128, 3, None, [1], None, None, None);
+        let conv_in = Conv2d::new(3, 128, 3, 1, [1], 1, None, None);
         let mut arr = vec![];
         for (i, s) in sz.iter().enumerate() {
             let block = vec![ResnetBlock::new(s.0, s.1), ResnetBlock::new(s.1, s.1)];
@@ -185,9 +185,9 @@ impl Encoder {
                     s.1,
                     s.1,
                     3,
-                    Some(2),
+                    2,
                     [0, 1, 0, 1],
-                    None,
+                    1,
                     None,
                     None,
                 )];
@@ -199,7 +199,7 @@ impl Encoder {
             mid: Mid::new(512),
             down: arr,
             norm_out: GroupNorm::new(32, 512, None, None),
-            conv_out: Conv2d::new(512, 8, 3, None, [1], None, None, None),
+            conv_out: Conv2d::new(512, 8, 3, 1, [1], 1, None, None),
         }
     }
 
@@ -257,10 +257,10 @@ impl ResBlock {
     fn new(channels: usize, emb_channels: usize, out_channels: usize) -> Self {
         Self {
             in_gn: GroupNorm::new(32, channels, None, None),
-            in_conv: Conv2d::new(channels, out_channels, 3, None, [1], None, None, None),
+            in_conv: Conv2d::new(channels, out_channels, 3, 1, [1], 1, None, None),
             emb_lin: Linear::new(emb_channels, out_channels, None),
             out_gn: GroupNorm::new(32, out_channels, None, None),
-            out_conv: Conv2d::new(out_channels, out_channels, 3, None, [1], None, None, None),
+            out_conv: Conv2d::new(out_channels, out_channels, 3, 1, [1], 1, None, None),
             conv_shortcut: if channels != out_channels {
                 Some(Conv2d::default(channels, out_channels, 1))
             } else {
@@ -461,7 +461,7 @@ pub struct Downsample {
 impl Downsample {
     fn new(channels: usize) -> Self {
         Self {
-            conv: Conv2d::new(channels, channels, 3, Some(2), [1], None, None, None),
+            conv: Conv2d::new(channels, channels, 3, 2, [1], 1, None, None),
         }
     }
 
@@ -477,7 +477,7 @@ pub struct Upsample {
 impl Upsample {
     fn new(channels: usize) -> Self {
         Self {
-            conv: Conv2d::new(channels, channels, 3, None, [1], None, None, None),
+            conv: Conv2d::new(channels, channels, 3, 1, [1], 1, None, None),
         }
     }
 
@@ -545,7 +545,7 @@ impl UNetModel {
             Linear::new(1280, 1280, None)],
 
             input_blocks: vec![
-                vec![Conv2d::new(4, 320, 3, None, [1], None, None, None).into()],
+                vec![Conv2d::new(4, 320, 3, 1, [1], 1, None, None).into()],
                 vec![ResBlock::new(320, 1280, 320).into(), SpatialTransformer::new(320, 768, 8, 40).into()],
                 vec![ResBlock::new(320, 1280, 320).into(), SpatialTransformer::new(320, 768, 8, 40).into()],
                 vec![Downsample::new(320).into()],
@@ -581,7 +581,7 @@ impl UNetModel {
             ],
 
             out_gn: GroupNorm::new(32, 320, None, None),
-            out_conv: Conv2d::new(320, 4, 3, None, [1], None, None, None),
+            out_conv: Conv2d::new(320, 4, 3, 1, [1], 1, None, None),
         }
     }
 
@@ -1067,15 +1067,12 @@ impl StableDiffusion {
     }
 }
 
-use memmap2::MmapOptions;
-use safetensors::SafeTensors;
-use std::fs::File;
+use std::collections::BTreeMap;
+use storm::nn::state::safe_load;
 
 #[rustfmt::skip]
 fn load_text_model(mut text_model: &mut CLIPTextTransformer, filename: impl AsRef<Path>) {
-    let file = File::open(filename).unwrap();
-    let buffer = unsafe { MmapOptions::new().map(&file).unwrap() };
-    let tensors = SafeTensors::deserialize(&buffer).unwrap();
+    let tensors = safe_load(filename).unwrap();
     text_model.embeddings.position_embedding.weight.assign_like(tt(&format!("text_model.embeddings.position_embedding.weight"), &tensors));
     text_model.embeddings.token_embedding.weight.assign_like(tt(&format!("text_model.embeddings.token_embedding.weight"), &tensors));
     for (i, l) in text_model.encoder.layers.iter_mut().enumerate() {
@@ -1103,9 +1100,7 @@ fn load_text_model(mut text_model: &mut CLIPTextTransformer, filename: impl AsRe
 
 #[rustfmt::skip]
 fn load_unet(mut unet: &mut UNetModel, filename: impl AsRef<Path>) {
-    let file = File::open(filename).unwrap();
-    let buffer = unsafe { MmapOptions::new().map(&file).unwrap() };
-    let tensors = SafeTensors::deserialize(&buffer).unwrap();
+    let tensors = safe_load(filename).unwrap();
     for (i, l) in unet.time_embedding.iter_mut().enumerate() {
         let i = i + 1;
         l.weights.assign_like(tt(&format!("time_embedding.linear_{i}.weight"), &tensors));
@@ -1128,15 +1123,15 @@ fn load_unet(mut unet: &mut UNetModel, filename: impl AsRef<Path>) {
     unet.out_gn.bias.as_mut().unwrap().assign_like(tt(&format!("conv_norm_out.bias"), &tensors));
 }
 
-fn tt(name: &str, tensors: &SafeTensors) -> Tensor {
+fn tt(name: &str, tensors: &BTreeMap<String, Tensor>) -> Tensor {
     println!("loading {name}");
-    let ret = Tensor::from_bytes(tensors.tensor(name).unwrap().data()).cast(float16).realize();
+    let ret = tensors[name].to(&DEVICE.name()).cast(float16).realize();
     //println!("data {:?}", ret.nd());
     ret
 }
 
 #[rustfmt::skip]
-fn load_blocks(block_name: &str, blocks: Vec<&mut UnetComponent>, tensors: &SafeTensors) {
+fn load_blocks(block_name: &str, blocks: Vec<&mut UnetComponent>, tensors: &BTreeMap<String, Tensor>) {
     let mut i = 0;
     let mut atn = 0;
     let mut res = 0;
@@ -1232,10 +1227,8 @@ fn load_blocks(block_name: &str, blocks: Vec<&mut UnetComponent>, tensors: &Safe
 
 #[rustfmt::skip]
 fn load_vae(mut model: &mut AutoencoderKL, filename: impl AsRef<Path>)  {
-    let file = File::open(filename).unwrap();
-    let buffer = unsafe { MmapOptions::new().map(&file).unwrap() };
-    let tensors = SafeTensors::deserialize(&buffer).unwrap();
-    fn load_resnet(name: &str, res: &mut ResnetBlock, tensors: &SafeTensors) {
+    let tensors = safe_load(filename).unwrap();
+    fn load_resnet(name: &str, res: &mut ResnetBlock, tensors: &BTreeMap<String, Tensor>) {
         res.conv1.weights.assign_like(tt(&format!("{name}.conv1.weight"), &tensors));
         res.conv1.bias = Some(tt(&format!("{name}.conv1.bias"), &tensors));
         res.conv2.weights.assign_like(tt(&format!("{name}.conv2.weight"), &tensors));
@@ -21,7 +21,7 @@ fn main() {
 
     // loss = (y - out).abs().sum() / y.numel()
     let mut model = Xornet::new();
-    let mut optim = adam(&[&mut model.l1, &mut model.l2], 0.01);
+    let mut optim = adam([&mut model.l1, &mut model.l2], 0.01);
     let x = Tensor::from([0., 0., 0., 1., 1., 0., 1., 1.]).reshape([4, 2]);
     let y = Tensor::from([0., 1., 1., 0.]).reshape([4, 1]);
     for i in 0..2000000 {
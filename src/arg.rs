@@ -18,9 +18,13 @@ pub enum Arg {
 
 impl Arg {
     pub fn to_dtype(&self) -> Dtype {
+        self.try_to_dtype().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_to_dtype(&self) -> Result<Dtype, StormError> {
         match self {
-            Arg::Dtype(d) => d.clone(),
-            t => panic!("Can not to_dtype() {t:?}"),
+            Arg::Dtype(d) => Ok(d.clone()),
+            t => Err(StormError::Internal(format!("Can not to_dtype() {t:?}"))),
         }
     }
 
@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::prelude::*;
+use crate::tensor::TensorId;
+use crate::tensor::mlops::Function;
+
+// Gradients of outputs with respect to inputs, each output weighted by its grad_output (None means
+// ones, which needs scalar outputs). Nothing is written to .grad. With create_graph the gradients
+// keep their graph and can be differentiated again, otherwise they are plain tensors. Inputs the
+// outputs don't depend on get zeros.
+pub fn grad(
+    outputs: &[&Tensor],
+    inputs: &[&Tensor],
+    grad_outputs: Option<&[&Tensor]>,
+    create_graph: bool,
+) -> Vec<Tensor> {
+    try_grad(outputs, inputs, grad_outputs, create_graph).unwrap_or_else(|e| panic!("{e}"))
+}
+
+pub fn try_grad(
+    outputs: &[&Tensor],
+    inputs: &[&Tensor],
+    grad_outputs: Option<&[&Tensor]>,
+    create_graph: bool,
+) -> Result<Vec<Tensor>, StormError> {
+    if let Some(gs) = grad_outputs
+        && gs.len() != outputs.len()
+    {
+        return Err(StormError::InvalidArgument {
+            op: "grad",
+            msg: format!("{} grad_outputs for {} outputs", gs.len(), outputs.len()),
+        });
+    }
+    let _no_grad = (!create_graph).then(Tensor::no_grad);
+    let mut grads = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+    for (i, out) in outputs.iter().enumerate() {
+        let g = match grad_outputs {
+            Some(gs) if gs[i].shape() != out.shape() => {
+                return Err(StormError::ShapeMismatch {
+                    op: "grad",
+                    lhs: out.shape().dims,
+                    rhs: gs[i].shape().dims,
+                });
+            }
+            Some(gs) => gs[i].clone(),
+            None if out.shape().len() == 1 && out.numel() == 1 => Tensor::_const(1f32),
+            None => {
+                return Err(StormError::InvalidArgument {
+                    op: "grad",
+                    msg: format!(
+                        "grad_outputs can only be left out for scalar outputs, got shape {}",
+                        out.shape()
+                    ),
+                });
+            }
+        };
+        accumulate(&mut grads, out.id, g);
+        Tensor::_deepwalk(out, &mut visited, &mut order);
+    }
+    // Every node comes after its parents, walking backwards a node's grad is complete when it's
+    // reached
+    for node in order.iter().rev() {
+        let Some(g) = grads.get(&node.id).cloned() else {
+            continue;
+        };
+        let ctx = node._ctx.as_ref().unwrap();
+        for (p, pg) in ctx.parents_ref().iter().zip(ctx.backward(&g)) {
+            if let Some(pg) = pg
+                && p.require_grad
+            {
+                assert!(
+                    p.shape() == pg.shape(),
+                    "grad shape must match tensor shape, {} != {}",
+                    pg.shape(),
+                    p.shape()
+                );
+                accumulate(&mut grads, p.id, pg);
+            }
+        }
+    }
+    Ok(v![grads.get(&x.id).cloned().unwrap_or_else(|| Tensor::zeros(x.shape())), for x in inputs])
+}
+
+fn accumulate(grads: &mut HashMap<TensorId, Tensor>, id: TensorId, g: Tensor) {
+    let g = match grads.remove(&id) {
+        Some(prev) => prev + g,
+        None => g,
+    };
+    grads.insert(id, g);
+}
+
+// Fresh leaves with the inputs' values, f sees these so the products are taken at the inputs
+fn leaves(inputs: &[&Tensor]) -> Vec<Tensor> {
+    v![{ let mut t = x.detach(); t.require_grad = true; t }, for x in inputs]
+}
+
+// Runs f at inputs and returns its output with the vector-Jacobian product v^T J for every input.
+// The products keep their graph through anything f closes over, so they can go into a loss like
+// a gradient penalty.
+pub fn vjp(
+    f: impl FnOnce(&[Tensor]) -> Tensor,
+    inputs: &[&Tensor],
+    v: &Tensor,
+) -> (Tensor, Vec<Tensor>) {
+    let xs = leaves(inputs);
+    let out = f(&xs);
+    let vjps = grad(&[&out], &v![x, for x in xs.iter()], Some(&[v]), true);
+    (out, vjps)
+}
+
+// Runs f at inputs and returns its output with the Jacobian-vector product J t, where the tangents
+// t line up with the inputs. It is the vjp of the vjp: v^T J is linear in v, so differentiating it
+// with respect to v along t gives J t.
+pub fn jvp(
+    f: impl FnOnce(&[Tensor]) -> Tensor,
+    inputs: &[&Tensor],
+    tangents: &[&Tensor],
+) -> (Tensor, Tensor) {
+    assert!(
+        inputs.len() == tangents.len(),
+        "jvp needs one tangent per input, got {} for {}",
+        tangents.len(),
+        inputs.len()
+    );
+    let xs = leaves(inputs);
+    let out = f(&xs);
+    let mut v = Tensor::zeros(out.shape());
+    v.require_grad = true;
+    let vjps = grad(&[&out], &v![x, for x in xs.iter()], Some(&[&v]), true);
+    let jvp = grad(&v![g, for g in vjps.iter()], &[&v], Some(tangents), true).remove(0);
+    (out, jvp)
+}
+
+// Runs the scalar function f at inputs and returns its output with the Hessian-vector product H v
+// for every input
+pub fn hvp(
+    f: impl FnOnce(&[Tensor]) -> Tensor,
+    inputs: &[&Tensor],
+    v: &[&Tensor],
+) -> (Tensor, Vec<Tensor>) {
+    let xs = leaves(inputs);
+    let out = f(&xs);
+    let xs = v![x, for x in xs.iter()];
+    let grads = grad(&[&out], &xs, None, true);
+    let hvps = grad(&v![g, for g in grads.iter()], &xs, Some(v), true);
+    (out, hvps)
+}
@@ -81,6 +81,27 @@ pub struct ConstBuffer {
     pub st: ShapeTracker,
 }
 
+// Random numbers counted from the seed and offset words in input idx, the same draw on every
+// backend. range is the [low, high) of randint, without it floats are uniform in [0, 1).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RandBuffer {
+    pub idx: usize,
+    pub range: Option<(isize, isize)>,
+    pub dtype: dtype::Dtype,
+    pub st: ShapeTracker,
+}
+
+// The source of a gather in input idx, read at the positions its index gives along one axis. st
+// points each element at position 0 of that axis, which has `size` elements `stride` apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GatherBuffer {
+    pub idx: usize,
+    pub stride: isize,
+    pub size: isize,
+    pub dtype: dtype::Dtype,
+    pub st: ShapeTracker,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct LocalBuffer {
     pub name: String,
@@ -99,6 +120,8 @@ impl core::fmt::Display for LocalBuffer {
 pub enum Buffers {
     MemBuffer(MemBuffer),
     ConstBuffer(ConstBuffer),
+    RandBuffer(RandBuffer),
+    GatherBuffer(GatherBuffer),
     LazyBuffer(LazyBuffer),
     LocalBuffer(LocalBuffer),
 }
@@ -108,6 +131,8 @@ impl Buffers {
         match self {
             Buffers::MemBuffer(b) => b.dtype.clone(),
             Buffers::ConstBuffer(b) => b.dtype.clone(),
+            Buffers::RandBuffer(b) => b.dtype.clone(),
+            Buffers::GatherBuffer(b) => b.dtype.clone(),
             Buffers::LazyBuffer(b) => b.dtype.clone(),
             Buffers::LocalBuffer(b) => b.dtype.clone(),
         }
@@ -117,6 +142,8 @@ impl Buffers {
         match self {
             Buffers::MemBuffer(b) => b.st.clone(),
             Buffers::ConstBuffer(b) => b.st.clone(),
+            Buffers::RandBuffer(b) => b.st.clone(),
+            Buffers::GatherBuffer(b) => b.st.clone(),
             Buffers::LazyBuffer(b) => b.st.clone(),
             Buffers::LocalBuffer(b) => panic!("Local buffer does not have shapetracker {b:?}"),
         }
@@ -125,6 +152,8 @@ impl Buffers {
     pub fn idx(&self) -> usize {
         match self {
             Buffers::MemBuffer(b) => b.idx,
+            Buffers::RandBuffer(b) => b.idx,
+            Buffers::GatherBuffer(b) => b.idx,
             t => panic!("{t:?} does not have a idx"),
         }
     }
@@ -151,6 +180,8 @@ macro_rules! BuffersFrom {
 
 BuffersFrom!(MemBuffer);
 BuffersFrom!(ConstBuffer);
+BuffersFrom!(RandBuffer);
+BuffersFrom!(GatherBuffer);
 BuffersFrom!(LazyBuffer);
 BuffersFrom!(LocalBuffer);
 
@@ -169,9 +200,9 @@ pub enum OptOps {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Opt {
-    op: OptOps,
-    axis: Option<isize>,
-    amt: Option<isize>,
+    pub op: OptOps,
+    pub axis: Option<isize>,
+    pub amt: Option<isize>,
 }
 
 impl Default for Opt {
@@ -203,6 +234,8 @@ pub struct Kernel {
     pub dont_use_locals: bool,
     pub local_alias: HashMap<usize, LocalBuffer>,
     pub applied_opts: Vec<Opt>,
+    // Opts found by beam search, hand_coded_optim applies these instead of its heuristics
+    pub applied_opts_cache: Option<Vec<Opt>>,
 }
 
 impl Kernel {
@@ -249,6 +282,7 @@ impl Kernel {
             dont_use_locals: false,
             local_alias: HashMap::new(),
             applied_opts: vec![],
+            applied_opts_cache: None,
         };
         ret.reshape_and_permute(None, Some(permute));
         // # parameters for optimization
@@ -258,8 +292,6 @@ impl Kernel {
         // self.dont_use_locals: bool = False
         ret.simplify_ones();
         ret.simplify_merge_adjacent();
-        // # cache
-        // self.applied_opts_cache: Optional[List[Opt]] = None
         ret
     }
 
@@ -384,13 +416,25 @@ impl Kernel {
     }
 
     pub fn apply_opt(&mut self, opt: Opt) {
+        self.try_apply_opt(opt).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    // Errors without touching the kernel when the opt doesn't apply to it, beam search relies on
+    // this to skip illegal candidates
+    pub fn try_apply_opt(&mut self, opt: Opt) -> Result<(), StormError> {
         use OptOps::*;
-        assert!(
+        let check = |cond: bool, msg: &str| match cond {
+            true => Ok(()),
+            false => Err(StormError::InvalidArgument {
+                op: "apply_opt",
+                msg: format!("{msg}, {opt:?}"),
+            }),
+        };
+        check(
             !self.dont_use_locals
                 || !matches!(opt.op, LOCAL | LASTLOCAL | GROUP | GROUPTOP | UPCASTMID),
-            "not using locals"
-        );
-        self.applied_opts.push(opt.clone());
+            "not using locals",
+        )?;
         let mut axis = -1;
         if let Some(opt_axis) = opt.axis {
             //axis = opt.axis + (self.first_reduce if opt.op == OptOps.UNROLL else (self.first_reduce+len(self.group_for_reduce) if opt.op in [OptOps.GROUP, OptOps.GROUPTOP] else 0))  # noqa: E501
@@ -404,23 +448,24 @@ impl Kernel {
                         0
                     }
                 });
+            check(axis >= 0 && axis < self.shape_len(), "invalid axis")?;
         }
         let mut amt = -1;
         if let Some(opt_amt) = opt.amt {
-            if opt_amt != 0 {
-                amt = opt_amt;
+            amt = if opt_amt != 0 {
+                opt_amt
             } else {
-                amt = self.full_shape()[axis];
-                assert!(amt != 1, "shift/padto of amt 1 or Node is meaningless");
-                if opt.op != PADTO {
-                    assert!(self.full_shape()[axis] % amt == 0)
-                }
+                self.full_shape()[axis]
+            };
+            check(amt > 1, "shift/padto of amt 1 or Node is meaningless")?;
+            if opt.op != PADTO {
+                check(self.full_shape()[axis] % amt == 0, "no longer valid shift")?;
             }
         }
         match opt.op {
             LOCAL | LASTLOCAL => {
-                assert!(self.opts.has_local, "target does not support local");
-                assert!(axis < self.first_reduce(), "can't local a reduce");
+                check(self.opts.has_local, "target does not support local")?;
+                check(axis < self.first_reduce(), "can't local a reduce")?;
                 if opt.op == LOCAL {
                     // assert not tensor cores
                     self.shift_to(axis, amt, None, Some(self.first_reduce()));
@@ -430,15 +475,15 @@ impl Kernel {
                 self.local_dims += 1;
             }
             GROUP | GROUPTOP => {
-                assert!(
+                check(
                     self.opts.has_local && self.opts.has_share,
-                    "target does not support local or shared mem"
-                );
-                assert!(
+                    "target does not support local or shared mem",
+                )?;
+                check(
                     axis >= self.first_reduce() + self.group_for_reduce.len() as isize
                         && axis < self.shape_len() - self.upcasted,
-                    "must be reduce axis to group"
-                );
+                    "must be reduce axis to group",
+                )?;
                 //assert!(not self.tensor_core, "can't group with tensor cores");
                 self.shift_to(
                     axis,
@@ -449,25 +494,29 @@ impl Kernel {
                 self.group_for_reduce.push(amt);
             }
             UNROLL => {
-                assert!(
+                check(
                     axis < self.shape_len() - self.upcasted,
-                    "can't upcasted already upcasted"
-                );
-                assert!(amt <= 32, "don't unroll more than 32");
+                    "can't upcasted already upcasted",
+                )?;
+                check(amt <= 32, "don't unroll more than 32")?;
                 self.shift_to(axis, amt, None, None);
                 self.upcast();
             }
             UPCAST => {
-                assert!(axis < self.first_reduce(), "upcast is for non-reduce");
-                assert!(amt <= 8, "don't upcast more than 8");
+                check(axis < self.first_reduce(), "upcast is for non-reduce")?;
+                check(amt <= 8, "don't upcast more than 8")?;
                 self.shift_to(axis, amt, None, None);
                 self.upcast();
             }
             UPCASTMID => {
+                check(
+                    !self.group_for_reduce.is_empty() && self.first_reduce() <= 2,
+                    "invalid upcast mid reduce",
+                )?;
                 let axes = self.sts[0].unit_stride_axes(false);
-                assert!(axes.len() == 1, "wrong number of stride 1 axis: {axis:?}");
-                assert!(axes[0] == axis, "wrong axis");
-                assert!(amt == 4, "don't upcast mid anything but 4");
+                check(axes.len() == 1, "wrong number of stride 1 axis")?;
+                check(axes[0] == axis, "wrong axis")?;
+                check(amt == 4, "don't upcast mid anything but 4")?;
                 self.shift_to(
                     axis,
                     amt,
@@ -477,21 +526,24 @@ impl Kernel {
                 self.group_for_reduce.push(amt);
             }
             NOLOCALS => {
-                assert!(self.opts.has_local && self.dont_use_locals, "NOLOCALS is meaningless if target does not support local or already not using locals");
-                assert!(
+                check(self.opts.has_local && !self.dont_use_locals, "NOLOCALS is meaningless if target does not support local or already not using locals")?;
+                check(
                     self.local_dims == 0 && self.group_for_reduce.len() == 0,
-                    "can't have no locals with locals"
-                );
+                    "can't have no locals with locals",
+                )?;
                 self.dont_use_locals = true;
             }
             PADTO => {
-                assert!(axis < self.first_reduce(), "cannot pad a reduce axis");
+                check(axis < self.first_reduce(), "cannot pad a reduce axis")?;
+                for st in self.sts.iter() {
+                    check(st.shape()[axis] > amt / 2, "pad adds more than double the work")?;
+                }
                 let mut padded = false;
-                for (i, st) in self.sts.iter_mut().enumerate() {
-                    assert!(
-                        st.shape()[axis] > amt / 2,
-                        "pad adds more than double the work"
-                    );
+                for st in self.sts.iter() {
+                    padded |= roundup(st.shape()[axis], amt) - st.shape()[axis] > 0;
+                }
+                check(padded, "nothing was padded")?;
+                for st in self.sts.iter_mut() {
                     let ru = roundup(st.shape()[axis], amt);
                     if ru - st.shape()[axis] > 0 {
                         *st = st.pad(
@@ -507,14 +559,14 @@ impl Kernel {
                             ]
                             .concat(),
                         );
-                        padded = true;
                     }
                 }
-                assert!(padded, "nothing was padded");
             }
-            _ => panic!(),
+            _ => check(false, "unknown opt")?,
         }
+        self.applied_opts.push(opt);
         self.simplify_ones();
+        Ok(())
     }
 
     pub fn upcast(&mut self) {
@@ -608,6 +660,12 @@ impl Kernel {
 
     pub fn hand_coded_optim(&mut self) {
         use OptOps::*;
+        if let Some(opts) = self.applied_opts_cache.clone() {
+            for opt in opts {
+                self.apply_opt(opt);
+            }
+            return;
+        }
         let MV_BLOCKSIZE = getenv("MV_BLOCKSIZE", 4);
         let MV_THREADS_PER_ROW = getenv("MV_THREADS_PER_ROW", 8);
         let MV_ROWS_PER_THREAD = getenv("MV_ROWS_PER_THREAD", 4);
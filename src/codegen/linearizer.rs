@@ -1,6 +1,7 @@
 use itertools::Itertools;
 
-use crate::dtype::{_bool, float16, float32, int32};
+use crate::dtype::{_bool, float16, float32, float64, int32, int64, uint32};
+use crate::random;
 use crate::lazy::get_lazyop_info;
 use crate::shape::shapetracker::strides_for_shape;
 use crate::{ops, prelude::*};
@@ -9,7 +10,7 @@ use std::ops::Index;
 use std::sync::Arc;
 
 use crate::arg::Arg;
-use crate::codegen::kernel::{Buffers, LocalBuffer, KERNEL_CNT};
+use crate::codegen::kernel::{Buffers, LocalBuffer, RandBuffer, KERNEL_CNT};
 use crate::ops::{Binary, LazyOp, LazyOpSrc, Reduce, Ternary, Unary};
 use crate::shape::symbolic::{iter_idxs, none_var, num, var, ArcNode, NodeOp};
 use crate::shape::ShapeTracker;
@@ -17,6 +18,7 @@ use crate::tensor::shape::Shape;
 use crate::{dtype, lazy::LazyBuffer, ops::OpType};
 
 use super::kernel::{ConstNum, Kernel, Opt};
+use super::optimizer::simplify_uops;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
 pub struct UOsId(pub(crate) usize);
@@ -55,15 +57,43 @@ pub enum UOps {
     IF,
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+// Inputs are shared and a uop is compared and hashed by its id, so values used more than once,
+// like the rounds of the random number generator, don't make the graph grow exponentially.
+#[derive(Clone, Debug)]
 pub struct UOp {
     pub(crate) uop: UOps,
     pub(crate) dtype: Option<dtype::Dtype>,
-    pub(crate) vin: Vec<UOp>,
+    pub(crate) vin: Arc<Vec<UOp>>,
     pub(crate) args: Vec<Arg>,
     pub(crate) id: UOsId,
 }
 
+impl UOp {
+    pub fn new(uop: UOps, dtype: Option<Dtype>, vin: Vec<UOp>, args: Vec<Arg>) -> Self {
+        Self {
+            uop,
+            dtype,
+            vin: Arc::new(vin),
+            args,
+            id: uop_id(),
+        }
+    }
+}
+
+impl PartialEq for UOp {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for UOp {}
+
+impl std::hash::Hash for UOp {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
 // impl core::fmt::Display for UOp {
 //     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 //         write!(
@@ -105,7 +135,7 @@ impl Default for LinearizerOptions {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Linearizer {
     // ast: LazyOp,
     // opts: LinearizerOptions,
@@ -229,8 +259,8 @@ impl Linearizer {
 
 
         for i in 0..self.kernel.bufs.len() {
-            let buf = &self.kernel.bufs[i];
-            if let Buffers::MemBuffer(buffer) = buf {
+            let buf = self.kernel.bufs[i].clone();
+            if let Buffers::MemBuffer(buffer) = &buf {
                 let a = Arg::Str(format!("data{}", buffer.idx));
                 let uop = self.uop_default(
                     UOps::DEFINE_GLOBAL,
@@ -240,6 +270,17 @@ impl Linearizer {
                 );
                 self.buf_uops[i] = Some(uop);
             }
+            // What it reads is the seed and offset
+            if let Buffers::RandBuffer(buffer) = &buf {
+                let a = Arg::Str(format!("data{}", buffer.idx));
+                let uop = self.uop_default(UOps::DEFINE_GLOBAL, Some(uint32), vec![], vec![a]);
+                self.buf_uops[i] = Some(uop);
+            }
+            if let Buffers::GatherBuffer(buffer) = &buf {
+                let a = Arg::Str(format!("data{}", buffer.idx));
+                let uop = self.uop_default(UOps::DEFINE_GLOBAL, Some(buffer.dtype.clone()), vec![], vec![a]);
+                self.buf_uops[i] = Some(uop);
+            }
         }
         // # add var vals
         // for var in vars_from_ast(self.ast):
@@ -327,7 +368,7 @@ impl Linearizer {
 
         if self.kernel.dont_use_locals {
             self.global_size =
-                Some(v![x.max().unwrap() as usize + 1, for x in loop_local_idxs.iter().rev()]);
+                Some(v![x.max().unwrap() as usize + 1, for x in loop_global_idxs.iter().rev()]);
             let mut extend_loop_uops = HashMap::new();
             for (i, x) in loop_global_idxs.iter().enumerate() {
                 extend_loop_uops.insert(
@@ -506,8 +547,19 @@ impl Linearizer {
         }
 
         // load late bufs
-        let iter_ = v![(i, b.clone()), for (i, b) in  self.kernel.bufs.iter().enumerate(), if !self.kernel.earlybufs.contains(b) && i != 0 && !matches!(b, Buffers::LocalBuffer(_))];
+        let iter_ = v![(i, b.clone()), for (i, b) in  self.kernel.bufs.iter().enumerate(), if !self.kernel.earlybufs.contains(b) && i != 0 && !matches!(b, Buffers::LocalBuffer(_) | Buffers::GatherBuffer(_))];
         loaded_buffers.extend(v![(b, self.global_load(i as isize, vec![global_idx.clone(), local_idxs.clone(), fake_reduce_idxs.clone(), upcast_idxs.clone()].concat(), None, None)), for (i, b) in iter_]);
+        // A gather loads at the positions in its index, which is one of the buffers loaded above
+        for x in self.kernel.ast.get_lazyops() {
+            if x.optype == ops::Buffer::Gather {
+                let (b, index) = (x.args[0].to_buf(), x.src[0].lo().args[0].to_buf());
+                let i = self.kernel.bufs.iter().position(|x| x == &b).unwrap();
+                let index = loaded_buffers[&index].clone();
+                let idxs = vec![global_idx.clone(), local_idxs.clone(), fake_reduce_idxs.clone(), upcast_idxs.clone()].concat();
+                let ret = self.gather_load(i, idxs, &index);
+                loaded_buffers.insert(b, ret);
+            }
+        }
         let val = self.ast_parse(
             self.kernel.ast.src[0].lo().clone(),
             &mut acc,
@@ -658,6 +710,10 @@ impl Linearizer {
                 self.uop(UOps::END, None, vec![u.clone()], vec![], false, None, true);
             }
         }
+
+        if getenv("UOPT", 1) != 0 {
+            self.uops = simplify_uops(&self.uops);
+        }
     }
 
     pub fn _const(&mut self, val: String, dtype: Dtype, insert_before: Option<isize>) -> UOp {
@@ -853,7 +909,7 @@ impl Linearizer {
         let ret = UOp {
             uop: uop.clone(),
             dtype: dtype.clone(),
-            vin: vin.clone(),
+            vin: Arc::new(vin.clone()),
             args: arg.clone(),
             id: uop_id(),
         };
@@ -917,10 +973,15 @@ impl Linearizer {
             acc.clone()
         };
 
+        let rand = match buf {
+            Buffers::RandBuffer(r) => Some(r.clone()),
+            _ => None,
+        };
+
         let mut amt = 1;
         let mut dim: Option<isize> = None;
         let upcast_dim = self.get_upcast_dim(i);
-        if upcast_dim.len() == 1 {
+        if upcast_dim.len() == 1 && rand.is_none() {
             let float4_expand = idxs[0].expand(None);
             if (float4_expand.len() == 4 || float4_expand.len() == 2) {
                 dim = Some(upcast_dim[0]);
@@ -1020,6 +1081,11 @@ impl Linearizer {
                         );
                         self.load_cache.insert(key.clone(), tmp);
                     }
+                } else if let Some(r) = &rand {
+                    let buf_uop = self.buf_uops[buf_uops_i].clone().unwrap();
+                    let rendered_idx = self.render(idx);
+                    let tmp = self.rand_load(r, buf_uop, rendered_idx);
+                    self.load_cache.insert(key.clone(), tmp);
                 } else {
                     assert!(
                         self.buf_uops[buf_uops_i].is_some(),
@@ -1068,6 +1134,157 @@ impl Linearizer {
         ret
     }
 
+    // Loads the gather buffer i at every element of idxs, moved along its axis by the index loaded
+    // for that element. Indices below zero count from the end and the rest are clamped, so a
+    // kernel never reads outside the source.
+    fn gather_load(&mut self, i: usize, idxs: Vec<ArcNode>, index: &[UOp]) -> Vec<UOp> {
+        let Buffers::GatherBuffer(g) = self.kernel.bufs[i].clone() else {
+            unreachable!()
+        };
+        let alu = |s: &mut Self, op: OpType, vin: Vec<UOp>| {
+            s.uop_default(UOps::ALU, Some(int32), vin, vec![Arg::OpType(op)])
+        };
+        let buf_uop = self.buf_uops[i].clone().unwrap();
+        let expand_vars = v![rename_var(idx.expand_idx(), &format!("_uidx{j}")), for (j, idx) in idxs.iter().enumerate()];
+        let fake_idxs = v![
+        {
+            let eidx = idx.expand_idx();
+            if eidx.is_var() {
+                idx.substitute(&HashMap::from([(idx.expand_idx(), ev.clone())]))
+            } else {
+                idx.clone()
+            }
+        }
+        ,for (idx, ev) in izip!(idxs.iter(), expand_vars.iter())];
+        let (g_idx, g_valid) = self.kernel.sts[i].expr_idxs(Some(fake_idxs));
+        let (e_idxs, e_valids) = (
+            g_idx.expand(Some(expand_vars.clone())),
+            g_valid.expand(Some(expand_vars.clone())),
+        );
+        let (zero, size) = (self._const("0".into(), int32, None), self._const(g.size.to_string(), int32, None));
+        let (last, stride) = (self._const((g.size - 1).to_string(), int32, None), self._const(g.stride.to_string(), int32, None));
+        let mut ret = vec![];
+        for (idx, valid, at) in izip!(e_idxs, e_valids, index) {
+            let at = self.uop_default(UOps::CAST, Some(int32), vec![at.clone()], vec![]);
+            let neg = alu(self, Binary::Cmplt.into(), vec![at.clone(), zero.clone()]);
+            let wrapped = alu(self, Binary::Add.into(), vec![at.clone(), size.clone()]);
+            let at = alu(self, Ternary::Where.into(), vec![neg, wrapped, at]);
+            let neg = alu(self, Binary::Cmplt.into(), vec![at.clone(), zero.clone()]);
+            let at = alu(self, Ternary::Where.into(), vec![neg, zero.clone(), at]);
+            let inside = alu(self, Binary::Cmplt.into(), vec![at.clone(), size.clone()]);
+            let at = alu(self, Ternary::Where.into(), vec![inside, at, last.clone()]);
+            let offset = alu(self, Binary::Mul.into(), vec![at, stride.clone()]);
+            let rendered_idx = self.render(idx);
+            let offset = alu(self, Binary::Add.into(), vec![rendered_idx, offset]);
+            let valid_tuple = if valid.min().unwrap() == 0 {
+                let invalid = if g.dtype.is_int() { "0" } else { "0.0" };
+                vec![self.render(valid), self._const(invalid.into(), g.dtype.clone(), None)]
+            } else {
+                vec![]
+            };
+            let vin = vec![vec![buf_uop.clone(), offset], valid_tuple].concat();
+            ret.push(self.uop_default(UOps::LOAD, Some(g.dtype.clone()), vin, vec![]));
+        }
+        ret
+    }
+
+    // Element idx of a draw, computed the way random::threefry_stream words are turned into numbers
+    // on the host. Its counter starts at the offset in words 2 and 3 of the input, which a draw
+    // never carries out of, and words 0 and 1 are the seed.
+    fn rand_load(&mut self, r: &RandBuffer, buf_uop: UOp, idx: UOp) -> UOp {
+        let alu = |s: &mut Self, op: Binary, a: &UOp, b: &UOp| {
+            let dtype = a.dtype.clone();
+            let op = vec![Arg::OpType(OpType::Binary(op))];
+            s.uop_default(UOps::ALU, dtype, vec![a.clone(), b.clone()], op)
+        };
+        let cast = |s: &mut Self, x: &UOp, dtype: Dtype| {
+            s.uop_default(UOps::CAST, Some(dtype), vec![x.clone()], vec![])
+        };
+        let word = |s: &mut Self, x: u32| s._const(x.to_string(), uint32, None);
+        let mut key = vec![];
+        for j in 0..4 {
+            let i = self.render(num(j));
+            key.push(self.uop_default(UOps::LOAD, Some(uint32), vec![buf_uop.clone(), i], vec![]));
+        }
+        let parity = word(self, random::PARITY);
+        let k2 = alu(self, Binary::Xor, &key[0], &key[1]);
+        let ks = [key[0].clone(), key[1].clone(), alu(self, Binary::Xor, &k2, &parity)];
+        let words = if r.range.is_none() { r.dtype.size.div_ceil(4) } else { 1 };
+        let (idx, n) = (cast(self, &idx, uint32), word(self, words as u32));
+        let first = alu(self, Binary::Mul, &idx, &n);
+        let first = alu(self, Binary::Add, &first, &key[2]);
+        let mut w = vec![];
+        for j in 0..words {
+            let j = word(self, j as u32);
+            let ctr = alu(self, Binary::Add, &first, &j);
+            let mut x0 = alu(self, Binary::Add, &ctr, &ks[0]);
+            let mut x1 = alu(self, Binary::Add, &key[3], &ks[1]);
+            for i in 0..5 {
+                for &rot in random::ROTATIONS[i % 2 * 4..i % 2 * 4 + 4].iter() {
+                    x0 = alu(self, Binary::Add, &x0, &x1);
+                    let (left, right) = (word(self, rot), word(self, 32 - rot));
+                    let hi = alu(self, Binary::Shl, &x1, &left);
+                    let lo = alu(self, Binary::Shr, &x1, &right);
+                    let rotated = alu(self, Binary::Or, &hi, &lo);
+                    x1 = alu(self, Binary::Xor, &rotated, &x0);
+                }
+                let inject = word(self, i as u32 + 1);
+                x0 = alu(self, Binary::Add, &x0, &ks[(i + 1) % 3]);
+                x1 = alu(self, Binary::Add, &x1, &ks[(i + 2) % 3]);
+                x1 = alu(self, Binary::Add, &x1, &inject);
+            }
+            w.push(x0);
+        }
+        // The top bits of a word as a float in [0, 1)
+        let unit = |s: &mut Self, x: &UOp, bits: u32, dtype: Dtype| {
+            let shift = word(s, 32 - bits);
+            let x = alu(s, Binary::Shr, x, &shift);
+            let x = cast(s, &x, dtype.clone());
+            let scale = s._const(format!("{:?}", 2f64.powi(-(bits as i32))), dtype, None);
+            alu(s, Binary::Mul, &x, &scale)
+        };
+        match r.range {
+            // Multiply-shift like random::below, the high word of w * n summed from 16 bit halves
+            Some((low, high)) => {
+                let n = (high - low) as u32;
+                let (sixteen, mask) = (word(self, 16), word(self, 0xFFFF));
+                let (c, d) = (word(self, n >> 16), word(self, n & 0xFFFF));
+                let a = alu(self, Binary::Shr, &w[0], &sixteen);
+                let b = alu(self, Binary::And, &w[0], &mask);
+                let ac = alu(self, Binary::Mul, &a, &c);
+                let bd = alu(self, Binary::Mul, &b, &d);
+                let (mut hi, mut mid) = (ac, alu(self, Binary::Shr, &bd, &sixteen));
+                for (x, y) in [(&a, &d), (&b, &c)] {
+                    let p = alu(self, Binary::Mul, x, y);
+                    let p_lo = alu(self, Binary::And, &p, &mask);
+                    let p_hi = alu(self, Binary::Shr, &p, &sixteen);
+                    mid = alu(self, Binary::Add, &mid, &p_lo);
+                    hi = alu(self, Binary::Add, &hi, &p_hi);
+                }
+                let carry = alu(self, Binary::Shr, &mid, &sixteen);
+                let hi = alu(self, Binary::Add, &hi, &carry);
+                let hi = cast(self, &hi, int64);
+                let low = self._const(low.to_string(), int64, None);
+                let x = alu(self, Binary::Add, &hi, &low);
+                cast(self, &x, r.dtype.clone())
+            }
+            // 32 bits of the first word and 21 of the second
+            None if r.dtype == float64 => {
+                let hi = unit(self, &w[0], 32, float64);
+                let lo = unit(self, &w[1], 21, float64);
+                let scale = self._const(format!("{:?}", 2f64.powi(-32)), float64, None);
+                let lo = alu(self, Binary::Mul, &lo, &scale);
+                alu(self, Binary::Add, &hi, &lo)
+            }
+            None if r.dtype == float16 => {
+                let x = unit(self, &w[0], 11, float32);
+                cast(self, &x, float16)
+            }
+            None if r.dtype.is_float() => unit(self, &w[0], 24, r.dtype.clone()),
+            None => cast(self, &w[0], r.dtype.clone()),
+        }
+    }
+
     fn global_store(&mut self, i: isize, idxs: Vec<ArcNode>, store: Vec<UOp>) -> Vec<UOp> {
         let buf_i = if i < 0 {
             self.kernel.bufs.len() as isize + i
@@ -1276,7 +1493,7 @@ impl Linearizer {
 
     fn replace_op(&mut self, old: &UOp, new: &UOp) {
         for u in self.uops.iter_mut() {
-            u.vin = v![if x == old { new.clone() } else {x.clone() }, for x in u.vin.iter()];
+            u.vin = Arc::new(v![if x == old { new.clone() } else {x.clone() }, for x in u.vin.iter()]);
         }
         self.uops
             .remove(self.uops.iter().position(|u| u == old).unwrap());
@@ -1407,21 +1624,25 @@ impl Linearizer {
     }
 }
 
+// Everything x is computed from, each uop once so shared inputs don't blow it up
 fn get_recursive_parents<'a>(
     x: &'a UOp,
     acc_scope: &mut HashMap<&'a UOp, Vec<&'a UOp>>,
     with_phi: bool,
-) -> Vec<&'a UOp> {
-    vec![
-        x.vin.iter().collect::<Vec<&UOp>>(),
-        v![get_recursive_parents(p, acc_scope, with_phi),for p in x.vin.iter()].concat(),
-        if with_phi && acc_scope.get(x).is_some() {
-            acc_scope[x].clone()
-        } else {
-            vec![]
-        },
-    ]
-    .concat()
+) -> HashSet<&'a UOp> {
+    let mut ret = HashSet::new();
+    let mut stack = vec![x];
+    while let Some(u) = stack.pop() {
+        if with_phi && let Some(scope) = acc_scope.get(u) {
+            ret.extend(scope.iter().copied());
+        }
+        for p in u.vin.iter() {
+            if ret.insert(p) {
+                stack.push(p);
+            }
+        }
+    }
+    ret
 }
 
 pub fn cartesian_product<T: Clone>(lists: Vec<Vec<T>>) -> Vec<Vec<T>> {
@@ -1 +1,560 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
 
+use crate::codegen::kernel::{Buffers, Opt, OptOps};
+use crate::arg::Arg;
+use crate::codegen::linearizer::{Linearizer, UOp, UOps, UOsId};
+use crate::device::diskcache;
+use crate::device::interpreter::{alu, cast};
+use crate::ops::{Binary, LazyOp, OpType, Ternary, Unary};
+use crate::prelude::*;
+
+lazy_static::lazy_static! {
+    // Every opt the search tries on each axis, amt 0 means the whole axis
+    pub static ref ACTIONS: Vec<Opt> = {
+        use OptOps::*;
+        let mut ret = vec![];
+        let mut add = |op: OptOps, axes: isize, amts: &[isize]| {
+            for axis in 0..axes {
+                for amt in amts {
+                    ret.push(Opt { op: op.clone(), axis: Some(axis), amt: Some(*amt) });
+                }
+            }
+        };
+        add(UPCAST, 8, &[0, 2, 3, 4, 5, 7]);
+        add(UNROLL, 4, &[0, 4]);
+        add(LOCAL, 6, &[2, 3, 4, 8, 13, 16, 29]);
+        add(GROUPTOP, 3, &[13, 16, 29, 32, 256]);
+        add(GROUP, 3, &[0, 4, 8, 16]);
+        add(PADTO, 7, &[32]);
+        add(LOCAL, 2, &[32]);
+        add(UPCASTMID, 3, &[4]);
+        ret.push(Opt { op: NOLOCALS, axis: None, amt: None });
+        ret
+    };
+}
+
+pub fn beam_width() -> usize {
+    getenv::<usize>("BEAM", 0)
+}
+
+// Every kernel reachable from `lin` with one more opt
+pub fn get_linearizer_actions(lin: &Linearizer) -> Vec<Linearizer> {
+    let mut seen = HashSet::new();
+    let mut ret = vec![];
+    for opt in ACTIONS.iter() {
+        let mut lin2 = lin.clone();
+        if lin2.kernel.try_apply_opt(opt.clone()).is_err() {
+            continue;
+        }
+        let k = &lin2.kernel;
+        let full_shape = k.full_shape().dims;
+        let upcast = full_shape[(k.shape_len() - k.upcasted) as usize..].iter().product::<isize>();
+        let local = full_shape
+            .iter()
+            .take(k.first_reduce() as usize + k.group_for_reduce.len())
+            .skip(k.global_dims() as usize)
+            .product::<isize>();
+        if upcast > 256 || local > 256 {
+            continue;
+        }
+        if seen.insert(format!("{:?}", (&k.sts, k.upcasted, k.local_dims, &k.group_for_reduce))) {
+            ret.push(lin2);
+        }
+    }
+    ret
+}
+
+// Dummy buffers with the size and dtype of every buffer the kernel touches, None when an index
+// has no buffer or one can't be allocated
+pub fn bufs_from_lin(device: &dyn Device, lin: &Linearizer) -> Option<Vec<Arc<dyn Buffer>>> {
+    let mut sizes: Vec<Option<(usize, Dtype)>> = vec![];
+    for b in lin.kernel.bufs.iter() {
+        if let Buffers::MemBuffer(b) = b {
+            if sizes.len() <= b.idx {
+                sizes.resize(b.idx + 1, None);
+            }
+            let size = b.st.real_size().max(sizes[b.idx].as_ref().map_or(0, |s| s.0));
+            sizes[b.idx] = Some((size, b.dtype.clone()));
+        }
+        // The seed and offset of a draw
+        if let Buffers::RandBuffer(b) = b {
+            if sizes.len() <= b.idx {
+                sizes.resize(b.idx + 1, None);
+            }
+            sizes[b.idx] = Some((4, uint32));
+        }
+        // Clamped indices reach at most size - 1 positions past the st along the gathered axis
+        if let Buffers::GatherBuffer(b) = b {
+            if sizes.len() <= b.idx {
+                sizes.resize(b.idx + 1, None);
+            }
+            let size = b.st.real_size() + ((b.size - 1) * b.stride) as usize;
+            sizes[b.idx] = Some((size, b.dtype.clone()));
+        }
+    }
+    sizes
+        .into_iter()
+        .map(|s| s.and_then(|(size, dtype)| device.try_alloc(size.max(1), dtype).ok()))
+        .collect()
+}
+
+// Best of a few runs in seconds, infinity if there are no buffers to run on or the kernel does not
+// build
+pub fn time_linearizer(
+    device: &dyn Device,
+    lin: &Linearizer,
+    bufs: Option<&[Arc<dyn Buffer>]>,
+) -> f64 {
+    let Some(bufs) = bufs else {
+        return f64::INFINITY;
+    };
+    let mut lin = lin.clone();
+    lin.linearize();
+    let pad = |s: &Option<Vec<usize>>| {
+        let mut s = s.clone().unwrap_or_default();
+        s.extend(vec![1; 3 - s.len()]);
+        s
+    };
+    let global_size = pad(&lin.global_size);
+    let local_size = pad(&lin.local_size);
+    let (name, prg_str) = device.render(lin);
+    let Ok(prg) = device.try_build(&name, &prg_str) else {
+        return f64::INFINITY;
+    };
+    v![{
+        let st = Instant::now();
+        prg.run(bufs, &global_size, Some(&local_size), &[], &[]);
+        device.synchronize();
+        st.elapsed().as_secs_f64()
+    }, for _ in 0..3]
+    .into_iter()
+    .fold(f64::INFINITY, f64::min)
+}
+
+// Searches for the fastest opts for `ast` on `device` keeping the best `amt` kernels each round.
+// The winner is compared against the hand coded opts, comes back linearized with its opts in
+// applied_opts_cache and is stored so later runs skip the search. Set IGNORE_BEAM_CACHE=1 to
+// search again.
+pub fn beam_search(device: &dyn Device, ast: LazyOp, amt: usize) -> Linearizer {
+    let debug = DEBUG.0.contains("BEAM");
+    let key = format!("beam {amt} {ast:?}");
+    let mut lin = Linearizer::new(ast.clone(), Some(device.linearizer_opts()));
+    if getenv::<usize>("IGNORE_BEAM_CACHE", 0) == 0
+        && let Some(opts) = diskcache::load_opts(device, &key)
+    {
+        lin.kernel.applied_opts_cache = Some(opts);
+        lin.kernel.hand_coded_optim();
+        lin.linearize();
+        return lin;
+    }
+
+    let bufs = bufs_from_lin(device, &lin);
+    let bufs = bufs.as_deref();
+    let st = Instant::now();
+    let mut beam = vec![(lin.clone(), time_linearizer(device, &lin, bufs))];
+    loop {
+        let candidates = beam.iter().flat_map(|(l, _)| get_linearizer_actions(l));
+        let mut timed = v![{ let tm = time_linearizer(device, &l, bufs); (l, tm) }, for l in candidates];
+        timed.retain(|(_, t)| t.is_finite());
+        timed.sort_by(|a, b| a.1.total_cmp(&b.1));
+        if debug && let Some((l, t)) = timed.first() {
+            println!("beam {:.2}us {:?}", t * 1e6, l.kernel.applied_opts);
+        }
+        if timed.is_empty() || beam[0].1 <= timed[0].1 {
+            break;
+        }
+        timed.truncate(amt);
+        beam = timed;
+    }
+
+    let hand_coded = device.get_lin(ast);
+    let hand_coded_tm = time_linearizer(device, &hand_coded, bufs);
+    let (mut lin, tm) = beam.swap_remove(0);
+    if hand_coded_tm < tm {
+        lin = hand_coded;
+    }
+    if debug {
+        println!(
+            "beam search took {:.2}s, {:.2}us (hand coded {:.2}us) {:?}",
+            st.elapsed().as_secs_f64(),
+            tm.min(hand_coded_tm) * 1e6,
+            hand_coded_tm * 1e6,
+            lin.kernel.applied_opts
+        );
+    }
+    diskcache::store_opts(device, &key, &lin.kernel.applied_opts);
+    lin.kernel.applied_opts_cache = Some(lin.kernel.applied_opts.clone());
+    lin.linearize();
+    lin
+}
+
+// Passes over a linearized kernel. Each takes the uops in order and returns them rewritten, with
+// whatever ends up unused dropped, so it can run and be checked on its own.
+pub type UOpPass = fn(&[UOp]) -> Vec<UOp>;
+
+pub const UOP_PASSES: [(&str, UOpPass); 5] = [
+    ("fold_constants", fold_constants),
+    ("simplify_identities", simplify_identities),
+    ("reduce_strength", reduce_strength),
+    ("eliminate_common", eliminate_common),
+    ("hoist_invariants", hoist_invariants),
+];
+
+// Runs every pass until the uops stop shrinking, DEBUG=UOPT prints what each one did
+pub fn simplify_uops(uops: &[UOp]) -> Vec<UOp> {
+    let debug = DEBUG.0.contains("UOPT");
+    let mut uops = uops.to_vec();
+    loop {
+        let n = uops.len();
+        for (name, pass) in UOP_PASSES.iter() {
+            let before = uops.len();
+            uops = pass(&uops);
+            if debug {
+                println!("{name}: {before} -> {} uops", uops.len());
+            }
+        }
+        if uops.len() >= n {
+            return uops;
+        }
+    }
+}
+
+// Rebuilds uops front to back with every input swapped for what it was rewritten to. f gets each
+// uop with its new inputs and returns what it becomes: itself, possibly changed, an earlier uop, or
+// a new one it pushed to the output first.
+fn rewrite(uops: &[UOp], mut f: impl FnMut(UOp, &mut Vec<UOp>) -> UOp) -> Vec<UOp> {
+    let mut map: HashMap<UOsId, UOp> = HashMap::new();
+    let mut ret = Vec::with_capacity(uops.len());
+    for u in uops {
+        let mut new = u.clone();
+        new.vin = Arc::new(v![map.get(&x.id).cloned().unwrap_or_else(|| x.clone()), for x in u.vin.iter()]);
+        let new = f(new, &mut ret);
+        if new.id == u.id {
+            ret.push(new.clone());
+        }
+        map.insert(u.id, new);
+    }
+    remove_childless(ret)
+}
+
+fn remove_childless(mut uops: Vec<UOp>) -> Vec<UOp> {
+    loop {
+        let used =
+            HashSet::<UOsId>::from_iter(uops.iter().flat_map(|u| u.vin.iter().map(|x| x.id)));
+        let n = uops.len();
+        uops.retain(|u| {
+            used.contains(&u.id)
+                || matches!(
+                    u.uop,
+                    UOps::STORE | UOps::BARRIER | UOps::DEFINE_GLOBAL | UOps::END
+                )
+        });
+        if uops.len() == n {
+            return uops;
+        }
+    }
+}
+
+fn const_val(u: &UOp) -> Option<f64> {
+    if u.uop != UOps::CONST {
+        return None;
+    }
+    match &u.args[0] {
+        Arg::Idx(i) => Some(*i as f64),
+        Arg::Str(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn make_const(val: f64, dtype: Dtype) -> UOp {
+    let arg = if dtype.is_float() {
+        Arg::Str(format!("{val:?}"))
+    } else {
+        Arg::Idx(val as isize)
+    };
+    UOp::new(UOps::CONST, Some(dtype), vec![], vec![arg])
+}
+
+// Reads an accumulator, which changes inside the loop it's declared for
+fn reads_acc(u: &UOp) -> bool {
+    u.vin
+        .iter()
+        .any(|x| matches!(x.uop, UOps::DEFINE_ACC | UOps::PHI))
+}
+
+// ALU on CONST inputs becomes a CONST, computed the way the interpreter runs it
+pub fn fold_constants(uops: &[UOp]) -> Vec<UOp> {
+    rewrite(uops, |u, out| {
+        if u.uop != UOps::ALU || u.dtype.as_ref().is_none_or(|d| d.sz > 1) {
+            return u;
+        }
+        let Some(x) = u.vin.iter().map(const_val).collect::<Option<Vec<f64>>>() else {
+            return u;
+        };
+        let dtype = u.dtype.clone().unwrap();
+        let op = u.args[0].to_op();
+        if !matches!(
+            op,
+            OpType::Unary(Unary::Neg) | OpType::Binary(_) | OpType::Ternary(_)
+        ) {
+            return u;
+        }
+        // Integer division by zero has no value to fold to
+        if matches!(op, OpType::Binary(Binary::Div | Binary::Mod))
+            && !dtype.is_float()
+            && x[1] == 0.
+        {
+            return u;
+        }
+        let Some(c) = alu(&op, &x, &dtype) else {
+            return u;
+        };
+        let c = make_const(cast(c, &dtype), dtype);
+        out.push(c.clone());
+        c
+    })
+}
+
+// x+0, x-0, x*1, x/1, max(x, x), -(-x), where on a constant condition or with equal branches, and
+// for ints x*0 and x%1. Inputs only replace the ALU when their dtype matches.
+pub fn simplify_identities(uops: &[UOp]) -> Vec<UOp> {
+    rewrite(uops, |u, out| {
+        if u.uop != UOps::ALU {
+            return u;
+        }
+        let c = v![const_val(x), for x in u.vin.iter()];
+        let vin = &u.vin;
+        let int = u.dtype.as_ref().is_some_and(|d| d.is_int());
+        let mut zero = || {
+            let z = make_const(0., u.dtype.clone().unwrap());
+            out.push(z.clone());
+            z
+        };
+        let ret = match u.args[0].to_op() {
+            OpType::Binary(Binary::Add) if c[1] == Some(0.) => Some(vin[0].clone()),
+            OpType::Binary(Binary::Add) if c[0] == Some(0.) => Some(vin[1].clone()),
+            OpType::Binary(Binary::Sub) if c[1] == Some(0.) => Some(vin[0].clone()),
+            OpType::Binary(Binary::Mul) if c[1] == Some(1.) => Some(vin[0].clone()),
+            OpType::Binary(Binary::Mul) if c[0] == Some(1.) => Some(vin[1].clone()),
+            OpType::Binary(Binary::Mul) if int && (c[0] == Some(0.) || c[1] == Some(0.)) => {
+                Some(zero())
+            }
+            OpType::Binary(Binary::Div) if c[1] == Some(1.) => Some(vin[0].clone()),
+            OpType::Binary(Binary::Mod) if int && c[1] == Some(1.) => Some(zero()),
+            OpType::Binary(Binary::Max) if vin[0].id == vin[1].id => Some(vin[0].clone()),
+            OpType::Unary(Unary::Neg)
+                if vin[0].uop == UOps::ALU && vin[0].args[0].to_op() == Unary::Neg =>
+            {
+                Some(vin[0].vin[0].clone())
+            }
+            OpType::Ternary(Ternary::Where) if vin[1].id == vin[2].id => Some(vin[1].clone()),
+            OpType::Ternary(Ternary::Where) => match c[0] {
+                Some(cond) if cond != 0. => Some(vin[1].clone()),
+                Some(_) => Some(vin[2].clone()),
+                None => None,
+            },
+            _ => None,
+        };
+        match ret {
+            Some(x) if x.dtype == u.dtype => x,
+            _ => u,
+        }
+    })
+}
+
+// Whether u is never negative, going by what it's computed from
+fn nonneg(u: &UOp, known: &HashMap<UOsId, bool>) -> bool {
+    let is = |x: &UOp| {
+        known
+            .get(&x.id)
+            .copied()
+            .unwrap_or_else(|| nonneg(x, &HashMap::new()))
+    };
+    match u.uop {
+        UOps::CONST => const_val(u).is_some_and(|x| x >= 0.),
+        UOps::SPECIAL => true,
+        UOps::LOOP | UOps::CAST => is(&u.vin[0]),
+        UOps::ALU => match u.args[0].to_op() {
+            OpType::Binary(
+                Binary::Add
+                | Binary::Mul
+                | Binary::Div
+                | Binary::Mod
+                | Binary::Shr
+                | Binary::And
+                | Binary::Or
+                | Binary::Xor,
+            ) => u.vin.iter().all(is),
+            OpType::Binary(Binary::Max) => u.vin.iter().any(is),
+            OpType::Binary(Binary::Cmplt) => true,
+            OpType::Ternary(Ternary::Where) => is(&u.vin[1]) && is(&u.vin[2]),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Largest e for which 2^e and 2^-e are both normal numbers of the float dtype, so dividing by one
+// is exactly multiplying by the other
+fn max_pow2_exp(dtype: &Dtype) -> f64 {
+    match dtype.size / dtype.sz {
+        2 => 14.,
+        8 => 1022.,
+        _ => 126.,
+    }
+}
+
+// Division and modulo by a power of two: shift and mask for nonnegative ints, where they agree with
+// C's truncation, and multiplying by the exact reciprocal for floats
+pub fn reduce_strength(uops: &[UOp]) -> Vec<UOp> {
+    let mut known = HashMap::new();
+    rewrite(uops, |mut u, out| {
+        let op = if u.uop == UOps::ALU {
+            Some(u.args[0].to_op())
+        } else {
+            None
+        };
+        let pow2 = u
+            .vin
+            .get(1)
+            .and_then(const_val)
+            .filter(|k| *k != 0. && k.abs().log2().fract() == 0.);
+        if let (Some(op), Some(k), Some(dtype)) = (op, pow2, u.dtype.clone()) {
+            let replace = |val: f64, dtype: Dtype, op: Binary, out: &mut Vec<UOp>| {
+                let c = make_const(val, dtype);
+                out.push(c.clone());
+                (c, Arg::OpType(OpType::Binary(op)))
+            };
+            let int = dtype.is_int() && dtype.sz == 1 && k > 0. && nonneg(&u.vin[0], &known);
+            let new = match op {
+                OpType::Binary(Binary::Div) if int => {
+                    Some(replace(k.log2(), dtype, Binary::Shr, out))
+                }
+                OpType::Binary(Binary::Mod) if int => {
+                    Some(replace(k - 1., dtype, Binary::And, out))
+                }
+                OpType::Binary(Binary::Div)
+                    if dtype.is_float() && k.abs().log2().abs() <= max_pow2_exp(&dtype) =>
+                {
+                    let cdtype = u.vin[1].dtype.clone().unwrap();
+                    Some(replace(1. / k, cdtype, Binary::Mul, out))
+                }
+                _ => None,
+            };
+            if let Some((c, arg)) = new {
+                Arc::make_mut(&mut u.vin)[1] = c;
+                u.args = vec![arg];
+            }
+        }
+        known.insert(u.id, nonneg(&u, &known));
+        u
+    })
+}
+
+// Drops pure uops already computed in the same or an enclosing LOOP or IF block
+pub fn eliminate_common(uops: &[UOp]) -> Vec<UOp> {
+    type Key = (UOps, Option<Dtype>, Vec<UOsId>, Vec<Arg>);
+    let mut seen: HashMap<Key, UOp> = HashMap::new();
+    let mut scopes: Vec<Vec<Key>> = vec![vec![]];
+    rewrite(uops, |u, _| {
+        match u.uop {
+            UOps::LOOP | UOps::IF => scopes.push(vec![]),
+            UOps::END => {
+                for k in scopes.pop().unwrap() {
+                    seen.remove(&k);
+                }
+            }
+            UOps::CONST | UOps::ALU | UOps::CAST | UOps::GEP if !reads_acc(&u) => {
+                let key = (
+                    u.uop.clone(),
+                    u.dtype.clone(),
+                    v![x.id, for x in u.vin.iter()],
+                    u.args.clone(),
+                );
+                if let Some(prev) = seen.get(&key) {
+                    return prev.clone();
+                }
+                scopes.last_mut().unwrap().push(key.clone());
+                seen.insert(key, u.clone());
+            }
+            _ => (),
+        }
+        u
+    })
+}
+
+// Moves pure uops out of every loop they don't depend on, to just before the outermost such LOOP.
+// Nothing leaves an IF, and anything reading an accumulator stays where it is.
+pub fn hoist_invariants(uops: &[UOp]) -> Vec<UOp> {
+    // blocks[0] is the kernel, every open LOOP or IF adds one starting with it
+    let mut blocks: Vec<Vec<UOp>> = vec![vec![]];
+    let mut floor = vec![0];
+    let mut depth: HashMap<UOsId, usize> = HashMap::new();
+    let mut pinned = HashSet::new();
+    for u in uops {
+        let d = blocks.len() - 1;
+        let at = match u.uop {
+            UOps::LOOP | UOps::IF => {
+                floor.push(if u.uop == UOps::IF { d + 1 } else { floor[d] });
+                blocks.push(vec![]);
+                d + 1
+            }
+            UOps::END => {
+                floor.pop();
+                let body = blocks.pop().unwrap();
+                blocks[d - 1].extend(body);
+                d - 1
+            }
+            UOps::CONST | UOps::ALU | UOps::CAST | UOps::GEP
+                if !reads_acc(u) && !u.vin.iter().any(|x| pinned.contains(&x.id)) =>
+            {
+                u.vin
+                    .iter()
+                    .map(|x| depth.get(&x.id).copied().unwrap_or(0))
+                    .fold(floor[d], usize::max)
+            }
+            _ => {
+                if matches!(u.uop, UOps::ALU | UOps::CAST | UOps::GEP) {
+                    pinned.insert(u.id);
+                }
+                d
+            }
+        };
+        depth.insert(u.id, at);
+        blocks[at].push(u.clone());
+    }
+    assert!(blocks.len() == 1, "unclosed LOOP or IF");
+    blocks.pop().unwrap()
+}
+
+// One line per uop with its inputs by position, for reading kernels and golden tests
+pub fn uops_listing(uops: &[UOp]) -> String {
+    let idx = HashMap::<UOsId, usize>::from_iter(uops.iter().enumerate().map(|(i, u)| (u.id, i)));
+    let arg = |a: &Arg| match a {
+        Arg::Str(s) => s.clone(),
+        Arg::Idx(i) => i.to_string(),
+        Arg::Usize(u) => u.to_string(),
+        Arg::Dtype(d) => d.type_name.to_string(),
+        Arg::OpType(OpType::Unary(op)) => format!("{op:?}"),
+        Arg::OpType(OpType::Binary(op)) => format!("{op:?}"),
+        Arg::OpType(OpType::Ternary(op)) => format!("{op:?}"),
+        a => format!("{a:?}"),
+    };
+    let mut ret = String::new();
+    for (i, u) in uops.iter().enumerate() {
+        let vin =
+            v![idx.get(&x.id).map_or("?".to_string(), |i| i.to_string()), for x in u.vin.iter()];
+        let line = format!(
+            "{i:>3} {:<13} {:<5} [{}] {}",
+            format!("{:?}", u.uop),
+            u.dtype.as_ref().map_or("", |d| d.type_name),
+            vin.join(", "),
+            v![arg(a), for a in u.args.iter()].join(" ")
+        );
+        ret += line.trim_end();
+        ret.push('\n');
+    }
+    ret
+}
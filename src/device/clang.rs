@@ -0,0 +1,330 @@
+#![cfg(not(target_arch = "wasm32"))]
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use crate::codegen::linearizer::{LinearizerOptions, UOp};
+use crate::prelude::*;
+use crate::renderer::cstyle::{LanguageOpts, Renderer};
+
+use super::{Buffer, Device, Program};
+
+extern "C" {
+    fn malloc(size: usize) -> *mut c_void;
+    fn free(ptr: *mut c_void);
+}
+
+// Every kernel is compiled to this signature, the host loops over the work items and passes the
+// current group/local index in `gid`/`lid`.
+type ClangKernel = unsafe extern "C" fn(*const *mut c_void, *const i32, *const i32);
+
+// dlopen hands back the already loaded library when a path is reused, every load gets its own file
+fn so_path(name: &str) -> std::path::PathBuf {
+    static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    std::env::temp_dir().join(format!("storm_{}_{n}_{name}.so", std::process::id()))
+}
+
+#[derive(Debug)]
+pub struct ClangDevice {
+    pub compiler: String,
+    pub renderer: Arc<dyn Renderer>,
+}
+
+impl ClangDevice {
+    pub fn new() -> anyhow::Result<Arc<dyn Device>> {
+        let compiler = getenv::<String>("CLANG", "clang".into());
+        let status = Command::new(&compiler)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(anyhow::anyhow!("{compiler} --version failed: {status}"));
+        }
+        Ok(Arc::new(Self {
+            compiler,
+            renderer: Arc::new(ClangRenderer::default()),
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClangBuffer {
+    ptr: *mut c_void,
+    bytesize: usize,
+    dtype: Dtype,
+}
+
+impl Buffer for ClangBuffer {
+    fn device(&self) -> String {
+        "CLANG".into()
+    }
+
+    fn ptr(&self) -> *mut core::ffi::c_void {
+        self.ptr
+    }
+
+    fn dtype(&self) -> Dtype {
+        self.dtype.clone()
+    }
+
+    fn bytesize(&self) -> usize {
+        self.bytesize
+    }
+
+    fn to_cpu(&self) -> Vec<u8> {
+        let mut dst = vec![0u8; self.bytesize()];
+        let ptr = dst.as_mut_ptr() as *mut u8;
+        let device = get_device(&self.device()).unwrap();
+        device.copyout(self, ptr);
+        device.synchronize();
+        dst
+    }
+}
+
+impl Drop for ClangBuffer {
+    fn drop(&mut self) {
+        ALLOCTOR.0.free(&*self)
+    }
+}
+
+#[derive(Debug)]
+pub struct ClangProgram {
+    name: String,
+    lib: libloading::Library,
+    func: ClangKernel,
+}
+
+impl Program for ClangProgram {
+    fn run(
+        &self,
+        bufs: &[Arc<dyn Buffer>],
+        global_size: &[usize],
+        local_size: Option<&[usize]>,
+        args: &[isize],
+        extra: &[String],
+    ) {
+        let ptrs = v![b.ptr(), for b in bufs.iter()];
+        let pad = |s: &[usize]| {
+            let mut s = s.to_vec();
+            s.extend(vec![1; 3 - s.len()]);
+            s
+        };
+        let global_size = pad(global_size);
+        let local_size = pad(local_size.unwrap_or(&[]));
+        for g2 in 0..global_size[2] {
+            for g1 in 0..global_size[1] {
+                for g0 in 0..global_size[0] {
+                    let gid = [g0 as i32, g1 as i32, g2 as i32];
+                    for l2 in 0..local_size[2] {
+                        for l1 in 0..local_size[1] {
+                            for l0 in 0..local_size[0] {
+                                let lid = [l0 as i32, l1 as i32, l2 as i32];
+                                unsafe { (self.func)(ptrs.as_ptr(), gid.as_ptr(), lid.as_ptr()) };
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Device for ClangDevice {
+    fn name(&self) -> String {
+        "CLANG".into()
+    }
+
+    fn _alloc(&self, size: usize, dtype: Dtype) -> anyhow::Result<Arc<dyn Buffer>> {
+        let bytesize = size * dtype.size;
+        let ptr = unsafe { malloc(bytesize.max(1)) };
+        if ptr.is_null() {
+            return Err(anyhow::anyhow!("malloc of {bytesize} bytes failed"));
+        }
+        Ok(Arc::new(ClangBuffer {
+            ptr,
+            bytesize,
+            dtype,
+        }))
+    }
+
+    fn buf_from_mem_ptr(
+        &self,
+        size: usize,
+        dtype: Dtype,
+        mem: *mut std::ffi::c_void,
+    ) -> Arc<dyn Buffer> {
+        Arc::new(ClangBuffer {
+            ptr: mem,
+            bytesize: size * dtype.size,
+            dtype,
+        })
+    }
+
+    fn try_build(&self, name: &str, program: &str) -> Result<Arc<dyn Program>, StormError> {
+        self.load(name, &self.compile(name, program)?.unwrap())
+    }
+
+    fn compile(&self, name: &str, program: &str) -> Result<Option<Vec<u8>>, StormError> {
+        let err = |log: String| StormError::CompileError {
+            name: name.to_string(),
+            program: program.to_string(),
+            log,
+        };
+        let path = so_path(name);
+        let mut child = Command::new(&self.compiler)
+            .args(["-shared", "-O2", "-fPIC", "-x", "c", "-", "-lm", "-o"])
+            .arg(&path)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| err(format!("failed to spawn {}: {e}", self.compiler)))?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(program.as_bytes())
+            .map_err(|e| err(e.to_string()))?;
+        let out = child.wait_with_output().map_err(|e| err(e.to_string()))?;
+        if !out.status.success() {
+            return Err(err(String::from_utf8_lossy(&out.stderr).into()));
+        }
+        let lib = std::fs::read(&path).map_err(|e| err(e.to_string()))?;
+        std::fs::remove_file(&path).ok();
+        Ok(Some(lib))
+    }
+
+    fn load(&self, name: &str, binary: &[u8]) -> Result<Arc<dyn Program>, StormError> {
+        let err = |e: String| StormError::Internal(format!("failed to load kernel {name}: {e}"));
+        // dlopen needs a file, the mapping stays valid after it is gone
+        let path = so_path(name);
+        std::fs::write(&path, binary).map_err(|e| err(e.to_string()))?;
+        unsafe {
+            let lib = libloading::Library::new(&path);
+            std::fs::remove_file(&path).ok();
+            let lib = lib.map_err(|e| err(e.to_string()))?;
+            let func = *lib
+                .get::<ClangKernel>(name.as_bytes())
+                .map_err(|e| err(e.to_string()))?;
+            Ok(Arc::new(ClangProgram {
+                name: name.to_string(),
+                lib,
+                func,
+            }))
+        }
+    }
+
+    fn copyout(&self, src: &dyn Buffer, dst: *mut u8) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(src.ptr() as *const u8, dst, src.bytesize());
+        }
+    }
+
+    fn copyin(&self, src: &[u8], dst: &dyn Buffer) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                src.as_ptr(),
+                dst.ptr() as *mut u8,
+                src.len().min(dst.bytesize()),
+            );
+        }
+    }
+
+    fn synchronize(&self) {}
+
+    fn linearizer_opts(&self) -> LinearizerOptions {
+        LinearizerOptions {
+            has_share: false,
+            ..Default::default()
+        }
+    }
+
+    fn renderer(&self) -> Arc<dyn Renderer> {
+        self.renderer.clone()
+    }
+
+    fn free(&self, ptr: *mut std::ffi::c_void) {
+        unsafe { free(ptr) }
+    }
+}
+
+#[derive(Debug)]
+pub struct ClangRenderer {
+    opts: Arc<LanguageOpts>,
+}
+
+impl Default for ClangRenderer {
+    fn default() -> Self {
+        Self {
+            opts: Arc::new(LanguageOpts {
+                code_for_workitem: HashMap::from([
+                    ("g".into(), (0..3).map(|i| format!("gid[{i}]")).collect()),
+                    ("l".into(), (0..3).map(|i| format!("lid[{i}]")).collect()),
+                    ("i".into(), (0..3).map(|i| format!("gid[{i}]")).collect()),
+                ]),
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+// ALU ops are float32. The plain C math functions work in double, so a value recomputed inside a
+// fused kernel wouldn't compare equal to the same value stored as float by another kernel.
+impl crate::ops::Op for ClangRenderer {
+    fn exp2(&self, x: &str) -> String {
+        format!("exp2f({x})")
+    }
+
+    fn log2(&self, x: &str) -> String {
+        format!("log2f({x})")
+    }
+
+    fn sin(&self, x: &str) -> String {
+        format!("sinf({x})")
+    }
+
+    fn sqrt(&self, x: &str) -> String {
+        format!("sqrtf({x})")
+    }
+}
+
+impl Renderer for ClangRenderer {
+    fn lang_opts(&self) -> Arc<LanguageOpts> {
+        self.opts.clone()
+    }
+
+    fn render_kernel(
+        &self,
+        function_name: &str,
+        kernel: &[String],
+        bufs: &[(String, dtype::Dtype)],
+        local_size: &[usize],
+        uops: &[UOp],
+        prekernel: &[String],
+    ) -> String {
+        let mut pre = vec![
+            "#include <math.h>".to_string(),
+            "#include <stdbool.h>".to_string(),
+            "#define max(x,y) (((x)>(y))?(x):(y))".to_string(),
+        ];
+        if any(&v![u.dtype.as_ref().is_some_and(|d| *d == half), for u in uops]) {
+            pre.push("#define half _Float16".to_string());
+        }
+        pre.extend(prekernel.iter().cloned());
+
+        // Buffers are passed as an array so every kernel has the same C signature
+        let mut prg = format!(
+            "void {function_name}(void** bufs, const int* gid, const int* lid) {{\n"
+        );
+        for (i, (name, dtype)) in bufs.iter().enumerate() {
+            let t = (if i > 0 { "const " } else { "" }).to_string() + dtype.c_name + "*";
+            prg += &format!("{t} {name} = ({t})bufs[{i}];\n");
+        }
+        prg += &format!("{}\n}}", kernel.join("\n"));
+        format!("{}\n{}", pre.join("\n"), prg)
+    }
+}
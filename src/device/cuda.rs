@@ -192,7 +192,7 @@ impl Buffer for CudaBuffer {
     fn to_cpu(&self) -> Vec<u8> {
         let mut dst = vec![0u8; self.bytesize()];
         let ptr = dst.as_mut_ptr() as *mut u8;
-        DEVICE.copyout(self, ptr);
+        get_device(&self.device()).unwrap().copyout(self, ptr);
         dst
     }
 }
@@ -254,34 +254,45 @@ impl Device for CudaDevice {
         }
     }
 
-    fn build(&self, name: &str, program: &str) -> Arc<dyn Program> {
+    fn try_build(&self, name: &str, program: &str) -> Result<Arc<dyn Program>, StormError> {
+        self.load(name, &self.compile(name, program)?.unwrap())
+    }
+
+    fn compile(&self, name: &str, program: &str) -> Result<Option<Vec<u8>>, StormError> {
+        //let ptx = cudarc::nvrtc::compile_ptx(program).unwrap();
+        let ptx = cudarc::nvrtc::compile_ptx_with_opts(
+            program,
+            CompileOptions {
+                include_paths: vec![
+                    "/usr/local/cuda/include".into(),
+                    "/usr/include".into(),
+                    "/opt/cuda/include/".into(),
+                ],
+                arch: Some(self.arch),
+                ..Default::default()
+            },
+        )
+        .map_err(|e| StormError::CompileError {
+            name: name.to_string(),
+            program: program.to_string(),
+            log: match e {
+                CompileError::CompileError { log, .. } => log.to_string_lossy().into(),
+                e => format!("{e:?}"),
+            },
+        })?;
+        Ok(Some(ptx.to_src().into_bytes()))
+    }
+
+    fn load(&self, name: &str, binary: &[u8]) -> Result<Arc<dyn Program>, StormError> {
+        let err = |e: String| StormError::Internal(format!("failed to load kernel {name}: {e}"));
         unsafe {
-            //let ptx = cudarc::nvrtc::compile_ptx(program).unwrap();
-            let ptx = cudarc::nvrtc::compile_ptx_with_opts(
-                program,
-                CompileOptions {
-                    include_paths: vec![
-                        "/usr/local/cuda/include".into(),
-                        "/usr/include".into(),
-                        "/opt/cuda/include/".into(),
-                    ],
-                    arch: Some(self.arch),
-                    ..Default::default()
-                },
-            );
-            if ptx.is_err() {
-                let e = ptx.clone().unwrap_err();
-                match e {
-                    CompileError::CompileError { nvrtc, options, log } => panic!("{}", log.to_str().unwrap()),
-                    _ => panic!("{e:?}"),
-                }
-            }
-            let ptx = ptx.unwrap();
             let mut module: cudarc::driver::sys::CUmodule = std::ptr::null_mut();
-            let cstring = CString::new(ptx.to_src()).unwrap();
+            let cstring = CString::new(binary).map_err(|e| err(e.to_string()))?;
             let r =
                 cudarc::driver::sys::cuModuleLoadData((&mut module) as _, cstring.as_ptr() as _);
-            assert!(r == cudaError_enum::CUDA_SUCCESS, "{:?}", r);
+            if r != cudaError_enum::CUDA_SUCCESS {
+                return Err(err(format!("{r:?}")));
+            }
             let mut func: cudarc::driver::sys::CUfunction = std::ptr::null_mut();
             let cstring = CString::new(name).unwrap();
             let r = cudarc::driver::sys::cuModuleGetFunction(
@@ -289,12 +300,14 @@ impl Device for CudaDevice {
                 module,
                 cstring.as_ptr() as _,
             );
-            assert!(r == cudaError_enum::CUDA_SUCCESS, "{:?}", r);
-            Arc::new(CudaProgram {
+            if r != cudaError_enum::CUDA_SUCCESS {
+                return Err(err(format!("{r:?}")));
+            }
+            Ok(Arc::new(CudaProgram {
                 module,
                 func,
                 device: self.clone(),
-            })
+            }))
         }
     }
 
@@ -305,7 +318,7 @@ impl Device for CudaDevice {
         }
     }
 
-    fn copyin(&self, src: Vec<u8>, dst: &dyn Buffer) {
+    fn copyin(&self, src: &[u8], dst: &dyn Buffer) {
         unsafe {
             let r = cuMemcpyHtoD_v2(dst.ptr() as _, src.as_ptr() as _, src.len());
             assert!(r == cudaError_enum::CUDA_SUCCESS, "{:?}", r);
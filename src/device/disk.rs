@@ -0,0 +1,156 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::prelude::*;
+use crate::renderer::cstyle::Renderer;
+
+use super::{Device, Program};
+
+pub type DiskData = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+// A tensor's bytes inside a (usually memory mapped) file. Disk buffers can't run kernels, moving
+// them to a device with `Tensor::to` reads the bytes when that copy is realized so the host only
+// ever holds one tensor of the file at a time.
+#[derive(Clone)]
+pub struct DiskBuffer {
+    data: DiskData,
+    offset: usize,
+    bytesize: usize,
+    dtype: Dtype,
+}
+
+impl core::fmt::Debug for DiskBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiskBuffer")
+            .field("offset", &self.offset)
+            .field("bytesize", &self.bytesize)
+            .field("dtype", &self.dtype)
+            .finish()
+    }
+}
+
+impl DiskBuffer {
+    // bfloat16 is stored as is and comes out as float32, kernels can't do math on it
+    pub fn new(data: DiskData, offset: usize, bytesize: usize, dtype: Dtype) -> Self {
+        assert!(offset + bytesize <= (*data).as_ref().len(), "disk buffer out of bounds");
+        Self {
+            data,
+            offset,
+            bytesize,
+            dtype,
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &(*self.data).as_ref()[self.offset..self.offset + self.bytesize]
+    }
+}
+
+// Maps the whole file, wasm has no mmap so it gets read instead
+pub fn open(path: impl AsRef<Path>) -> anyhow::Result<DiskData> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let file = std::fs::File::open(path)?;
+        // Safety: the file must not be truncated while it is mapped, same as any mmap
+        Ok(Arc::new(unsafe { memmap2::Mmap::map(&file)? }))
+    }
+    #[cfg(target_arch = "wasm32")]
+    Ok(Arc::new(std::fs::read(path)?))
+}
+
+impl Buffer for DiskBuffer {
+    fn device(&self) -> String {
+        "DISK".into()
+    }
+
+    fn ptr(&self) -> *mut core::ffi::c_void {
+        self.bytes().as_ptr() as *mut core::ffi::c_void
+    }
+
+    fn dtype(&self) -> Dtype {
+        if self.dtype == bfloat16 {
+            float32
+        } else {
+            self.dtype.clone()
+        }
+    }
+
+    fn bytesize(&self) -> usize {
+        self.bytesize / self.dtype.size * self.dtype().size
+    }
+
+    fn to_cpu(&self) -> Vec<u8> {
+        let bytes = self.bytes();
+        if self.dtype == bfloat16 {
+            v![
+                half::bf16::from_le_bytes([b[0], b[1]]).to_f32().to_le_bytes(),
+                for b in bytes.chunks(2)
+            ]
+            .concat()
+        } else {
+            bytes.to_vec()
+        }
+    }
+
+    fn host_bytes(&self) -> Option<&[u8]> {
+        (self.dtype != bfloat16).then(|| self.bytes())
+    }
+}
+
+// The device safe_load's tensors live on. It reads them back and is the source of copies to other
+// devices, but has nothing to allocate or run kernels with.
+#[derive(Debug)]
+pub struct DiskDevice;
+
+impl DiskDevice {
+    pub fn new() -> anyhow::Result<Arc<dyn Device>> {
+        Ok(Arc::new(DiskDevice))
+    }
+}
+
+impl Device for DiskDevice {
+    fn name(&self) -> String {
+        "DISK".into()
+    }
+
+    fn _alloc(&self, size: usize, dtype: Dtype) -> anyhow::Result<Arc<dyn Buffer>> {
+        Err(anyhow::anyhow!("DISK buffers only come from files"))
+    }
+
+    fn buf_from_mem_ptr(
+        &self,
+        size: usize,
+        dtype: Dtype,
+        mem: *mut std::ffi::c_void,
+    ) -> Arc<dyn Buffer> {
+        unreachable!("DISK buffers only come from files")
+    }
+
+    fn try_build(&self, name: &str, program: &str) -> Result<Arc<dyn Program>, StormError> {
+        Err(read_only())
+    }
+
+    fn copyout(&self, src: &dyn Buffer, dst: *mut u8) {
+        let bytes = src.to_cpu();
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len()) }
+    }
+
+    fn copyin(&self, src: &[u8], dst: &dyn Buffer) {
+        panic!("{}", read_only())
+    }
+
+    fn synchronize(&self) {}
+
+    fn renderer(&self) -> Arc<dyn Renderer> {
+        unreachable!("DISK can't run kernels")
+    }
+
+    fn free(&self, ptr: *mut std::ffi::c_void) {}
+}
+
+pub fn read_only() -> StormError {
+    StormError::InvalidArgument {
+        op: "DISK",
+        msg: "disk tensors are read only, move them to a device with Tensor::to first".into(),
+    }
+}
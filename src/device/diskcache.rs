@@ -0,0 +1,208 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::codegen::kernel::{Opt, OptOps};
+use crate::prelude::*;
+
+use super::Device;
+
+// Bump when the file layout or anything that changes generated code without showing up in the
+// key changes.
+const VERSION: &str = concat!("storm kernel cache v1 ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone)]
+pub struct DiskCacheEntry {
+    pub name: String,
+    pub prg_str: String,
+    pub global_size: Vec<usize>,
+    pub local_size: Vec<usize>,
+    pub binary: Vec<u8>,
+}
+
+pub fn enabled() -> bool {
+    getenv::<usize>("DISKCACHE", 0) == 1
+}
+
+pub fn cache_dir() -> PathBuf {
+    let dir = getenv::<String>("CACHE_DIR", "".into());
+    if dir.len() > 0 {
+        return dir.into();
+    }
+    if let Ok(xdg) = std::env::var("XDG_CACHE_HOME") {
+        return PathBuf::from(xdg).join("storm");
+    }
+    PathBuf::from(std::env::var("HOME").unwrap_or(".".into()))
+        .join(".cache")
+        .join("storm")
+}
+
+// FNV-1a, std's hasher is not guaranteed to be stable across releases.
+fn stable_hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Everything besides the AST that changes what a device generates
+fn opts_key(device: &dyn Device) -> String {
+    let mut lang_opts = (*device.renderer().lang_opts()).clone();
+    let mut workitem = lang_opts.code_for_workitem.drain().collect::<Vec<_>>();
+    workitem.sort();
+    let mut type_map = v![(k.type_name, v), for (k, v) in lang_opts.type_map.drain()];
+    type_map.sort();
+    format!(
+        "{VERSION}\n{}\n{:?}\n{:?}\n{:?}\n{:?}",
+        device.name(),
+        device.linearizer_opts(),
+        lang_opts,
+        workitem,
+        type_map
+    )
+}
+
+fn path(device: &dyn Device, key: &str) -> PathBuf {
+    cache_dir()
+        .join(device.name().to_lowercase())
+        .join(format!("{:016x}", stable_hash(key)))
+}
+
+fn write_bytes(out: &mut Vec<u8>, b: &[u8]) {
+    out.extend((b.len() as u64).to_le_bytes());
+    out.extend(b);
+}
+
+fn write_sizes(out: &mut Vec<u8>, s: &[usize]) {
+    write_bytes(out, &v![(*x as u64).to_le_bytes(), for x in s.iter()].concat());
+}
+
+fn read_bytes<'a>(b: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u64::from_le_bytes(b.get(..8)?.try_into().ok()?) as usize;
+    let end = len.checked_add(8)?;
+    let ret = b.get(8..end)?;
+    *b = &b[end..];
+    Some(ret)
+}
+
+fn read_string(b: &mut &[u8]) -> Option<String> {
+    String::from_utf8(read_bytes(b)?.to_vec()).ok()
+}
+
+// None when the bytes aren't whole u64s, the entry is corrupt
+fn read_sizes(b: &mut &[u8]) -> Option<Vec<usize>> {
+    let b = read_bytes(b)?;
+    if b.len() % 8 != 0 {
+        return None;
+    }
+    Some(v![u64::from_le_bytes(x.try_into().unwrap()) as usize, for x in b.chunks_exact(8)])
+}
+
+// Reads the file at `path` and returns what follows its header. Files written by another version,
+// with different device options or for another key are stale and get removed.
+fn read_file(path: &Path, device: &dyn Device, key: &str) -> Option<Vec<u8>> {
+    let mut buf = vec![];
+    std::fs::File::open(path).ok()?.read_to_end(&mut buf).ok()?;
+    let b = &mut buf.as_slice();
+    let body = (|| {
+        if read_string(b)? != opts_key(device) || read_string(b)? != key {
+            return None;
+        }
+        Some(b.to_vec())
+    })();
+    if body.is_none() {
+        std::fs::remove_file(path).ok();
+    }
+    body
+}
+
+fn write_file(path: &Path, device: &dyn Device, key: &str, body: &[u8]) {
+    let mut out = vec![];
+    write_bytes(&mut out, opts_key(device).as_bytes());
+    write_bytes(&mut out, key.as_bytes());
+    out.extend(body);
+    // Write then rename so a concurrent reader never sees half an entry
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    let ok = std::fs::create_dir_all(path.parent().unwrap()).is_ok()
+        && std::fs::write(&tmp, out).is_ok()
+        && std::fs::rename(&tmp, path).is_ok();
+    if !ok && DEBUG.0.contains("CACHE") {
+        println!("failed to write kernel cache entry {path:?}");
+    }
+}
+
+pub fn load(device: &dyn Device, key: &str) -> Option<DiskCacheEntry> {
+    let path = path(device, key);
+    let buf = read_file(&path, device, key)?;
+    let b = &mut buf.as_slice();
+    let entry = (|| {
+        Some(DiskCacheEntry {
+            name: read_string(b)?,
+            prg_str: read_string(b)?,
+            global_size: read_sizes(b)?,
+            local_size: read_sizes(b)?,
+            binary: read_bytes(b)?.to_vec(),
+        })
+    })();
+    if entry.is_none() {
+        std::fs::remove_file(&path).ok();
+    }
+    entry
+}
+
+pub fn store(device: &dyn Device, key: &str, entry: &DiskCacheEntry) {
+    let mut out = vec![];
+    write_bytes(&mut out, entry.name.as_bytes());
+    write_bytes(&mut out, entry.prg_str.as_bytes());
+    write_sizes(&mut out, &entry.global_size);
+    write_sizes(&mut out, &entry.local_size);
+    write_bytes(&mut out, &entry.binary);
+    write_file(&path(device, key), device, key, &out);
+}
+
+// Beam search results are kept apart from compiled kernels, they are a lot more expensive to
+// recreate and are stored whether or not DISKCACHE is set.
+fn opts_path(device: &dyn Device, key: &str) -> PathBuf {
+    cache_dir()
+        .join(device.name().to_lowercase())
+        .join("beam")
+        .join(format!("{:016x}", stable_hash(key)))
+}
+
+fn parse_opt(line: &str) -> Option<Opt> {
+    use OptOps::*;
+    let parse_arg = |s: &str| if s == "-" { Some(None) } else { s.parse().ok().map(Some) };
+    let mut it = line.split(' ');
+    let op = match it.next()? {
+        "UPCAST" => UPCAST,
+        "UPCASTMID" => UPCASTMID,
+        "UNROLL" => UNROLL,
+        "LOCAL" => LOCAL,
+        "LASTLOCAL" => LASTLOCAL,
+        "GROUP" => GROUP,
+        "GROUPTOP" => GROUPTOP,
+        "NOLOCALS" => NOLOCALS,
+        "PADTO" => PADTO,
+        _ => return None,
+    };
+    Some(Opt {
+        op,
+        axis: parse_arg(it.next()?)?,
+        amt: parse_arg(it.next()?)?,
+    })
+}
+
+// Opts are stored one `op axis amt` per line, `-` for a missing axis or amt
+pub fn load_opts(device: &dyn Device, key: &str) -> Option<Vec<Opt>> {
+    let path = opts_path(device, key);
+    let body = String::from_utf8(read_file(&path, device, key)?).ok();
+    let opts = body.and_then(|body| v![parse_opt(l), for l in body.lines()].into_iter().collect());
+    if opts.is_none() {
+        std::fs::remove_file(&path).ok();
+    }
+    opts
+}
+
+pub fn store_opts(device: &dyn Device, key: &str, opts: &[Opt]) {
+    let fmt = |x: Option<isize>| x.map_or("-".to_string(), |x| x.to_string());
+    let body = v![format!("{:?} {} {}\n", o.op, fmt(o.axis), fmt(o.amt)), for o in opts].concat();
+    write_file(&opts_path(device, key), device, key, body.as_bytes());
+}
@@ -0,0 +1,508 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use half::{bf16, f16};
+
+use crate::arg::Arg;
+use crate::codegen::linearizer::{Linearizer, LinearizerOptions, UOp, UOps};
+use crate::ops::{Binary, OpType, Ternary, Unary};
+use crate::prelude::*;
+use crate::renderer::cstyle::{LanguageOpts, Renderer};
+
+use super::{Buffer, Device, Program};
+
+// Runs the linearizer's uops directly, one work item at a time. Slow, but needs no toolchain at
+// runtime, so it also works on wasm.
+#[derive(Debug, Default)]
+pub struct InterpreterDevice {
+    uops: Mutex<HashMap<String, Vec<UOp>>>,
+}
+
+impl InterpreterDevice {
+    pub fn new() -> anyhow::Result<Arc<dyn Device>> {
+        Ok(Arc::new(Self::default()))
+    }
+}
+
+// Memory is a leaked Box<Vec<u8>>, the pointer handed out is the box itself.
+unsafe fn host_mem<'a>(ptr: *mut core::ffi::c_void) -> &'a mut Vec<u8> {
+    &mut *(ptr as *mut Vec<u8>)
+}
+
+#[derive(Debug, Clone)]
+pub struct InterpreterBuffer {
+    ptr: *mut core::ffi::c_void,
+    bytesize: usize,
+    dtype: Dtype,
+}
+
+impl Buffer for InterpreterBuffer {
+    fn device(&self) -> String {
+        "INTERPRETER".into()
+    }
+
+    fn ptr(&self) -> *mut core::ffi::c_void {
+        self.ptr
+    }
+
+    fn dtype(&self) -> Dtype {
+        self.dtype.clone()
+    }
+
+    fn bytesize(&self) -> usize {
+        self.bytesize
+    }
+
+    fn to_cpu(&self) -> Vec<u8> {
+        let mut dst = vec![0u8; self.bytesize()];
+        let ptr = dst.as_mut_ptr() as *mut u8;
+        let device = get_device(&self.device()).unwrap();
+        device.copyout(self, ptr);
+        device.synchronize();
+        dst
+    }
+}
+
+impl Drop for InterpreterBuffer {
+    fn drop(&mut self) {
+        ALLOCTOR.0.free(&*self)
+    }
+}
+
+// A uop with its sources resolved to positions in the program
+#[derive(Debug, Clone)]
+struct Inst {
+    uop: UOps,
+    dtype: Option<Dtype>,
+    vin: Vec<usize>,
+    args: Vec<Arg>,
+    // LOOP/IF: position of the matching END, END: position of the LOOP/IF it closes
+    jump: usize,
+    // DEFINE_GLOBAL: index into the buffers passed to run()
+    buf: usize,
+}
+
+#[derive(Debug)]
+pub struct InterpreterProgram {
+    name: String,
+    insts: Vec<Inst>,
+}
+
+// What load and store can read and write
+const HOST_DTYPES: [&str; 13] =
+    ["bool", "f16", "bf16", "f32", "f64", "i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64"];
+
+impl InterpreterProgram {
+    // Errors on uops the interpreter can't run, like local memory that needs work items in lockstep
+    fn new(name: &str, program: &str, uops: &[UOp]) -> Result<Self, StormError> {
+        let unsupported = |what: String| StormError::CompileError {
+            name: name.to_string(),
+            program: program.to_string(),
+            log: format!("{what} is not supported by the interpreter"),
+        };
+        let idxs: HashMap<&UOp, usize> = HashMap::from_iter(v![(u, i), for (i, u) in uops.iter().enumerate()]);
+        let mut insts: Vec<Inst> = vec![];
+        let mut n_bufs = 0;
+        for (i, u) in uops.iter().enumerate() {
+            // PHI writes back into its accumulator, so it shares the accumulator's slot
+            let vin = v![insts[idxs[x]].slot(idxs[x]), for x in u.vin.iter()];
+            let mut inst = Inst {
+                uop: u.uop.clone(),
+                dtype: u.dtype.clone(),
+                vin,
+                args: u.args.clone(),
+                jump: 0,
+                buf: 0,
+            };
+            match u.uop {
+                UOps::END => {
+                    let start = idxs[&u.vin[0]];
+                    inst.jump = start;
+                    insts[start].jump = i;
+                }
+                UOps::DEFINE_GLOBAL => {
+                    let dtype = u.dtype.as_ref().unwrap();
+                    if !HOST_DTYPES.contains(&dtype.type_name) {
+                        return Err(StormError::UnsupportedDtype {
+                            dtype: dtype.type_name.to_string(),
+                            on: "INTERPRETER".into(),
+                        });
+                    }
+                    inst.buf = n_bufs;
+                    n_bufs += u.args.len();
+                }
+                UOps::ALU => {
+                    let op = u.args[0].to_op();
+                    if alu(&op, &[0.; 3], u.dtype.as_ref().unwrap()).is_none() {
+                        return Err(unsupported(format!("{op:?}")));
+                    }
+                }
+                UOps::DEFINE_LOCAL | UOps::BARRIER | UOps::WMMA => {
+                    return Err(unsupported(format!("{:?}", u.uop)));
+                }
+                _ => (),
+            }
+            insts.push(inst);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            insts,
+        })
+    }
+
+    fn exec(&self, bufs: &[(*mut u8, usize)], gid: &[usize], lid: &[usize], vals: &mut [[f64; 4]]) {
+        let mut pc = 0;
+        while pc < self.insts.len() {
+            let inst = &self.insts[pc];
+            let dtype = inst.dtype.as_ref();
+            let vin = &inst.vin;
+            match inst.uop {
+                UOps::DEFINE_GLOBAL => {
+                    vals[pc][0] = inst.buf as f64;
+                }
+                UOps::SPECIAL => {
+                    let dim = match &inst.args[0] {
+                        Arg::Usize(d) => *d,
+                        t => panic!("{t:?}"),
+                    };
+                    let name = inst.args[1].to_str();
+                    vals[pc][0] = if name.starts_with("l") { lid[dim] } else { gid[dim] } as f64;
+                }
+                UOps::CONST | UOps::DEFINE_ACC => {
+                    let x = match &inst.args[0] {
+                        Arg::Idx(i) => *i as f64,
+                        a => a.to_str().parse::<f64>().unwrap(),
+                    };
+                    vals[pc] = [cast(x, dtype.unwrap()); 4];
+                }
+                UOps::LOOP => {
+                    vals[pc][0] = vals[vin[0]][0];
+                    if vals[pc][0] >= vals[vin[1]][0] {
+                        pc = inst.jump;
+                    }
+                }
+                UOps::IF => {
+                    if vals[vin[0]][0] == 0.0 {
+                        pc = inst.jump;
+                    }
+                }
+                UOps::END => {
+                    let start = &self.insts[inst.jump];
+                    if start.uop == UOps::LOOP {
+                        vals[inst.jump][0] += 1.0;
+                        if vals[inst.jump][0] < vals[start.vin[1]][0] {
+                            pc = inst.jump;
+                        }
+                    }
+                }
+                UOps::LOAD => {
+                    let dtype = dtype.unwrap();
+                    if vin.len() > 3 && vals[vin[2]][0] == 0.0 {
+                        vals[pc] = vals[vin[3]];
+                    } else {
+                        let buf_dtype = self.insts[vin[0]].dtype.as_ref().unwrap();
+                        let (ptr, len) = bufs[vals[vin[0]][0] as usize];
+                        let idx = vals[vin[1]][0] as isize;
+                        for j in 0..dtype.sz {
+                            vals[pc][j] = cast(load(ptr, len, buf_dtype, idx + j as isize), dtype);
+                        }
+                    }
+                }
+                UOps::STORE => {
+                    if vin.len() <= 3 || vals[vin[3]][0] != 0.0 {
+                        let buf_dtype = self.insts[vin[0]].dtype.as_ref().unwrap();
+                        let sz = self.insts[vin[2]].dtype.as_ref().map_or(1, |d| d.sz);
+                        let (ptr, len) = bufs[vals[vin[0]][0] as usize];
+                        let idx = vals[vin[1]][0] as isize;
+                        for j in 0..sz {
+                            store(ptr, len, buf_dtype, idx + j as isize, vals[vin[2]][j]);
+                        }
+                    }
+                }
+                UOps::ALU => {
+                    let dtype = dtype.unwrap();
+                    let op = inst.args[0].to_op();
+                    let mut x = [0f64; 3];
+                    for j in 0..dtype.sz {
+                        for (k, s) in vin.iter().enumerate() {
+                            x[k] = vals[*s][j];
+                        }
+                        vals[pc][j] = cast(alu(&op, &x, dtype).unwrap(), dtype);
+                    }
+                }
+                UOps::CAST => {
+                    let dtype = dtype.unwrap();
+                    if vin.len() == 1 {
+                        vals[pc] = [cast(vals[vin[0]][0], dtype); 4];
+                    } else {
+                        for (j, s) in vin.iter().enumerate() {
+                            vals[pc][j] = vals[*s][0];
+                        }
+                    }
+                }
+                UOps::GEP => {
+                    let j = inst.args[0].to_str().parse::<usize>().unwrap();
+                    vals[pc] = [vals[vin[0]][j]; 4];
+                }
+                UOps::PHI => {
+                    vals[vin[0]] = vals[vin[1]];
+                }
+                _ => unreachable!(),
+            }
+            pc += 1;
+        }
+    }
+}
+
+impl Inst {
+    fn slot(&self, i: usize) -> usize {
+        if self.uop == UOps::PHI {
+            self.vin[0]
+        } else {
+            i
+        }
+    }
+}
+
+pub(crate) fn cast(x: f64, dtype: &Dtype) -> f64 {
+    match dtype.type_name {
+        "bool" => (x != 0.0) as u8 as f64,
+        "f16" => f16::from_f64(x).to_f64(),
+        "bf16" => bf16::from_f64(x).to_f64(),
+        "f32" => x as f32 as f64,
+        "i8" => x as i64 as i8 as f64,
+        "i16" => x as i64 as i16 as f64,
+        "i32" => x as i64 as i32 as f64,
+        "i64" => x as i64 as f64,
+        "u8" => x as i64 as u8 as f64,
+        "u16" => x as i64 as u16 as f64,
+        "u32" => x as i64 as u32 as f64,
+        "u64" => x as u64 as f64,
+        _ => x,
+    }
+}
+
+// Bit ops on i128 so neither u64 above i64::MAX nor negative i64 gets clamped, wrapped to 64 bits
+// like C and to the dtype by the cast after. Slots are f64, so 64 bit ints are only exact below
+// 2^53, here as in every other op.
+fn bits(x: &[f64], dtype: &Dtype, f: impl Fn(i128, i128) -> i128) -> f64 {
+    let r = f(x[0] as i128, x[1] as i128);
+    if dtype.is_unsigned() { r as u64 as f64 } else { r as i64 as f64 }
+}
+
+// None for ops that aren't ALU ops
+pub(crate) fn alu(op: &OpType, x: &[f64], dtype: &Dtype) -> Option<f64> {
+    Some(match op {
+        OpType::Unary(Unary::Neg) => -x[0],
+        OpType::Unary(Unary::Exp2) => x[0].exp2(),
+        OpType::Unary(Unary::Log2) => x[0].log2(),
+        OpType::Unary(Unary::Sin) => x[0].sin(),
+        OpType::Unary(Unary::Sqrt) => x[0].sqrt(),
+        OpType::Unary(Unary::Noop | Unary::Cast) => x[0],
+        OpType::Binary(Binary::Add) => x[0] + x[1],
+        OpType::Binary(Binary::Sub) => x[0] - x[1],
+        OpType::Binary(Binary::Mul) => x[0] * x[1],
+        OpType::Binary(Binary::Div) if dtype.is_int() => {
+            if x[1] == 0.0 {
+                0.0
+            } else {
+                (x[0] / x[1]).trunc()
+            }
+        }
+        OpType::Binary(Binary::Div) => x[0] / x[1],
+        // f64 % truncates like C's %
+        OpType::Binary(Binary::Mod) => x[0] % x[1],
+        OpType::Binary(Binary::Max) => x[0].max(x[1]),
+        OpType::Binary(Binary::Cmplt) => (x[0] < x[1]) as u8 as f64,
+        OpType::Binary(Binary::Shr) => bits(x, dtype, |a, b| a.wrapping_shr(b as u32)),
+        OpType::Binary(Binary::And) => bits(x, dtype, |a, b| a & b),
+        OpType::Binary(Binary::Or) => bits(x, dtype, |a, b| a | b),
+        OpType::Binary(Binary::Xor) => bits(x, dtype, |a, b| a ^ b),
+        OpType::Binary(Binary::Shl) => bits(x, dtype, |a, b| a.wrapping_shl(b as u32)),
+        OpType::Ternary(Ternary::Mulacc) => x[0] * x[1] + x[2],
+        OpType::Ternary(Ternary::Where) => {
+            if x[0] != 0.0 {
+                x[1]
+            } else {
+                x[2]
+            }
+        }
+        _ => return None,
+    })
+}
+
+fn offset(len: usize, dtype: &Dtype, idx: isize) -> usize {
+    assert!(
+        idx >= 0 && (idx as usize + 1) * dtype.size <= len,
+        "out of bounds access at {idx} in buffer of {} {}",
+        len / dtype.size,
+        dtype.type_name
+    );
+    idx as usize * dtype.size
+}
+
+fn load(ptr: *mut u8, len: usize, dtype: &Dtype, idx: isize) -> f64 {
+    unsafe {
+        let p = ptr.add(offset(len, dtype, idx));
+        match dtype.type_name {
+            "bool" => (p.read() != 0) as u8 as f64,
+            "f16" => (p as *const f16).read_unaligned().to_f64(),
+            "bf16" => (p as *const bf16).read_unaligned().to_f64(),
+            "f32" => (p as *const f32).read_unaligned() as f64,
+            "f64" => (p as *const f64).read_unaligned(),
+            "i8" => (p as *const i8).read_unaligned() as f64,
+            "i16" => (p as *const i16).read_unaligned() as f64,
+            "i32" => (p as *const i32).read_unaligned() as f64,
+            "i64" => (p as *const i64).read_unaligned() as f64,
+            "u8" => p.read() as f64,
+            "u16" => (p as *const u16).read_unaligned() as f64,
+            "u32" => (p as *const u32).read_unaligned() as f64,
+            "u64" => (p as *const u64).read_unaligned() as f64,
+            t => unreachable!("load of {t}"),
+        }
+    }
+}
+
+fn store(ptr: *mut u8, len: usize, dtype: &Dtype, idx: isize, x: f64) {
+    unsafe {
+        let p = ptr.add(offset(len, dtype, idx));
+        match dtype.type_name {
+            "bool" => p.write((x != 0.0) as u8),
+            "f16" => (p as *mut f16).write_unaligned(f16::from_f64(x)),
+            "bf16" => (p as *mut bf16).write_unaligned(bf16::from_f64(x)),
+            "f32" => (p as *mut f32).write_unaligned(x as f32),
+            "f64" => (p as *mut f64).write_unaligned(x),
+            "i8" => (p as *mut i8).write_unaligned(x as i8),
+            "i16" => (p as *mut i16).write_unaligned(x as i16),
+            "i32" => (p as *mut i32).write_unaligned(x as i32),
+            "i64" => (p as *mut i64).write_unaligned(x as i64),
+            "u8" => p.write(x as u8),
+            "u16" => (p as *mut u16).write_unaligned(x as u16),
+            "u32" => (p as *mut u32).write_unaligned(x as u32),
+            "u64" => (p as *mut u64).write_unaligned(x as u64),
+            t => unreachable!("store of {t}"),
+        }
+    }
+}
+
+impl Program for InterpreterProgram {
+    fn run(
+        &self,
+        bufs: &[Arc<dyn Buffer>],
+        global_size: &[usize],
+        local_size: Option<&[usize]>,
+        args: &[isize],
+        extra: &[String],
+    ) {
+        let bufs = v![unsafe { let m = host_mem(b.ptr()); (m.as_mut_ptr(), m.len()) }, for b in bufs.iter()];
+        let pad = |s: &[usize]| {
+            let mut s = s.to_vec();
+            s.extend(vec![1; 3 - s.len()]);
+            s
+        };
+        let global_size = pad(global_size);
+        let local_size = pad(local_size.unwrap_or(&[]));
+        let mut vals = vec![[0f64; 4]; self.insts.len()];
+        for g2 in 0..global_size[2] {
+            for g1 in 0..global_size[1] {
+                for g0 in 0..global_size[0] {
+                    for l2 in 0..local_size[2] {
+                        for l1 in 0..local_size[1] {
+                            for l0 in 0..local_size[0] {
+                                self.exec(&bufs, &[g0, g1, g2], &[l0, l1, l2], &mut vals);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Device for InterpreterDevice {
+    fn name(&self) -> String {
+        "INTERPRETER".into()
+    }
+
+    fn _alloc(&self, size: usize, dtype: Dtype) -> anyhow::Result<Arc<dyn Buffer>> {
+        let mem = Box::new(vec![0u8; size * dtype.size]);
+        Ok(self.buf_from_mem_ptr(size, dtype, Box::into_raw(mem) as _))
+    }
+
+    fn buf_from_mem_ptr(
+        &self,
+        size: usize,
+        dtype: Dtype,
+        mem: *mut std::ffi::c_void,
+    ) -> Arc<dyn Buffer> {
+        Arc::new(InterpreterBuffer {
+            ptr: mem,
+            bytesize: size * dtype.size,
+            dtype,
+        })
+    }
+
+    fn render(&self, mut lin: Linearizer) -> (String, String) {
+        lin.linearize();
+        let idxs: HashMap<&UOp, usize> = HashMap::from_iter(v![(u, i), for (i, u) in lin.uops.iter().enumerate()]);
+        let prg = v![format!("{i:4} {:?} {:?} {:?} {:?}", u.uop, u.dtype.as_ref().map(|d| d.type_name), v![idxs[x], for x in u.vin.iter()], u.args), for (i, u) in lin.uops.iter().enumerate()];
+        self.uops
+            .lock()
+            .unwrap()
+            .insert(lin.name.clone(), lin.uops);
+        (lin.name, prg.join("\n"))
+    }
+
+    fn try_build(&self, name: &str, program: &str) -> Result<Arc<dyn Program>, StormError> {
+        let uops = self.uops.lock().unwrap().remove(name).ok_or_else(|| {
+            StormError::Internal(format!("interpreter can only build programs it rendered, not {name}"))
+        })?;
+        Ok(Arc::new(InterpreterProgram::new(name, program, &uops)?))
+    }
+
+    fn copyout(&self, src: &dyn Buffer, dst: *mut u8) {
+        unsafe {
+            let m = host_mem(src.ptr());
+            std::ptr::copy_nonoverlapping(m.as_ptr(), dst, src.bytesize());
+        }
+    }
+
+    fn copyin(&self, src: &[u8], dst: &dyn Buffer) {
+        unsafe {
+            let m = host_mem(dst.ptr());
+            let n = src.len().min(dst.bytesize());
+            m[..n].copy_from_slice(&src[..n]);
+        }
+    }
+
+    fn synchronize(&self) {}
+
+    fn linearizer_opts(&self) -> LinearizerOptions {
+        LinearizerOptions {
+            has_share: false,
+            ..Default::default()
+        }
+    }
+
+    fn renderer(&self) -> Arc<dyn Renderer> {
+        Arc::new(InterpreterRenderer::default())
+    }
+
+    fn free(&self, ptr: *mut std::ffi::c_void) {
+        unsafe { drop(Box::from_raw(ptr as *mut Vec<u8>)) }
+    }
+}
+
+// Only here to satisfy Device::renderer, programs are never rendered to source
+#[derive(Debug, Default)]
+pub struct InterpreterRenderer {
+    opts: Arc<LanguageOpts>,
+}
+
+impl crate::ops::Op for InterpreterRenderer {}
+
+impl Renderer for InterpreterRenderer {
+    fn lang_opts(&self) -> Arc<LanguageOpts> {
+        self.opts.clone()
+    }
+}
@@ -1,63 +1,97 @@
-use anyhow::anyhow;
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use crate::{
     codegen::linearizer::{Linearizer, LinearizerOptions},
     dtype::Dtype,
+    error::{Result, StormError},
     ops::{getenv, LazyOp},
     renderer::cstyle::{uops_to_cstyle, LanguageOpts, Renderer},
     shape::symbolic::NodeOp,
 };
 
+// Every backend by name, in the order DEVICE tries them when it isn't set
 lazy_static::lazy_static! {
-    pub static ref DEVICES: Vec<fn() -> anyhow::Result<Arc<dyn Device>>> = vec![
+    pub static ref DEVICES: Vec<(&'static str, fn() -> anyhow::Result<Arc<dyn Device>>)> = vec![
         #[cfg(not(any(target_os = "macos", target_arch = "wasm32")))]
-        cuda::CudaDevice::new,
+        ("CUDA", cuda::CudaDevice::new),
         #[cfg(not(any(target_arch = "wasm32")))]
-        opencl::CLDevice::new,
-        wgpu::WGPUDevice::new,
+        ("OPENCL", opencl::CLDevice::new),
+        ("WGPU", wgpu::WGPUDevice::new),
+        #[cfg(not(any(target_arch = "wasm32")))]
+        ("CLANG", clang::ClangDevice::new),
+        ("INTERPRETER", interpreter::InterpreterDevice::new),
+        ("DISK", disk::DiskDevice::new),
     ];
 }
 
+lazy_static::lazy_static! {
+    pub static ref OPEN_DEVICES: Mutex<HashMap<String, Arc<dyn Device>>> = Default::default();
+}
+
 lazy_static::lazy_static! {
     pub static ref DEVICE: Arc<dyn Device> = {
         let d_name = getenv::<String>("DEVICE", "".into()).to_string().to_uppercase();
-        let mut d = vec![];
-        for func in DEVICES.iter() {
+        if d_name.len() > 0 {
+            return get_device(&d_name).expect("no available backend found");
+        }
+        let mut opened = OPEN_DEVICES.lock().unwrap();
+        // DISK only holds files, it can't run kernels
+        for (_, func) in DEVICES.iter().filter(|(name, _)| *name != "DISK") {
             if let Ok(device) = func() {
-                if d_name.len() > 0  {
-                    if d_name == device.name() {
-                        d.push(device);
-                        break;
-                    }
-                } else {
-                    d.push(device);
-                    break;
-                }
+                return opened.entry(device.name()).or_insert(device).clone();
             }
         }
-        if d.len() == 0 {
-            panic!("no available backend found")
-        }
-        d[0].to_owned()
+        panic!("no available backend found")
     };
 }
 
+// Devices only have one instance each, so "CUDA:0" and "CUDA" are the same device
+pub fn canonicalize_device(name: &str) -> String {
+    let name = name.to_uppercase();
+    name.strip_suffix(":0").unwrap_or(&name).to_string()
+}
+
+// Returns the live device with this name, opening it on first use
+pub fn get_device(name: &str) -> Result<Arc<dyn Device>> {
+    let name = canonicalize_device(name);
+    let mut opened = OPEN_DEVICES.lock().unwrap();
+    if let Some(device) = opened.get(&name) {
+        return Ok(device.clone());
+    }
+    // Only the matching backend is opened, the others never touch their drivers
+    let (_, func) = DEVICES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .ok_or_else(|| StormError::NoDevice(name.clone()))?;
+    // The backend exists but its driver failed, keep what the driver said
+    let device =
+        func().map_err(|e| StormError::Internal(format!("failed to open {name}: {e:#}")))?;
+    opened.insert(name, device.clone());
+    Ok(device)
+}
+
 // #[derive(Default, Debug)]
 // pub struct PendingCopy(Vec<Vec<u8>>);
 //
 // unsafe impl Send for PendingCopy {}
 // unsafe impl Sync for PendingCopy {}
 
+pub mod clang;
 pub mod cuda;
+pub mod disk;
+pub mod diskcache;
+pub mod interpreter;
 pub mod opencl;
 pub mod wgpu;
 
 pub mod prelude {
-    pub use super::{ALLOCTOR, DEVICE};
+    pub use super::{get_device, ALLOCTOR, DEVICE};
 }
 
 pub trait Device: Send + Sync + core::fmt::Debug {
@@ -67,7 +101,10 @@ pub trait Device: Send + Sync + core::fmt::Debug {
     fn name(&self) -> String;
     fn _alloc(&self, size: usize, dtype: Dtype) -> anyhow::Result<Arc<dyn Buffer>>;
     fn alloc(&self, size: usize, dtype: Dtype) -> Arc<dyn Buffer> {
-        ALLOCTOR.0.alloc(size, dtype)
+        self.try_alloc(size, dtype).unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_alloc(&self, size: usize, dtype: Dtype) -> Result<Arc<dyn Buffer>> {
+        ALLOCTOR.0.try_alloc(&self.name(), size, dtype)
     }
     fn buf_from_mem_ptr(
         &self,
@@ -75,9 +112,20 @@ pub trait Device: Send + Sync + core::fmt::Debug {
         dtype: Dtype,
         mem: *mut std::ffi::c_void,
     ) -> Arc<dyn Buffer>;
-    fn build(&self, name: &str, program: &str) -> Arc<dyn Program>;
+    fn build(&self, name: &str, program: &str) -> Arc<dyn Program> {
+        self.try_build(name, program).unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_build(&self, name: &str, program: &str) -> Result<Arc<dyn Program>>;
+    // Devices that can turn a program into a standalone binary get their kernels cached on disk
+    fn compile(&self, name: &str, program: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+    // Errors when the binary can't be loaded, callers fall back to building from source
+    fn load(&self, name: &str, binary: &[u8]) -> Result<Arc<dyn Program>> {
+        Err(StormError::Internal(format!("{} can not load program binaries", self.name())))
+    }
     fn copyout(&self, src: &dyn Buffer, dst: *mut u8);
-    fn copyin(&self, src: Vec<u8>, dst: &dyn Buffer);
+    fn copyin(&self, src: &[u8], dst: &dyn Buffer);
     fn synchronize(&self);
     fn linearizer_opts(&self) -> LinearizerOptions {
         LinearizerOptions::default()
@@ -114,60 +162,183 @@ pub trait Buffer: core::fmt::Debug {
     fn dtype(&self) -> Dtype;
     fn bytesize(&self) -> usize;
     fn to_cpu(&self) -> Vec<u8>;
+    // The bytes when the host can read them in place, copies to a device skip to_cpu then
+    fn host_bytes(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    // Bytes handed out and not freed yet
+    pub in_use: usize,
+    // Bytes freed into the cache, kept for reuse
+    pub cached: usize,
+    // Most bytes in use at once
+    pub peak: usize,
+    // Allocations that went to the device
+    pub allocs: usize,
+    // Allocations served from the cache
+    pub cache_hits: usize,
 }
 
+// Freed pointers of one device by bytesize, each tagged with when it was freed
 #[derive(Default)]
+struct Pool {
+    cached: HashMap<usize, VecDeque<(u64, *mut std::ffi::c_void)>>,
+    tick: u64,
+    stats: MemoryStats,
+}
+
+impl Pool {
+    fn take(&mut self, bytes: usize) -> Option<*mut std::ffi::c_void> {
+        let (_, ptr) = self.cached.get_mut(&bytes)?.pop_back()?;
+        self.stats.cached -= bytes;
+        Some(ptr)
+    }
+
+    // Least recently freed first
+    fn evict(&mut self) -> Option<*mut std::ffi::c_void> {
+        let (&bytes, mems) = self
+            .cached
+            .iter_mut()
+            .filter(|(_, mems)| !mems.is_empty())
+            .min_by_key(|(_, mems)| mems[0].0)?;
+        let (_, ptr) = mems.pop_front().unwrap();
+        self.stats.cached -= bytes;
+        Some(ptr)
+    }
+
+    fn drain(&mut self) -> Vec<*mut std::ffi::c_void> {
+        self.stats.cached = 0;
+        self.cached.drain().flat_map(|(_, mems)| mems.into_iter().map(|(_, p)| p)).collect()
+    }
+}
+
+// Caches freed buffers per device for reuse when LRU is 1, the default. Each device caches at
+// most LRU_LIMIT bytes, 1GB by default and 0 for no limit, evicting the least recently freed. Both
+// are read once, when the allocator is first used. Every device has its own pool and lock, so
+// buffers dropped on one don't wait on another, and device frees run with no lock held.
 pub struct Allocator {
-    pub cached: Arc<HashMap<usize, Vec<*mut std::ffi::c_void>>>, // <Bytesize, Vec<_>>
+    pools: Mutex<HashMap<String, Arc<Mutex<Pool>>>>,
+    lru: bool,
+    limit: AtomicUsize,
 }
 
 unsafe impl Send for Allocator {}
 unsafe impl Sync for Allocator {}
 
+impl Default for Allocator {
+    fn default() -> Self {
+        Self {
+            pools: Mutex::default(),
+            lru: getenv::<String>("LRU", "1".into()) == "1",
+            limit: AtomicUsize::new(getenv("LRU_LIMIT", 1 << 30)),
+        }
+    }
+}
+
 impl Allocator {
-    pub fn alloc(&self, size: usize, dtype: Dtype) -> Arc<dyn Buffer> {
-        unsafe {
-            let mut cc = self.cached.clone();
-            let cached = Arc::get_mut_unchecked(&mut cc);
-            if let Some(mems) = cached.get_mut(&(size * dtype.size))
-                && !mems.is_empty()
-            {
-                DEVICE.buf_from_mem_ptr(size, dtype, mems.pop().unwrap())
+    pub fn alloc(&self, device: &str, size: usize, dtype: Dtype) -> Arc<dyn Buffer> {
+        self.try_alloc(device, size, dtype).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn pool(&self, device: &str) -> Arc<Mutex<Pool>> {
+        self.pools.lock().unwrap().entry(device.to_string()).or_default().clone()
+    }
+
+    // Falls back to freeing the device's cached buffers when it is full
+    pub fn try_alloc(&self, device: &str, size: usize, dtype: Dtype) -> Result<Arc<dyn Buffer>> {
+        let device = get_device(device)?;
+        let bytes = size * dtype.size;
+        let pool = self.pool(&device.name());
+        let cached = {
+            let mut pool = pool.lock().unwrap();
+            let ptr = pool.take(bytes);
+            pool.stats.in_use += bytes;
+            pool.stats.peak = pool.stats.peak.max(pool.stats.in_use);
+            if ptr.is_some() {
+                pool.stats.cache_hits += 1;
             } else {
-                if let Ok(b) = DEVICE._alloc(size, dtype.clone()) {
-                    b
-                } else {
-                    self.free_cached();
-                    DEVICE._alloc(size, dtype).unwrap()
-                }
+                pool.stats.allocs += 1;
             }
+            ptr
+        };
+        if let Some(ptr) = cached {
+            return Ok(device.buf_from_mem_ptr(size, dtype, ptr));
         }
+        device._alloc(size, dtype.clone()).or_else(|_| {
+            self.free_device(&device);
+            device._alloc(size, dtype).map_err(|_| {
+                let stats = &mut pool.lock().unwrap().stats;
+                stats.in_use -= bytes;
+                stats.allocs -= 1;
+                StormError::OutOfMemory {
+                    device: device.name(),
+                    bytes,
+                }
+            })
+        })
     }
 
     pub fn free(&self, buf: &dyn Buffer) {
-        unsafe {
-            if std::env::var("LRU").unwrap_or("1".into()) == "1" {
-                let mut cc = self.cached.clone();
-                let cached = Arc::get_mut_unchecked(&mut cc);
-                cached.entry(buf.bytesize()).or_default().push(buf.ptr());
+        let device = get_device(&buf.device()).unwrap();
+        let bytes = buf.bytesize();
+        let evicted = {
+            let pool = self.pool(&device.name());
+            let mut pool = pool.lock().unwrap();
+            pool.stats.in_use = pool.stats.in_use.saturating_sub(bytes);
+            if self.lru {
+                pool.tick += 1;
+                let tick = pool.tick;
+                pool.cached.entry(bytes).or_default().push_back((tick, buf.ptr()));
+                pool.stats.cached += bytes;
+                let limit = self.cache_limit();
+                let mut evicted = vec![];
+                while limit > 0 && pool.stats.cached > limit {
+                    evicted.push(pool.evict().unwrap());
+                }
+                evicted
             } else {
-                DEVICE.free(buf.ptr());
+                vec![buf.ptr()]
             }
+        };
+        for ptr in evicted {
+            device.free(ptr);
         }
     }
 
-    pub fn free_cached(&self) {
-        for cached in self.cached.values() {
-            for opaque in cached.iter() {
-                DEVICE.free(*opaque)
-            }
+    // The most bytes each device keeps cached, 0 for no limit
+    pub fn cache_limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    // Takes over from LRU_LIMIT, caches over the new limit shrink as buffers are freed
+    pub fn set_cache_limit(&self, bytes: usize) {
+        self.limit.store(bytes, Ordering::Relaxed)
+    }
+
+    fn free_device(&self, device: &Arc<dyn Device>) {
+        let mems = self.pool(&device.name()).lock().unwrap().drain();
+        for ptr in mems {
+            device.free(ptr)
         }
-        unsafe {
-            let mut cc = self.cached.clone();
-            let cached = Arc::get_mut_unchecked(&mut cc);
-            cached.clear();
+    }
+
+    pub fn free_cached(&self) {
+        let names = Vec::from_iter(self.pools.lock().unwrap().keys().cloned());
+        for name in names {
+            self.free_device(&get_device(&name).unwrap());
         }
     }
+
+    pub fn stats(&self) -> BTreeMap<String, MemoryStats> {
+        let pools = Vec::from_iter(self.pools.lock().unwrap().clone());
+        BTreeMap::from_iter(pools.into_iter().map(|(name, pool)| {
+            let stats = pool.lock().unwrap().stats.clone();
+            (name, stats)
+        }))
+    }
 }
 
 #[derive(Default)]
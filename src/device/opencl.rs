@@ -33,7 +33,7 @@ impl CLDevice {
     pub fn new() -> anyhow::Result<Arc<dyn Device>> {
         let device_id = *get_all_devices(CL_DEVICE_TYPE_GPU)?
             .first()
-            .expect("no device found in platform");
+            .ok_or(anyhow::anyhow!("no device found in platform"))?;
         let device = opencl3::device::Device::new(device_id);
         let context = Context::from_device(&device).unwrap();
         let queue = CommandQueue::create_default(&context, CL_QUEUE_PROFILING_ENABLE)
@@ -46,6 +46,29 @@ impl CLDevice {
             renderer: Arc::new(CLRenderer::default()),
         }))
     }
+
+    fn build_source(
+        &self,
+        name: &str,
+        program: &str,
+    ) -> Result<opencl3::program::Program, StormError> {
+        opencl3::program::Program::create_and_build_from_source(&self.context, program, "").map_err(
+            |log| StormError::CompileError {
+                name: name.to_string(),
+                program: program.to_string(),
+                log: log.to_string(),
+            },
+        )
+    }
+
+    fn program(&self, name: &str, program: opencl3::program::Program) -> Arc<dyn Program> {
+        let kernel = Kernel::create(&program, name).expect("Kernel::create failed");
+        Arc::new(CLProgram {
+            device: self.clone(),
+            program,
+            kernel,
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -75,8 +98,9 @@ impl Buffer for CLBuffer {
     fn to_cpu(&self) -> Vec<u8> {
         let mut dst = vec![0u8; self.bytesize()];
         let ptr = dst.as_mut_ptr() as *mut u8;
-        DEVICE.copyout(self, ptr);
-        DEVICE.synchronize();
+        let device = get_device(&self.device()).unwrap();
+        device.copyout(self, ptr);
+        device.synchronize();
         dst
     }
 }
@@ -186,22 +210,21 @@ impl Device for CLDevice {
         }
     }
 
-    fn build(&self, name: &str, program: &str) -> Arc<dyn Program> {
+    fn try_build(&self, name: &str, program: &str) -> Result<Arc<dyn Program>, StormError> {
+        Ok(self.program(name, self.build_source(name, program)?))
+    }
+
+    fn compile(&self, name: &str, program: &str) -> Result<Option<Vec<u8>>, StormError> {
+        // One binary per device in the context, which only ever holds self.device
+        let program = self.build_source(name, program)?;
+        Ok(program.get_binaries().ok().and_then(|b| b.into_iter().next()))
+    }
+
+    fn load(&self, name: &str, binary: &[u8]) -> Result<Arc<dyn Program>, StormError> {
         let program =
-            opencl3::program::Program::create_and_build_from_source(&self.context, program, "");
-        // Need to `Display` print, panic will print message in Debug which will escape characters
-        // like new lines `\n`
-        if program.is_err() {
-            println!("{}", program.err().unwrap());
-            panic!("Program::create_and_build_from_source failed");
-        };
-        let program = program.unwrap();
-        let kernel = Kernel::create(&program, name).expect("Kernel::create failed");
-        Arc::new(CLProgram {
-            device: self.clone(),
-            program,
-            kernel,
-        })
+            opencl3::program::Program::create_and_build_from_binary(&self.context, &[binary], "")
+                .map_err(|e| StormError::Internal(format!("failed to load kernel {name}: {e}")))?;
+        Ok(self.program(name, program))
     }
 
     fn copyout(&self, src: &dyn Buffer, dst: *mut u8) {
@@ -220,7 +243,7 @@ impl Device for CLDevice {
         }
     }
 
-    fn copyin(&self, mut src: Vec<u8>, dst: &dyn Buffer) {
+    fn copyin(&self, src: &[u8], dst: &dyn Buffer) {
         unsafe {
             opencl3::command_queue::enqueue_write_buffer(
                 self.queue.get(),
@@ -228,7 +251,7 @@ impl Device for CLDevice {
                 CL_BLOCKING,
                 0,
                 dst.bytesize(),
-                src.as_mut_ptr() as opencl3::memory::cl_mem,
+                src.as_ptr() as opencl3::memory::cl_mem,
                 0,
                 core::ptr::null(),
             )
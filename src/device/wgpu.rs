@@ -1,7 +1,7 @@
 use wgpu::{BindGroupLayoutEntry, ShaderModuleDescriptor};
 
 use crate::{
-    ops::{Dtype, Op, DEVICE},
+    ops::{Dtype, Op},
     prelude::*,
     renderer::cstyle::LanguageOpts,
 };
@@ -35,8 +35,9 @@ impl Buffer for WGPUBuffer {
     fn to_cpu(&self) -> Vec<u8> {
         let mut dst = vec![0u8; self.bytesize()];
         let ptr = dst.as_mut_ptr() as *mut u8;
-        DEVICE.copyout(self, ptr);
-        DEVICE.synchronize();
+        let device = get_device(&self.device()).unwrap();
+        device.copyout(self, ptr);
+        device.synchronize();
         dst
     }
 }
@@ -70,7 +71,7 @@ async fn new() -> anyhow::Result<WGPUDevice> {
     let adapter = instance
         .request_adapter(&wgpu::RequestAdapterOptions::default())
         .await
-        .unwrap();
+        .ok_or(anyhow::anyhow!("no wgpu adapter found"))?;
     let mut limits = wgpu::Limits::default();
     limits.max_buffer_size = 1 << 30;
     limits.max_storage_buffer_binding_size = 1 << 30;
@@ -84,8 +85,7 @@ async fn new() -> anyhow::Result<WGPUDevice> {
             },
             None,
         )
-        .await
-        .unwrap();
+        .await?;
     Ok(WGPUDevice {
         device: Box::into_raw(Box::new(DeviceWrapper { device, queue })),
     })
@@ -141,19 +141,48 @@ impl Device for WGPUDevice {
         }
     }
 
-    fn build(&self, name: &str, program: &str) -> std::sync::Arc<dyn super::Program> {
+    fn try_build(
+        &self,
+        name: &str,
+        program: &str,
+    ) -> Result<std::sync::Arc<dyn super::Program>, StormError> {
         unsafe {
             let device = &*(self.device as *mut wgpu::Device);
-            Arc::new(WGPUProgram {
+            // Invalid WGSL would otherwise go to the uncaptured error handler, which panics
+            device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let module = device.create_shader_module(ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(std::borrow::Cow::Borrowed(program)),
+            });
+            if let Some(e) = pollster::block_on(device.pop_error_scope()) {
+                return Err(StormError::CompileError {
+                    name: name.into(),
+                    program: program.into(),
+                    log: e.to_string(),
+                });
+            }
+            Ok(Arc::new(WGPUProgram {
                 name: name.into(),
-                program: device.create_shader_module(ShaderModuleDescriptor {
-                    label: None,
-                    source: wgpu::ShaderSource::Wgsl(std::borrow::Cow::Borrowed(program)),
-                }),
-            })
+                program: module,
+            }))
         }
     }
 
+    // Shader modules can't be serialized, cache the WGSL so at least rendering is skipped
+    fn compile(&self, name: &str, program: &str) -> Result<Option<Vec<u8>>, StormError> {
+        Ok(Some(program.as_bytes().to_vec()))
+    }
+
+    fn load(
+        &self,
+        name: &str,
+        binary: &[u8],
+    ) -> Result<std::sync::Arc<dyn super::Program>, StormError> {
+        let program = std::str::from_utf8(binary)
+            .map_err(|e| StormError::Internal(format!("cached WGSL for {name}: {e}")))?;
+        self.try_build(name, program)
+    }
+
     fn copyout(&self, src: &dyn super::Buffer, dst: *mut u8) {
         unsafe {
             let wrapper = &*(self.device as *mut DeviceWrapper);
@@ -190,11 +219,11 @@ impl Device for WGPUDevice {
         }
     }
 
-    fn copyin(&self, src: Vec<u8>, dst: &dyn super::Buffer) {
+    fn copyin(&self, src: &[u8], dst: &dyn super::Buffer) {
         unsafe {
             (*self.device)
                 .queue
-                .write_buffer(&(*(dst.ptr() as *mut _)), 0, &src)
+                .write_buffer(&(*(dst.ptr() as *mut _)), 0, src)
         }
     }
 
@@ -257,6 +286,14 @@ impl Default for WGSLRenderer {
 }
 
 impl Op for WGSLRenderer {
+    fn shr(&self, a: &str, b: &str) -> String {
+        format!("({a}>>u32({b}))")
+    }
+
+    fn shl(&self, a: &str, b: &str) -> String {
+        format!("({a}<<u32({b}))")
+    }
+
     fn cmplt(&self, a: &str, b: &str) -> String {
         format!("f32({a}<{b})")
     }
@@ -381,7 +418,7 @@ impl Program for WGPUProgram {
         extra: &[String],
     ) {
         unsafe {
-            let wrapper = &*(DEVICE.device_ptr() as *mut DeviceWrapper);
+            let wrapper = &*(get_device("WGPU").unwrap().device_ptr() as *mut DeviceWrapper);
             let bind_group_layout =
                 wrapper
                     .device
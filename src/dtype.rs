@@ -61,6 +61,23 @@ impl Dtype {
     pub fn is_unsigned(&self) -> bool {
         matches!(*self, uint8 | uint16 | uint32 | uint64)
     }
+
+    // The smallest and largest value, written like a const of this dtype. Floats go to infinity.
+    pub fn min_max(&self) -> (String, String) {
+        let (min, max): (i128, i128) = match *self {
+            int8 => (i8::MIN as i128, i8::MAX as i128),
+            int16 => (i16::MIN as i128, i16::MAX as i128),
+            int32 => (i32::MIN as i128, i32::MAX as i128),
+            int64 => (i64::MIN as i128, i64::MAX as i128),
+            uint8 => (0, u8::MAX as i128),
+            uint16 => (0, u16::MAX as i128),
+            uint32 => (0, u32::MAX as i128),
+            uint64 => (0, u64::MAX as i128),
+            _bool => (0, 1),
+            _ => return (f32::NEG_INFINITY.to_string(), f32::INFINITY.to_string()),
+        };
+        (min.to_string(), max.to_string())
+    }
 }
 
 pub const _bool: Dtype = Dtype {
@@ -0,0 +1,32 @@
+// Errors a caller can recover from, returned by the `try_` variants. The plain versions of those
+// functions panic with the error's message.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StormError {
+    #[error("{op}: shape {lhs:?} does not match {rhs:?}")]
+    ShapeMismatch {
+        op: &'static str,
+        lhs: Vec<isize>,
+        rhs: Vec<isize>,
+    },
+    #[error("{op}: {msg}")]
+    InvalidArgument { op: &'static str, msg: String },
+    #[error("{device} ran out of memory allocating {bytes} bytes")]
+    OutOfMemory { device: String, bytes: usize },
+    // `program` is the rendered kernel, `log` is whatever the compiler printed
+    #[error("failed to compile {name}:\n{program}\n{log}")]
+    CompileError {
+        name: String,
+        program: String,
+        log: String,
+    },
+    #[error("{dtype} is not supported by {on}")]
+    UnsupportedDtype { dtype: String, on: String },
+    #[error("no available backend named {0}")]
+    NoDevice(String),
+    #[error("can't run schedule, buffer {0} isn't realized")]
+    NotRealized(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+pub type Result<T> = std::result::Result<T, StormError>;
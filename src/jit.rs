@@ -0,0 +1,191 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::prelude::*;
+use crate::profiler;
+
+#[derive(Debug, Clone)]
+pub struct JitItem {
+    pub name: String,
+    // Estimated flops and bytes moved, for the profiler
+    pub info: (f64, usize),
+    pub prg: Arc<dyn Program>,
+    pub bufs: Vec<Arc<dyn Buffer>>,
+    pub global_size: Vec<usize>,
+    pub local_size: Vec<usize>,
+}
+
+thread_local! {
+    // Kernels run by `run_schedule` on this thread while a TensorJit is capturing
+    static CAPTURE: RefCell<Option<Vec<JitItem>>> = RefCell::new(None);
+}
+
+pub fn is_capturing() -> bool {
+    CAPTURE.with(|c| c.borrow().is_some())
+}
+
+pub(crate) fn capture(item: JitItem) {
+    CAPTURE.with(|c| {
+        if let Some(items) = c.borrow_mut().as_mut() {
+            items.push(item);
+        }
+    })
+}
+
+fn ptr(b: &Arc<dyn Buffer>) -> usize {
+    b.ptr() as usize
+}
+
+fn tensor_buffer(t: &Tensor) -> Arc<dyn Buffer> {
+    t.buffer.realized().expect("jit input is not realized")
+}
+
+// Wraps a function that realizes the same kernels every call, e.g. a training step. The first
+// call runs it normally, the next two are recorded and every call after that replays the kernels
+// of the last recording with the new inputs swapped in, without building a graph or scheduling.
+//
+// Buffers read by the function that were written by the previous call (parameters and optimizer
+// state updated with `assign`) are updated in place on replay, or copied back before it when the
+// old value is still read after the update, so tensors held outside keep seeing the latest values.
+// The returned tensors are the ones from the last recording and get overwritten by each replay.
+// Random tensors and copies between devices can not be jitted.
+pub struct TensorJit<F: FnMut(&[Tensor]) -> Vec<Tensor>> {
+    f: F,
+    cnt: usize,
+    prev: Vec<JitItem>,
+    jit_cache: Vec<JitItem>,
+    // (item, buf, input)
+    input_replace: Vec<(usize, usize, usize)>,
+    input_shapes: Vec<(Vec<isize>, Dtype)>,
+    // (src, dst) copied before every replay
+    state_copies: Vec<(Arc<dyn Buffer>, Arc<dyn Buffer>)>,
+    ret: Vec<Tensor>,
+}
+
+impl<F: FnMut(&[Tensor]) -> Vec<Tensor>> TensorJit<F> {
+    pub fn new(f: F) -> Self {
+        Self {
+            f,
+            cnt: 0,
+            prev: vec![],
+            jit_cache: vec![],
+            input_replace: vec![],
+            input_shapes: vec![],
+            state_copies: vec![],
+            ret: vec![],
+        }
+    }
+
+    pub fn call(&mut self, inputs: &[Tensor]) -> Vec<Tensor> {
+        let inputs = v![x.contiguous().realize(), for x in inputs];
+        let input_bufs = v![tensor_buffer(x), for x in inputs.iter()];
+        if self.cnt >= 3 {
+            assert!(
+                self.input_shapes == v![(x.shape().dims, x.dtype()), for x in inputs.iter()],
+                "jit inputs changed shape or dtype"
+            );
+            for &(i, j, k) in self.input_replace.iter() {
+                self.jit_cache[i].bufs[j] = input_bufs[k].clone();
+            }
+            for (src, dst) in self.state_copies.iter() {
+                get_device(&dst.device()).unwrap().copyin(&src.to_cpu(), &**dst);
+            }
+            let profiling = profiler::is_enabled();
+            for item in self.jit_cache.iter() {
+                if profiling {
+                    profiler::run(
+                        &*item.prg,
+                        &*get_device(&item.bufs[0].device()).unwrap(),
+                        &item.name,
+                        &item.bufs,
+                        &item.global_size,
+                        &item.local_size,
+                        item.info,
+                        std::time::Instant::now(),
+                    );
+                } else {
+                    item.prg.run(&item.bufs, &item.global_size, Some(&item.local_size), &[], &[]);
+                }
+            }
+            if DEBUG.0.contains("JIT") {
+                println!("jit replayed {} kernels", self.jit_cache.len());
+            }
+            return self.ret.clone();
+        }
+        if self.cnt == 0 {
+            self.cnt += 1;
+            let ret = (self.f)(&inputs);
+            Tensor::corealize(ret.clone());
+            return ret;
+        }
+
+        assert!(!is_capturing(), "can't nest TensorJit");
+        CAPTURE.with(|c| c.replace(Some(vec![])));
+        let ret = (self.f)(&inputs);
+        Tensor::corealize(ret.clone());
+        let items = CAPTURE.with(|c| c.take()).unwrap();
+        if self.cnt == 1 {
+            self.prev = items;
+        } else {
+            self.jit_cache = items;
+            self.input_shapes = v![(x.shape().dims, x.dtype()), for x in inputs.iter()];
+            self.finalize(&input_bufs);
+            if DEBUG.0.contains("JIT") {
+                println!(
+                    "jit captured {} kernels with {} inputs",
+                    self.jit_cache.len(),
+                    self.input_replace.len()
+                );
+            }
+        }
+        self.cnt += 1;
+        self.ret = ret.clone();
+        ret
+    }
+
+    // Compares the two recordings to find which buffers are inputs and which are state carried
+    // over from the previous call
+    fn finalize(&mut self, input_bufs: &[Arc<dyn Buffer>]) {
+        let prev = std::mem::take(&mut self.prev);
+        assert!(
+            prev.len() == self.jit_cache.len()
+                && izip!(prev.iter(), self.jit_cache.iter())
+                    .all(|(a, b)| Arc::ptr_eq(&a.prg, &b.prg) && a.bufs.len() == b.bufs.len()),
+            "jitted function does not run the same kernels every call"
+        );
+        let prev_writer: HashMap<usize, usize> =
+            HashMap::from_iter(v![(ptr(&item.bufs[0]), i), for (i, item) in prev.iter().enumerate()]);
+        let inputs: HashMap<usize, usize> =
+            HashMap::from_iter(v![(ptr(b), i), for (i, b) in input_bufs.iter().enumerate()]);
+        let mut written = HashSet::new();
+        // state buffer -> (writer, readers)
+        let mut state: HashMap<usize, (usize, Vec<(usize, usize)>)> = HashMap::new();
+        for (i, item) in self.jit_cache.iter().enumerate() {
+            for (j, b) in item.bufs.iter().enumerate().skip(1) {
+                let p = ptr(b);
+                if written.contains(&p) {
+                    continue;
+                }
+                if let Some(&k) = inputs.get(&p) {
+                    self.input_replace.push((i, j, k));
+                } else if let Some(&w) = prev_writer.get(&p) {
+                    state.entry(p).or_insert((w, vec![])).1.push((i, j));
+                }
+            }
+            written.insert(ptr(&item.bufs[0]));
+        }
+        for (_, (w, readers)) in state {
+            let new = self.jit_cache[w].bufs[0].clone();
+            if readers.iter().all(|&(i, _)| i <= w) {
+                // Nothing reads the old value after it was overwritten, update in place
+                for (i, j) in readers {
+                    self.jit_cache[i].bufs[j] = new.clone();
+                }
+            } else {
+                let (i, j) = readers[0];
+                self.state_copies.push((new, self.jit_cache[i].bufs[j].clone()));
+            }
+        }
+    }
+}
@@ -1,20 +1,27 @@
 use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::Display;
 use std::ops::{Deref, DerefMut};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 use half::f16;
 use itertools::Itertools;
-use rand::Rng;
 
 use crate::codegen::kernel::Buffers;
-use crate::codegen::kernel::{ConstBuffer, MemBuffer};
+use crate::codegen::kernel::{ConstBuffer, GatherBuffer, MemBuffer, RandBuffer};
+use crate::codegen::optimizer::{beam_search, beam_width};
+use crate::jit::{self, JitItem};
 use crate::dtype::{least_upper_dtype, NumType};
 use crate::ops::{self, ScheduleItem};
+use crate::memory;
+use crate::profiler;
+use crate::device::canonicalize_device;
+use crate::device::diskcache::{self, DiskCacheEntry};
+use crate::device::disk;
 use crate::prelude::*;
 use crate::{
     arg::Arg,
     ops::{LazyOp, LazyOpSrc, Load, Movement, OpType},
+    random,
     shape::{shapetracker::ShapeTracker, symbolic::gcd},
 };
 
@@ -84,7 +91,9 @@ impl DerefMut for STArc {
 pub struct LazyBuffer {
     pub lazyop: LOArc,
     pub st: ShapeTracker,
-    pub device_buffer: Arc<Option<Arc<dyn Buffer>>>,
+    pub device: String,
+    // Shared by every view and clone, realizing any of them realizes them all
+    pub device_buffer: Arc<RwLock<Option<Arc<dyn Buffer>>>>,
     pub _base: Option<Arc<LazyBuffer>>,
     pub shape: Vec<isize>,
     pub id: LazyBufferId,
@@ -109,14 +118,15 @@ impl core::fmt::Debug for LazyBuffer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "<LB {:?} dtype={:?} op={:?} st={:?}>",
-            self.shape, self.dtype.type_name, self.lazyop.optype, self.st.views
+            "<LB {} {:?} dtype={:?} op={:?} st={:?}>",
+            self.device, self.shape, self.dtype.type_name, self.lazyop.optype, self.st.views
         )
     }
 }
 
 impl LazyBuffer {
     pub fn new(
+        device: &str,
         st: ShapeTracker,
         optype: OpType,
         op: LazyOp,
@@ -131,10 +141,11 @@ impl LazyBuffer {
             // views: HashSet::new(),
             id: lb_id(),
             dtype,
+            device: canonicalize_device(device),
             device_buffer: if base.is_some() {
                 base.as_ref().unwrap().device_buffer.clone()
             } else {
-                Arc::new(None)
+                Arc::default()
             },
             _base: base,
             force_realize: false,
@@ -159,11 +170,7 @@ impl LazyBuffer {
     }
 
     pub fn device(&self) -> String {
-        if let Some(d) = self.device_buffer.as_ref() {
-            d.device()
-        } else {
-            "Unrealized".into()
-        }
+        self.device.clone()
     }
 
     pub fn base(&self) -> Self {
@@ -181,7 +188,16 @@ impl LazyBuffer {
     }
 
     pub fn is_realized(&self) -> bool {
-        self.base().device_buffer.is_some()
+        self.realized().is_some()
+    }
+
+    pub fn realized(&self) -> Option<Arc<dyn Buffer>> {
+        self.device_buffer.read().unwrap().clone()
+    }
+
+    // Swaps in the buffer for this one and every view and clone of it
+    pub fn set_realized(&self, buf: Option<Arc<dyn Buffer>>) {
+        *self.device_buffer.write().unwrap() = buf;
     }
 
     pub fn map_buffers(&self, real_srcs: &HashMap<LazyBuffer, LazyOpSrc>) -> LazyOpSrc {
@@ -195,6 +211,7 @@ impl LazyBuffer {
         optype: OpType,
         shape: &[isize],
         dtype: Dtype,
+        device: &str,
         args: Option<Vec<Arg>>,
         src: Option<LazyBuffer>,
     ) -> Self {
@@ -203,6 +220,7 @@ impl LazyBuffer {
             ss.push(src.into());
         };
         create_lazybuffer(
+            device,
             ShapeTracker::new(shape, None),
             LazyOp::new(optype, ss, args),
             dtype,
@@ -210,11 +228,12 @@ impl LazyBuffer {
         )
     }
 
-    pub fn _const(val: impl Display, dtype: Dtype) -> Self {
+    pub fn _const(val: impl Display, dtype: Dtype, device: &str) -> Self {
         Self::loadop(
             OpType::Load(Load::Const),
             &vec![1],
             dtype,
+            device,
             Some(vec![Arg::Str(val.to_string())]),
             None,
         )
@@ -225,6 +244,7 @@ impl LazyBuffer {
             OpType::Load(Load::Const),
             &vec![1],
             self.dtype.clone(),
+            &self.device,
             Some(vec![Arg::Str(val.to_string())]),
             None,
         )
@@ -235,11 +255,12 @@ impl LazyBuffer {
     pub fn from_bytes(x: &[u8]) -> Self {
         let bytes = x;
         let mut buf = DEVICE.alloc(x.len(), dtype::type_to_dtype::<u8>());
-        DEVICE.copyin(bytes.to_vec(), &*buf);
+        DEVICE.copyin(bytes, &*buf);
         Self {
             lazyop: LazyOp::new(Load::From.into(), vec![], None).into(),
             st: ShapeTracker::from_shape(&[x.len() as isize]).into(),
-            device_buffer: Arc::new(Some(buf)),
+            device: DEVICE.name(),
+            device_buffer: Arc::new(RwLock::new(Some(buf))),
             _base: None,
             shape: vec![x.len() as isize],
             // children: HashSet::new(),
@@ -251,6 +272,23 @@ impl LazyBuffer {
         }
     }
 
+    // Wraps a buffer that already holds the data, e.g. one on disk
+    pub fn from_buffer(buf: Arc<dyn Buffer>, shape: &[isize]) -> Self {
+        assert!(shape.iter().product::<isize>() as usize * buf.dtype().size == buf.bytesize());
+        Self {
+            lazyop: LazyOp::new(Load::From.into(), vec![], None).into(),
+            st: ShapeTracker::from_shape(shape).into(),
+            device: canonicalize_device(&buf.device()),
+            _base: None,
+            shape: shape.to_vec(),
+            id: lb_id(),
+            dtype: buf.dtype(),
+            device_buffer: Arc::new(RwLock::new(Some(buf))),
+            force_realize: false,
+            contiguous_child: Arc::new(None),
+        }
+    }
+
     pub fn from_cpu<T: NumType>(x: Vec<T>) -> Self {
         let bytes = x
             .iter()
@@ -258,11 +296,12 @@ impl LazyBuffer {
             .collect::<Vec<Vec<u8>>>()
             .concat();
         let mut buf = DEVICE.alloc(x.len(), dtype::type_to_dtype::<T>());
-        DEVICE.copyin(bytes, &*buf);
+        DEVICE.copyin(&bytes, &*buf);
         Self {
             lazyop: LazyOp::new(Load::From.into(), vec![], None).into(),
             st: ShapeTracker::from_shape(&[x.len() as isize]).into(),
-            device_buffer: Arc::new(Some(buf)),
+            device: DEVICE.name(),
+            device_buffer: Arc::new(RwLock::new(Some(buf))),
             _base: None,
             shape: vec![x.len() as isize],
             // children: HashSet::new(),
@@ -274,22 +313,26 @@ impl LazyBuffer {
         }
     }
 
-    // pub fn copy_to_device(&self, device: &str) -> Self {
-    //     if !self.is_realized()
-    //         && matches!(self.lazyop.optype, OpType::Load(_))
-    //         && self.lazyop.optype != Load::Const
-    //     {
-    //         return self.clone();
-    //     }
-    //     Self::loadop(
-    //         OpType::Load(Load::From),
-    //         &self.shape,
-    //         self.dtype.clone(),
-    //         &self.device,
-    //         None,
-    //         Some(self.contiguous()),
-    //     )
-    // }
+    pub fn copy_to_device(&self, device: &str) -> Self {
+        let device = canonicalize_device(device);
+        if self.device == device {
+            return self.clone();
+        }
+        // Consts are never realized, they only need to be moved
+        if self.is_unrealized_const() {
+            return Self::_const(self.base().lazyop.args[0].to_str(), self.dtype.clone(), &device)
+                .reshape(&vec![1; self.shape.len()])
+                .expand(&self.shape);
+        }
+        Self::loadop(
+            OpType::Load(Load::From),
+            &self.shape,
+            self.dtype.clone(),
+            &device,
+            None,
+            Some(self.contiguous()),
+        )
+    }
 
     pub fn contiguous(&self) -> Self {
         if !self.st.contiguous()
@@ -309,6 +352,28 @@ impl LazyBuffer {
         self.clone()
     }
 
+    // Self at the positions `index` gives along `dim`, shaped like index. Its own kernel reads
+    // the index and loads self by offset, so self is made contiguous first.
+    pub fn gather(&self, index: &Self, dim: usize) -> Self {
+        assert!(
+            index.is_unrealized_const() || index.device == self.device,
+            "can not gather from {} with an index on {}",
+            self.device,
+            index.device
+        );
+        create_lazybuffer(
+            &self.device,
+            ShapeTracker::from_shape(&index.shape),
+            LazyOp::new(
+                OpType::Load(Load::Gather),
+                vec![self.contiguous().into(), index.clone().into()],
+                Some(vec![Arg::Idx(dim as isize)]),
+            ),
+            self.dtype.clone(),
+            None,
+        )
+    }
+
     pub fn is_unrealized_const(&self) -> bool {
         !self.is_realized() && self.base().lazyop.optype == Load::Const
     }
@@ -319,12 +384,13 @@ impl LazyBuffer {
 
     pub fn _view(&self, op: Movement, new_st: ShapeTracker) -> Self {
         if self.st.size() == 0 {
-            return Self::_const(0, self.dtype.clone()).reshape(&new_st.shape().dims);
+            return Self::_const(0, self.dtype.clone(), &self.device).reshape(&new_st.shape().dims);
         }
         if new_st.contiguous() && self.base_ref().shape == new_st.shape().dims {
             self.base()
         } else {
             create_lazybuffer(
+                &self.device,
                 new_st,
                 LazyOp::new(OpType::Movement(op), vec![], None),
                 self.dtype.clone(),
@@ -355,6 +421,16 @@ impl LazyBuffer {
         //     .unwrap()
         //     .dtype
         //     .clone();
+        // Consts are created on the default device, they follow whatever they are combined with
+        let devices = v![s.device.clone(), for s in srcs.iter(), if !s.is_unrealized_const()]
+            .into_iter()
+            .unique()
+            .collect::<Vec<String>>();
+        assert!(
+            devices.len() <= 1,
+            "can not mix buffers on {devices:?} in one op, move them with copy_to_device first"
+        );
+        let out_device = devices.first().unwrap_or(&self.device).clone();
         let srcs: Vec<LazyOpSrc> = srcs
             .into_iter()
             .map(|x| {
@@ -373,6 +449,7 @@ impl LazyBuffer {
         //     _bool
         // };
         create_lazybuffer(
+            &out_device,
             ShapeTracker::new(&self.shape, None),
             LazyOp::new(optype, srcs, None),
             self.dtype.clone(),
@@ -386,6 +463,7 @@ impl LazyBuffer {
         }
         let unbound_new_shape = new_shape;
         create_lazybuffer(
+            &self.device,
             ShapeTracker::new(new_shape, None),
             LazyOp::new(
                 optype,
@@ -403,7 +481,7 @@ impl LazyBuffer {
     }
 
     pub fn cast(&self, dtype: Dtype, bitcast: Option<bool>) -> Self {
-        if dtype.size == self.dtype.size {
+        if dtype == self.dtype {
             return self.clone();
         }
         if dtype.size <= self.dtype.size && self != self.base_ref() {
@@ -414,6 +492,7 @@ impl LazyBuffer {
         }
         let bitcast = bitcast.unwrap_or(false);
         create_lazybuffer(
+            &self.device,
             ShapeTracker::from_shape(&self.shape),
             LazyOp::new(
                 ops::Unary::Cast.into(),
@@ -485,6 +564,7 @@ impl LazyBuffer {
 }
 
 pub fn create_lazybuffer(
+    device: &str,
     st: ShapeTracker,
     op: LazyOp,
     dtype: Dtype,
@@ -495,7 +575,7 @@ pub fn create_lazybuffer(
         optype,
         OpType::Load(Load::Empty) | OpType::Load(Load::Rand) | OpType::Load(Load::Const)
     ) {
-        let ret = LazyBuffer::new(st, optype, op, dtype, base);
+        let ret = LazyBuffer::new(device, st, optype, op, dtype, base);
         if DEBUG.0.contains("LB") {
             println!("{} {:?}", ret.id, ret);
         }
@@ -508,7 +588,7 @@ pub fn create_lazybuffer(
     //   return lazycache[wop]
     //
     // lazycache[wop] = ret = LazyBuffer(device, st, optype, op, dtype, base=base)
-    let ret = LazyBuffer::new(st, optype, op, dtype, base);
+    let ret = LazyBuffer::new(device, st, optype, op, dtype, base);
     if DEBUG.0.contains("LB") {
         println!("{} {:?}", ret.id, ret);
     }
@@ -557,30 +637,62 @@ fn get_contraction(old_shape: &[isize], new_shape: &[isize]) -> Option<Vec<Vec<i
     Some(ret)
 }
 
-fn _realize_from(buffer: &LazyBuffer) {
-    todo!()
+fn _realize_from(buffer: &LazyBuffer, src: &LazyBuffer) -> Result<(), StormError> {
+    if buffer.device == "DISK" {
+        return Err(disk::read_only());
+    }
+    let device = get_device(&buffer.device)?;
+    let src = src.realized().unwrap();
+    let b = device.try_alloc(
+        buffer.shape.iter().product::<isize>() as usize,
+        buffer.dtype.clone(),
+    )?;
+    // Disk buffers are copied straight out of the mapped file, the rest go through host memory
+    match src.host_bytes() {
+        Some(bytes) => device.copyin(bytes, b.as_ref()),
+        None => device.copyin(&src.to_cpu(), b.as_ref()),
+    }
+    buffer.set_realized(Some(b));
+    Ok(())
 }
 
-fn _realize_empty(buffer: &LazyBuffer) {
-    let mut buffer = buffer.clone();
-    unsafe {
-        let x = Arc::get_mut_unchecked(&mut buffer.device_buffer);
-        x.replace(DEVICE.alloc(
-            buffer.shape.iter().product::<isize>() as usize,
-            buffer.dtype.clone(),
-        ));
-    }
+fn _realize_empty(buffer: &LazyBuffer) -> Result<(), StormError> {
+    let b = get_device(&buffer.device)?.try_alloc(
+        buffer.shape.iter().product::<isize>() as usize,
+        buffer.dtype.clone(),
+    )?;
+    buffer.set_realized(Some(b));
+    Ok(())
 }
 
-fn _realize_rand(buffer: &LazyBuffer) {
-    let mut buffer = buffer.clone();
-    let numel = buffer.shape.iter().product::<isize>() as usize;
-    let mut on_cpu = gen_rand_num_bytes(numel, &buffer.dtype);
-    unsafe {
-        let mut b = DEVICE.alloc(numel, buffer.dtype.clone());
-        DEVICE.copyin(on_cpu, b.as_ref());
-        Arc::get_mut_unchecked(&mut buffer.device_buffer).replace(b);
+// Writes into the arena's buffer once nothing else holds it. A tensor or a jit capture still
+// reading the previous output keeps it, and the arena moves to a fresh buffer instead. The plan
+// counted that output in the arena, so its bytes go on the device's peak, checked against limit
+// before allocating.
+fn _realize_arena(
+    buffer: &LazyBuffer,
+    arena: &memory::Arena,
+    buf: &mut Option<Arc<dyn Buffer>>,
+    peak: &mut usize,
+    limit: usize,
+) -> Result<(), StormError> {
+    match buf {
+        Some(b) if Arc::strong_count(b) == 1 => buffer.set_realized(Some(b.clone())),
+        _ => {
+            if buf.is_some() {
+                *peak += arena.bytes();
+                if limit > 0 && *peak > limit {
+                    return Err(StormError::OutOfMemory {
+                        device: arena.device.clone(),
+                        bytes: *peak,
+                    });
+                }
+            }
+            _realize_empty(buffer)?;
+            *buf = buffer.realized();
+        }
     }
+    Ok(())
 }
 
 // fn _realize_const(buffer: &LazyBuffer) {
@@ -599,35 +711,6 @@ fn _realize_contiguous(buffer: &LazyBuffer) {
     todo!();
 }
 
-use rand::SeedableRng;
-// Have to do this because the lack of num trait in Rust.
-// num_traits's traits are not object safe.
-#[rustfmt::skip]
-fn gen_rand_num_bytes(size: usize, dtype: &Dtype) -> Vec<u8> {
-    // let chains, it is not irrefutable_let_patterns
-    #[allow(irrefutable_let_patterns)]
-    let mut rng = if let seed = getenv::<isize>("SEED", -1) && seed >= 0 {
-        rand::rngs::StdRng::seed_from_u64(seed as u64)
-    } else {
-        rand::rngs::StdRng::from_rng(rand::thread_rng()).unwrap()
-    };
-    let ptr = match dtype.type_name {
-        "f16" => { let mut ret = (0..size).map(|_| rng.gen::<f16>()).collect::<Vec<f16>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "f32" => { let mut ret = (0..size).map(|_| rng.gen::<f32>()).collect::<Vec<f32>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "f64" => { let mut ret = (0..size).map(|_| rng.gen::<f64>()).collect::<Vec<f64>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "u8"  => { let mut ret = (0..size).map(|_| rng.gen::< u8>()).collect::<Vec< u8>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "u16" => { let mut ret = (0..size).map(|_| rng.gen::<u16>()).collect::<Vec<u16>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "u32" => { let mut ret = (0..size).map(|_| rng.gen::<u32>()).collect::<Vec<u32>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "u64" => { let mut ret = (0..size).map(|_| rng.gen::<u64>()).collect::<Vec<u64>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "i8"  => { let mut ret = (0..size).map(|_| rng.gen::< i8>()).collect::<Vec< i8>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "i16" => { let mut ret = (0..size).map(|_| rng.gen::<i16>()).collect::<Vec<i16>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "i32" => { let mut ret = (0..size).map(|_| rng.gen::<i32>()).collect::<Vec<i32>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        "i64" => { let mut ret = (0..size).map(|_| rng.gen::<i64>()).collect::<Vec<i64>>(); let ret_ptr = ret.as_mut_ptr() as *mut u8; std::mem::forget(ret); ret_ptr},
-        t => panic!("unable gen type t={t}"),
-    };
-    unsafe { Vec::<u8>::from_raw_parts(ptr, size * dtype.size, size * dtype.size)}
-}
-
 pub fn _replace_bufferops(op: LazyOp) -> (LazyOp, Vec<LazyBuffer>) {
     let mut replacements: HashMap<LazyBuffer, LazyOp> = HashMap::new();
     let mut base_bufs: Vec<LazyBuffer> =
@@ -691,6 +774,7 @@ pub fn _replace_bufferops(op: LazyOp) -> (LazyOp, Vec<LazyBuffer>) {
 
 #[derive(Debug, Clone)]
 pub struct KernelCache {
+    name: String,
     prg_str: String,
     prg: Arc<dyn Program>,
     global_size: Vec<usize>,
@@ -704,13 +788,29 @@ lazy_static::lazy_static! {
     pub static ref KERNEL_CACHED: Mutex<HashMap<String, KernelCache>>  = Default::default();
 }
 
-pub fn run_schedule(mut schedule: VecDeque<ScheduleItem>) {
+pub fn run_schedule(schedule: VecDeque<ScheduleItem>) {
+    try_run_schedule(schedule).unwrap_or_else(|e| panic!("{e}"))
+}
+
+pub fn try_run_schedule(mut schedule: VecDeque<ScheduleItem>) -> Result<(), StormError> {
     let debug_cache = DEBUG.0.contains("CACHE");
     let debug_kernel = DEBUG.0.contains("KERNEL");
     let debug_sch = DEBUG.0.contains("SCH");
+    let disk_cache = diskcache::enabled();
+    let beam = beam_width();
+    let profiling = profiler::is_enabled();
+    let mut plan = memory::plan(schedule.make_contiguous());
+    if DEBUG.0.contains("MEM") && !schedule.is_empty() {
+        print!("{}", plan.report());
+    }
+    let mem_limit = getenv("MEM_LIMIT", 0);
+    plan.check(mem_limit)?;
+    let mut arena_bufs = vec![None; plan.arenas.len()];
     let mut k_lock = KERNEL_CACHED.lock().unwrap();
     while !schedule.is_empty() {
+        let step = plan.assignment.len() - schedule.len();
         let mut si = schedule.pop_front().unwrap();
+        let wall_start = std::time::Instant::now();
         if debug_sch {
             println!("{:?}", si);
         }
@@ -720,14 +820,19 @@ pub fn run_schedule(mut schedule: VecDeque<ScheduleItem>) {
         //println!("si optype {:?}", si.ast.optype);
         for x in si.inputs.iter() {
             if !x.is_realized() {
-                panic!("Can't run schedule, {x:?} isnt't realized")
+                return Err(StormError::NotRealized(x.id.to_string()));
             }
         }
         match &si.ast.optype {
             OpType::Load(l) => {
+                if jit::is_capturing() && matches!(l, Load::From) {
+                    return Err(StormError::InvalidArgument {
+                        op: "jit",
+                        msg: format!("can't capture {l:?}"),
+                    });
+                }
                 match l {
-                    Load::Rand => _realize_rand(&si.out),
-                    Load::From => _realize_from(&si.out),
+                    Load::From => _realize_from(&si.out, &si.inputs[0])?,
                     Load::Custom => todo!(),
                     _ => (),
                 }
@@ -736,14 +841,34 @@ pub fn run_schedule(mut schedule: VecDeque<ScheduleItem>) {
             }
             _ => (),
         }
-        if si.out.device_buffer.is_none() {
-            _realize_empty(&si.out);
+        // The jit would replay the kernel with the seed and offset of the recorded draw
+        if jit::is_capturing() && si.out.lazyop.optype == Load::Rand {
+            return Err(StormError::InvalidArgument {
+                op: "jit",
+                msg: format!("can't capture {:?}", Load::Rand),
+            });
+        }
+        if si.out.device == "DISK" {
+            return Err(disk::read_only());
+        }
+        if !si.out.is_realized() {
+            match plan.assignment[step] {
+                Some(a) => {
+                    let arena = &plan.arenas[a];
+                    let peak = plan.peak.get_mut(&arena.device).unwrap();
+                    _realize_arena(&si.out, arena, &mut arena_bufs[a], peak, mem_limit)?
+                }
+                None => _realize_empty(&si.out)?,
+            }
         }
         si.out.lazyop.src.clear();
         si.out.lazyop.buffers.clear();
-        let mut bufs = vec![(*si.out.device_buffer).as_ref().unwrap().clone()];
-        bufs.extend(v![(*b.device_buffer).as_ref().unwrap().clone(), for b in si.inputs.iter()]);
-        let cached = k_lock.get(&format!("{:?}", si.ast));
+        let mut bufs = vec![si.out.realized().unwrap()];
+        bufs.extend(v![b.realized().unwrap(), for b in si.inputs.iter()]);
+        let device = get_device(&si.out.device)?;
+        // BEAM changes the kernel an ast gets, a kernel found without it must not be reused with it
+        let key = format!("{} beam={beam} {:?}", si.out.device, si.ast);
+        let cached = k_lock.get(&key);
         if let Some(kernel) = cached {
             if debug_cache {
                 println!("\ncached hit");
@@ -751,15 +876,33 @@ pub fn run_schedule(mut schedule: VecDeque<ScheduleItem>) {
             if DEBUG.0.contains("KERNEL") {
                 println!("{}", kernel.prg_str);
             }
-            kernel.prg.run(
-                &bufs,
-                kernel.global_size.as_ref(),
-                Some(kernel.local_size.as_ref()),
-                &[],
-                &[],
+        } else if let Some((entry, prg)) = disk_cache
+            .then(|| diskcache::load(&*device, &key))
+            .flatten()
+            .and_then(|e| device.load(&e.name, &e.binary).ok().map(|prg| (e, prg)))
+        {
+            if debug_cache {
+                println!("\ndisk cache hit");
+            }
+            if debug_kernel {
+                println!("{}", entry.prg_str);
+            }
+            k_lock.insert(
+                key.clone(),
+                KernelCache {
+                    name: entry.name,
+                    prg_str: entry.prg_str,
+                    prg,
+                    global_size: entry.global_size,
+                    local_size: entry.local_size,
+                },
             );
         } else {
-            let mut lin = DEVICE.get_lin(si.ast.clone());
+            let mut lin = if beam > 0 {
+                beam_search(&*device, si.ast.clone(), beam)
+            } else {
+                device.get_lin(si.ast.clone())
+            };
             lin.linearize();
             let global_size = if let Some(mut gs) = lin.global_size.clone() {
                 gs.extend(vec![1; 3 - gs.len()]);
@@ -773,26 +916,84 @@ pub fn run_schedule(mut schedule: VecDeque<ScheduleItem>) {
             } else {
                 vec![]
             };
-            let (name, prg_str) = DEVICE.render(lin);
+            let (name, prg_str) = device.render(lin);
             if debug_kernel {
                 println!("{prg_str}");
             }
             if debug_cache {
                 println!("\nzero hit");
             }
-            let prg = DEVICE.build(&name, &prg_str);
-            prg.run(&bufs, &global_size, Some(&local_size), &[], &[]);
+            let binary = if disk_cache { device.compile(&name, &prg_str)? } else { None };
+            // Entries that don't load are never stored, the kernel is built from source instead
+            let prg = if let Some(binary) = binary
+                && let Ok(prg) = device.load(&name, &binary)
+            {
+                diskcache::store(
+                    &*device,
+                    &key,
+                    &DiskCacheEntry {
+                        name: name.clone(),
+                        prg_str: prg_str.clone(),
+                        global_size: global_size.clone(),
+                        local_size: local_size.clone(),
+                        binary,
+                    },
+                );
+                prg
+            } else {
+                device.try_build(&name, &prg_str)?
+            };
             k_lock.insert(
-                format!("{:?}", si.ast),
+                key.clone(),
                 KernelCache {
+                    name,
                     prg_str,
                     prg,
-                    global_size: global_size.clone(),
-                    local_size: local_size.clone(),
+                    global_size,
+                    local_size,
                 },
             );
         }
+        let kernel = &k_lock[&key];
+        // The ast's store already counts the output's bytes
+        let (flops, mem) = if profiling || jit::is_capturing() {
+            let info = get_lazyop_info(&si.ast.clone().into());
+            (info.flops, info.mem_estimate())
+        } else {
+            (0., 0)
+        };
+        if profiling {
+            profiler::run(
+                &*kernel.prg,
+                &*device,
+                &kernel.name,
+                &bufs,
+                &kernel.global_size,
+                &kernel.local_size,
+                (flops, mem),
+                wall_start,
+            );
+        } else {
+            kernel.prg.run(&bufs, &kernel.global_size, Some(&kernel.local_size), &[], &[]);
+        }
+        if jit::is_capturing() {
+            jit::capture(JitItem {
+                name: kernel.name.clone(),
+                info: (flops, mem),
+                prg: kernel.prg.clone(),
+                bufs,
+                global_size: kernel.global_size.clone(),
+                local_size: kernel.local_size.clone(),
+            });
+        }
+        // The arena's last reader ran, its buffer goes once the outputs sharing it are dropped
+        for (a, arena) in plan.arenas.iter().enumerate() {
+            if arena.last_use == step {
+                arena_bufs[a] = None;
+            }
+        }
     }
+    Ok(())
 }
 
 #[derive(Default, Debug)]
@@ -923,6 +1124,12 @@ pub fn _recursive_lb<'a>(
     if matches!(buf.lazyop.optype, OpType::Load(Load::From)) {
         realizes.insert(buf.lazyop.src[0].lb().base_ref());
     }
+    // Both get read by offset, from buffers of their own
+    if matches!(buf.lazyop.optype, OpType::Load(Load::Gather)) {
+        for x in buf.lazyop.src.iter() {
+            realizes.insert(x.lb().base_ref());
+        }
+    }
 
     for x in buf.lazyop.src.iter() {
         *children
@@ -948,7 +1155,7 @@ pub fn _is_padding_okay(buf: &LazyBuffer, realizes: &HashSet<&LazyBuffer>) -> bo
     ) {
         return false;
     }
-    any(&v![_is_padding_okay(x.lb().base_ref(), realizes), for x in buf.lazyop.src.iter()])
+    buf.lazyop.src.iter().all(|x| _is_padding_okay(x.lb().base_ref(), realizes))
 }
 pub fn _recursive_lazyop<'a>(
     mut buf: &'a LazyBuffer,
@@ -1038,16 +1245,82 @@ pub fn _recursive_schedule<'a>(
     seen.insert(out);
     let mut inputs: Vec<&LazyBuffer> = vec![];
     // if out.op in {LoadOps.CUSTOM, LoadOps.SYNC, LoadOps.WAIT, LoadOps.COPY, LoadOps.EMPTY}:
-    let op = if matches!(
-        out.lazyop.optype,
-        OpType::Load(Load::Rand) | OpType::Load(Load::From)
-    ) {
+    let op = if out.lazyop.optype == Load::From {
         inputs = v![x.lb(),for x in out.lazyop.src.iter()];
         LazyOp::new(
             out.lazyop.optype.clone(),
             vec![],
             Some(out.lazyop.args.clone()),
         )
+    } else if out.lazyop.optype == Load::Rand {
+        // A kernel on its own that only reads the seed and offset
+        inputs = v![x.lb(), for x in out.lazyop.src.iter()];
+        let range = match out.lazyop.args[..] {
+            [Arg::Idx(low), Arg::Idx(high)] => Some((low, high)),
+            _ => None,
+        };
+        let st = ShapeTracker::from_shape(&out.shape);
+        let rand = LazyOp::new(
+            OpType::Buffer(ops::Buffer::Rand),
+            vec![],
+            Some(vec![Arg::Buffer(
+                RandBuffer {
+                    idx: 1,
+                    range,
+                    dtype: out.dtype.clone(),
+                    st: st.clone(),
+                }
+                .into(),
+            )]),
+        );
+        LazyOp::new(
+            OpType::Buffer(ops::Buffer::Store),
+            vec![rand.into()],
+            Some(vec![Arg::Buffer(
+                MemBuffer {
+                    idx: 0,
+                    dtype: out.dtype.clone(),
+                    st,
+                }
+                .into(),
+            )]),
+        )
+    } else if out.lazyop.optype == Load::Gather {
+        // A kernel on its own, the source is input 1 and the index is loaded like any buffer
+        let (src, index) = (out.lazyop.src[0].lb(), out.lazyop.src[1].lb());
+        let dim = out.lazyop.args[0].to_idx() as usize;
+        inputs.push(src.base_ref());
+        let st = ShapeTracker::from_shape(&out.shape);
+        let index = _recursive_lazyop(index, &mut inputs, st.clone(), realizes, &mut HashMap::new(), Some(false));
+        let src_st = ShapeTracker::from_shape(&src.shape)
+            .shrink(&v![(0, if d == dim { 1 } else { s }), for (d, &s) in out.shape.iter().enumerate()])
+            .expand(&out.shape);
+        let gather = LazyOp::new(
+            OpType::Buffer(ops::Buffer::Gather),
+            vec![index.into()],
+            Some(vec![Arg::Buffer(
+                GatherBuffer {
+                    idx: 1,
+                    stride: src.shape[dim + 1..].iter().product(),
+                    size: src.shape[dim],
+                    dtype: out.dtype.clone(),
+                    st: src_st,
+                }
+                .into(),
+            )]),
+        );
+        LazyOp::new(
+            OpType::Buffer(ops::Buffer::Store),
+            vec![gather.into()],
+            Some(vec![Arg::Buffer(
+                MemBuffer {
+                    idx: 0,
+                    dtype: out.dtype.clone(),
+                    st,
+                }
+                .into(),
+            )]),
+        )
     } else {
         let output_st = ShapeTracker::from_shape(if reduce_for_op.contains_key(out) {
             &reduce_for_op[out].shape
@@ -1237,12 +1510,17 @@ pub fn get_lazyop_info(ast: &LazyOpSrc) -> FlopCounter {
                     return FlopCounter::buffer_load(&o.lo().args[0].to_buf());
                 }
                 ops::Buffer::Store => {
-                    return get_lazyop_info(&o).buffer_store(&o.lo().args[0].to_buf())
+                    return get_lazyop_info(&o.lo().src[0]).buffer_store(&o.lo().args[0].to_buf())
                 }
-                ops::Buffer::Const => {
+                ops::Buffer::Const | ops::Buffer::Rand => {
                     //println!("CONST CONST CONST\n{:?}", o);
                     return FlopCounter::buffer_const(&o.lo().args[0].to_buf());
                 }
+                ops::Buffer::Gather => {
+                    let mut ret = FlopCounter::buffer_load(&o.lo().args[0].to_buf());
+                    ret.mem.extend(get_lazyop_info(&o.lo().src[0]).mem);
+                    return ret;
+                }
                 t => println!("{t:?}"),
             },
             t => println!("{t:?}"),
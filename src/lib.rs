@@ -3,18 +3,30 @@
 
 use std::collections::HashSet;
 
+// Lets `#[derive(Module)]` name this crate from inside it
+extern crate self as storm;
+
 pub mod arg;
+pub mod autograd;
 pub mod codegen;
 pub mod device;
 pub mod dtype;
+pub mod error;
+pub mod jit;
 pub mod lazy;
 pub mod macros;
+pub mod memory;
 pub mod nn;
 pub mod ops;
+pub mod profiler;
+pub mod random;
 pub mod renderer;
 pub mod shape;
 pub mod tensor;
 pub mod utils;
+pub mod viz;
+
+pub use memory::{cache_limit, empty_cache, memory_stats, set_cache_limit};
 
 #[derive(Debug, Clone)]
 pub struct DebugStruct(HashSet<String>);
@@ -26,10 +38,15 @@ lazy_static::lazy_static! {
 pub mod prelude {
     pub use crate::device::{prelude::*, Buffer, Device, Program};
     pub use crate::dtype::{self, Dtype};
+    pub use crate::error::StormError;
     pub use crate::izip;
+    pub use crate::jit::TensorJit;
     pub use crate::lazy::LazyBuffer;
     pub use crate::macros::*;
     pub use crate::nn::optim::*;
+    pub use crate::nn::Module;
+    pub use crate::nn::state::StateDict;
+    pub use crate::tensor::shape::IntoAxes;
     pub use crate::tensor::{Tensor, TensorDefaultType};
     pub use crate::DEBUG;
     pub use crate::utils::*;
@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+pub use crate::device::MemoryStats;
+use crate::lazy::LazyBufferId;
+use crate::ops::{Load, OpType, ScheduleItem};
+use crate::prelude::*;
+
+// One buffer shared by kernel outputs of the same device, dtype and size whose lifetimes don't
+// overlap
+#[derive(Debug, Clone)]
+pub struct Arena {
+    pub device: String,
+    pub dtype: Dtype,
+    pub numel: usize,
+    // Schedule items writing into the arena, in order
+    pub members: Vec<usize>,
+    // Last schedule item reading the arena
+    pub last_use: usize,
+}
+
+impl Arena {
+    pub fn bytes(&self) -> usize {
+        self.numel * self.dtype.size
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryPlan {
+    // The arena each schedule item writes into, None for outputs that outlive the schedule
+    pub assignment: Vec<Option<usize>>,
+    pub arenas: Vec<Arena>,
+    // Per device, the most bytes alive at once while the schedule runs, realized inputs included
+    pub peak: BTreeMap<String, usize>,
+    // Per device, the same without sharing, every output keeping its own buffer
+    pub unshared: BTreeMap<String, usize>,
+}
+
+fn bytes(lb: &LazyBuffer) -> usize {
+    lb.shape.iter().product::<isize>() as usize * lb.dtype.size
+}
+
+// Plans the buffers of a schedule before it runs. A kernel output only read by later items in the
+// schedule is an intermediate: it lives from its kernel to its last reader, and intermediates of
+// the same device, dtype and size go first-fit into arenas. Everything else lives to the end.
+pub fn plan(schedule: &[ScheduleItem]) -> MemoryPlan {
+    let n = schedule.len();
+    let produced: HashMap<LazyBufferId, usize> =
+        HashMap::from_iter(schedule.iter().enumerate().map(|(i, si)| (si.out.id, i)));
+    let mut last_use = vec![None; n];
+    let mut inputs = HashMap::new();
+    for (i, si) in schedule.iter().enumerate() {
+        for x in si.inputs.iter() {
+            match produced.get(&x.id) {
+                Some(&j) => last_use[j] = Some(i),
+                None => {
+                    inputs.insert(x.id, x);
+                }
+            }
+        }
+    }
+    // Bytes allocated and freed at each step, per device
+    let mut deltas: BTreeMap<String, Vec<isize>> = BTreeMap::new();
+    let mut live = |device: &str, bytes: usize, start: usize, end: usize| {
+        let d = deltas
+            .entry(device.to_string())
+            .or_insert_with(|| vec![0; n + 1]);
+        d[start] += bytes as isize;
+        d[end + 1] -= bytes as isize;
+    };
+    let mut ret = MemoryPlan::default();
+    for x in inputs.values() {
+        live(&x.device, bytes(x), 0, n - 1);
+        *ret.unshared.entry(x.device.clone()).or_default() += bytes(x);
+    }
+    for (i, si) in schedule.iter().enumerate() {
+        let out = &si.out;
+        let allocates = match &si.ast.optype {
+            OpType::Load(l) => *l == Load::From,
+            _ => !out.is_realized(),
+        };
+        let arena = match last_use[i] {
+            Some(end) if allocates && !matches!(si.ast.optype, OpType::Load(_)) => {
+                let numel = out.shape.iter().product::<isize>() as usize;
+                let a = ret
+                    .arenas
+                    .iter()
+                    .position(|a| {
+                        a.last_use < i
+                            && a.numel == numel
+                            && a.dtype == out.dtype
+                            && a.device == out.device
+                    })
+                    .unwrap_or_else(|| {
+                        ret.arenas.push(Arena {
+                            device: out.device.clone(),
+                            dtype: out.dtype.clone(),
+                            numel,
+                            members: vec![],
+                            last_use: end,
+                        });
+                        ret.arenas.len() - 1
+                    });
+                ret.arenas[a].members.push(i);
+                ret.arenas[a].last_use = end;
+                Some(a)
+            }
+            _ => None,
+        };
+        if !allocates && out.is_realized() {
+            live(&out.device, bytes(out), 0, n - 1);
+            *ret.unshared.entry(out.device.clone()).or_default() += bytes(out);
+        } else if allocates {
+            *ret.unshared.entry(out.device.clone()).or_default() += bytes(out);
+            if arena.is_none() {
+                live(&out.device, bytes(out), i, n - 1);
+            }
+        }
+        ret.assignment.push(arena);
+    }
+    for a in ret.arenas.iter() {
+        live(&a.device, a.bytes(), a.members[0], a.last_use);
+    }
+    for (device, d) in deltas {
+        let mut cur = 0;
+        let peak = d.iter().map(|x| {
+            cur += x;
+            cur
+        });
+        ret.peak.insert(device, peak.max().unwrap_or(0) as usize);
+    }
+    ret
+}
+
+impl MemoryPlan {
+    // Errors on the first device whose predicted peak is over limit bytes, 0 is no limit
+    pub fn check(&self, limit: usize) -> Result<(), StormError> {
+        match self.peak.iter().find(|(_, p)| limit > 0 && **p > limit) {
+            Some((device, &bytes)) => Err(StormError::OutOfMemory {
+                device: device.clone(),
+                bytes,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let planned = self.assignment.iter().flatten().count();
+        let mut ret = format!(
+            "memory plan: {} items, {planned} intermediates in {} arenas\n",
+            self.assignment.len(),
+            self.arenas.len()
+        );
+        for (device, peak) in self.peak.iter() {
+            writeln!(
+                ret,
+                "{device:<16} peak {peak:>12} bytes {:>12} unshared",
+                self.unshared[device]
+            )
+            .unwrap();
+        }
+        ret
+    }
+}
+
+// What the allocator holds on every device it has allocated on
+pub fn memory_stats() -> BTreeMap<String, MemoryStats> {
+    ALLOCTOR.0.stats()
+}
+
+// Frees every cached buffer back to its device, for between phases that need different sizes
+pub fn empty_cache() {
+    ALLOCTOR.0.free_cached()
+}
+
+// The most bytes the allocator keeps cached per device, 0 for no limit. Starts at LRU_LIMIT.
+pub fn cache_limit() -> usize {
+    ALLOCTOR.0.cache_limit()
+}
+
+pub fn set_cache_limit(bytes: usize) {
+    ALLOCTOR.0.set_cache_limit(bytes)
+}
@@ -1,28 +1,46 @@
-use std::sync::Arc;
-
 use crate::prelude::*;
 
+pub mod module;
 pub mod optim;
+pub mod state;
+
+pub use module::Module;
 
-pub struct Conv2d {
+// Convolution over the last D axes, stride and dilation take one value or one per axis
+#[derive(Module)]
+pub struct ConvNd<const D: usize> {
+    #[module(rename = "weight")]
     pub weights: Tensor,
     pub bias: Option<Tensor>,
-    pub kernel_size: usize,
-    pub stride: usize,
+    #[module(skip)]
+    pub kernel_size: [usize; D],
+    #[module(skip)]
+    pub stride: [usize; D],
+    #[module(skip)]
     pub padding: Vec<usize>,
-    pub dilation: usize,
+    #[module(skip)]
+    pub dilation: [usize; D],
+    #[module(skip)]
     pub groups: usize,
 }
 
-impl Conv2d {
-    pub fn default(in_channel: usize, out_channel: usize, kernel_size: usize) -> Self {
+pub type Conv1d = ConvNd<1>;
+pub type Conv2d = ConvNd<2>;
+pub type Conv3d = ConvNd<3>;
+
+fn axes<const D: usize>(x: impl IntoAxes) -> [usize; D] {
+    x.into_axes(D).try_into().unwrap()
+}
+
+impl<const D: usize> ConvNd<D> {
+    pub fn default(in_channel: usize, out_channel: usize, kernel_size: impl IntoAxes) -> Self {
         Self::new(
             in_channel,
             out_channel,
             kernel_size,
-            None,
+            1,
             [],
-            None,
+            1,
             None,
             None,
         )
@@ -31,23 +49,24 @@ impl Conv2d {
     pub fn new<V: Into<Vec<usize>>>(
         in_channel: usize,
         out_channel: usize,
-        kernel_size: usize,
-        stride: Option<usize>,
+        kernel_size: impl IntoAxes,
+        stride: impl IntoAxes,
         padding: V,
-        dilation: Option<usize>,
+        dilation: impl IntoAxes,
         groups: Option<usize>,
         bias: Option<bool>,
     ) -> Self {
-        let stride = stride.unwrap_or(1);
+        let kernel_size = axes(kernel_size);
+        let stride = axes(stride);
         let mut padding = padding.into();
         if padding.len() == 0 {
             padding.push(0);
         }
-        let dilation = dilation.unwrap_or(1);
+        let dilation = axes(dilation);
         let groups = groups.unwrap_or(1);
         let bias = bias.unwrap_or(false);
         let weights = Tensor::kaiming_uniform(
-            [out_channel, in_channel / groups, kernel_size, kernel_size],
+            [vec![out_channel, in_channel / groups], kernel_size.to_vec()].concat(),
             Some(5.0.sqrt()),
         );
         let bound = 1.0 / f32::sqrt(weights.shape().dims[1..].iter().product::<isize>() as f32);
@@ -79,7 +98,97 @@ impl Conv2d {
     }
 }
 
+// Transposed convolution over the last D axes, the weight is (in, out / groups, *kernel_size).
+// Stride, padding, output_padding and dilation take one value or one per axis.
+#[derive(Module)]
+pub struct ConvTransposeNd<const D: usize> {
+    #[module(rename = "weight")]
+    pub weights: Tensor,
+    pub bias: Option<Tensor>,
+    #[module(skip)]
+    pub kernel_size: [usize; D],
+    #[module(skip)]
+    pub stride: [usize; D],
+    #[module(skip)]
+    pub padding: [usize; D],
+    #[module(skip)]
+    pub output_padding: [usize; D],
+    #[module(skip)]
+    pub dilation: [usize; D],
+    #[module(skip)]
+    pub groups: usize,
+}
+
+pub type ConvTranspose1d = ConvTransposeNd<1>;
+pub type ConvTranspose2d = ConvTransposeNd<2>;
+pub type ConvTranspose3d = ConvTransposeNd<3>;
+
+impl<const D: usize> ConvTransposeNd<D> {
+    pub fn default(in_channel: usize, out_channel: usize, kernel_size: impl IntoAxes) -> Self {
+        Self::new(
+            in_channel,
+            out_channel,
+            kernel_size,
+            1,
+            0,
+            0,
+            1,
+            None,
+            None,
+        )
+    }
+
+    pub fn new(
+        in_channel: usize,
+        out_channel: usize,
+        kernel_size: impl IntoAxes,
+        stride: impl IntoAxes,
+        padding: impl IntoAxes,
+        output_padding: impl IntoAxes,
+        dilation: impl IntoAxes,
+        groups: Option<usize>,
+        bias: Option<bool>,
+    ) -> Self {
+        let kernel_size = axes(kernel_size);
+        let groups = groups.unwrap_or(1);
+        let weights = Tensor::kaiming_uniform(
+            [vec![in_channel, out_channel / groups], kernel_size.to_vec()].concat(),
+            Some(5.0.sqrt()),
+        );
+        let bound = 1.0 / f32::sqrt(weights.shape().dims[1..].iter().product::<isize>() as f32);
+        let bias = if bias.unwrap_or(true) {
+            Some(Tensor::uniform_range([out_channel], -bound, bound))
+        } else {
+            None
+        };
+        Self {
+            weights,
+            bias,
+            kernel_size,
+            stride: axes(stride),
+            padding: axes(padding),
+            output_padding: axes(output_padding),
+            dilation: axes(dilation),
+            groups,
+        }
+    }
+
+    pub fn call(&self, x: &Tensor) -> Tensor {
+        x.conv_transpose2d(
+            &self.weights,
+            self.bias.as_ref(),
+            self.groups,
+            self.stride,
+            self.dilation,
+            self.padding,
+            self.output_padding,
+        )
+    }
+}
+
+#[derive(Module)]
 pub struct Linear {
+    #[module(rename = "weight")]
     pub weights: Tensor,
     pub bias: Option<Tensor>,
 }
@@ -103,10 +212,15 @@ impl Linear {
     }
 }
 
+#[derive(Module)]
 pub struct GroupNorm {
+    #[module(skip)]
     pub num_groups: usize,
+    #[module(skip)]
     pub num_channels: usize,
+    #[module(skip)]
     pub eps: f32,
+    #[module(rename = "weight")]
     pub weights: Option<Tensor>,
     pub bias: Option<Tensor>,
 }
@@ -152,17 +266,18 @@ impl GroupNorm {
     }
 }
 
+#[derive(Module)]
 pub struct Embedding {
+    #[module(skip)]
     pub vocab_size: usize,
+    #[module(skip)]
     pub embed_size: usize,
-    pub vocab_counter: Arc<Option<Tensor>>,
     pub weight: Tensor,
 }
 
 impl Embedding {
     pub fn new(vocab_size: usize, embed_size: usize) -> Self {
         Self {
-            vocab_counter: Default::default(),
             vocab_size,
             embed_size,
             weight: Tensor::glorot_uniform([vocab_size, embed_size]),
@@ -170,40 +285,39 @@ impl Embedding {
     }
 
     pub fn call(&self, idx: &Tensor) -> Tensor {
-        if self.vocab_counter.is_none() {
-            unsafe {
-                *Arc::get_mut_unchecked(&mut self.vocab_counter.clone()) =
-                    Some(Tensor::arange(self.vocab_size as f32).reshape([1, 1, self.vocab_size]))
-            };
-        }
-        let [batch_size, seqlen] = idx.shape().dims[..] else {
-            panic!()
-        };
-        if seqlen == 0 {
-            Tensor::empty([batch_size, 0, self.embed_size as isize])
-        } else {
-            (*self.vocab_counter)
-                .as_ref()
-                .unwrap()
-                ._eq(&idx.unsqueeze(2))
-                .expand(vec![idx.shape().dims.clone(), vec![self.vocab_size as isize]].concat())
-                .matmul(&self.weight)
+        if idx.numel() == 0 {
+            return Tensor::empty(vec![idx.shape().dims, vec![self.embed_size as isize]].concat());
         }
+        self.weight.index([idx])
     }
 }
 
-pub struct BatchNorm2d {
+// Normalizes every channel (axis 1) of a batch. In training mode (`Tensor::set_training`) it uses
+// the batch statistics and updates the running ones, in eval mode it uses the running ones.
+#[derive(Module)]
+pub struct BatchNorm {
+    #[module(skip)]
     pub eps: f32,
+    #[module(skip)]
     pub momentum: f32,
+    #[module(skip)]
     pub track_running_stats: bool,
+    #[module(rename = "weight")]
     pub weights: Option<Tensor>,
     pub bias: Option<Tensor>,
+    #[module(buffer)]
     pub running_mean: Tensor,
+    #[module(buffer)]
     pub running_var: Tensor,
+    #[module(buffer)]
     pub num_batches_tracked: Tensor,
 }
 
-impl BatchNorm2d {
+pub type BatchNorm1d = BatchNorm;
+pub type BatchNorm2d = BatchNorm;
+pub type BatchNorm3d = BatchNorm;
+
+impl BatchNorm {
     pub fn new(
         size: usize,
         eps: Option<f32>,
@@ -234,13 +348,49 @@ impl BatchNorm2d {
             num_batches_tracked,
         }
     }
+
+    pub fn call(&mut self, x: &Tensor) -> Tensor {
+        let (mean, invstd) = if Tensor::training() {
+            // Like torch, the unbiased running variance needs at least two values per channel
+            let n = x.numel() as f32 / x.shape()[1] as f32;
+            assert!(n > 1.0, "BatchNorm: expected more than 1 value per channel when training");
+            let axis = v![i as isize, for i in 0..x.shape().len(), if i != 1];
+            let shape = vec![vec![1, -1], vec![1; x.shape().len() - 2]].concat();
+            let batch_mean = x.mean(axis.clone(), false);
+            let y = x - &batch_mean.detach().reshape(shape);
+            let batch_var = (&y * &y).mean(axis, false);
+            if self.track_running_stats {
+                // The running variance is unbiased
+                let running_mean = (&self.running_mean * (1.0 - self.momentum)
+                    + batch_mean.detach() * self.momentum)
+                    .realize();
+                let running_var = (&self.running_var * (1.0 - self.momentum)
+                    + batch_var.detach() * (self.momentum * n / (n - 1.0)))
+                    .realize();
+                let num_batches_tracked = (&self.num_batches_tracked + 1.0).realize();
+                self.running_mean.assign(running_mean);
+                self.running_var.assign(running_var);
+                self.num_batches_tracked.assign(num_batches_tracked);
+            }
+            (batch_mean, (batch_var + self.eps).rsqrt())
+        } else {
+            (self.running_mean.clone(), (&self.running_var + self.eps).rsqrt())
+        };
+        x.batchnorm(self.weights.as_ref(), self.bias.as_ref(), &mean, &invstd)
+    }
 }
 
+#[derive(Module)]
 pub struct LayerNorm {
+    #[module(skip)]
     pub normalized_shape: Vec<isize>,
+    #[module(skip)]
     pub axis: Vec<isize>,
+    #[module(skip)]
     pub eps: f32,
+    #[module(skip)]
     pub elementwise_affine: bool,
+    #[module(rename = "weight")]
     pub weights: Option<Tensor>,
     pub bias: Option<Tensor>,
 }
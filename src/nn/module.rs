@@ -0,0 +1,158 @@
+use crate::prelude::*;
+
+pub use storm_derive::Module;
+
+// Name of the child `name` of the module called `prefix`
+pub fn join_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{prefix}.{name}")
+    }
+}
+
+// Anything holding tensors. `prefix` is the name of the module itself, "" at the root, and every
+// tensor is named by its path from there like torch does, e.g. "c1.weight". Layers use torch's
+// names so checkpoints line up. Implement it with `#[derive(Module)]`.
+pub trait Module {
+    // Tensors trained by an optimizer
+    fn named_parameters(&self, prefix: &str) -> Vec<(String, &Tensor)>;
+    fn named_parameters_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)>;
+
+    // State that is saved with the parameters but not trained, e.g. batchnorm running stats
+    fn named_buffers(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+        vec![]
+    }
+    fn named_buffers_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+        vec![]
+    }
+
+    // Ready to hand to an optimizer, e.g. `adam(model.parameters(), 0.001)`
+    fn parameters(&mut self) -> Vec<&mut Tensor> {
+        v![t, for (_, t) in self.named_parameters_mut("")]
+    }
+
+    fn buffers(&self) -> Vec<&Tensor> {
+        v![t, for (_, t) in self.named_buffers("")]
+    }
+
+    // Train/eval mode is per thread and shared by every module, see `Tensor::set_training`
+    fn train(&self) {
+        Tensor::set_training(true);
+    }
+
+    fn eval(&self) {
+        Tensor::set_training(false);
+    }
+}
+
+impl Module for Tensor {
+    fn named_parameters(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+        vec![(prefix.to_string(), self)]
+    }
+
+    fn named_parameters_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+        vec![(prefix.to_string(), self)]
+    }
+}
+
+impl<M: Module> Module for Option<M> {
+    fn named_parameters(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+        self.as_ref().map_or(vec![], |m| m.named_parameters(prefix))
+    }
+
+    fn named_parameters_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+        self.as_mut().map_or(vec![], |m| m.named_parameters_mut(prefix))
+    }
+
+    fn named_buffers(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+        self.as_ref().map_or(vec![], |m| m.named_buffers(prefix))
+    }
+
+    fn named_buffers_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+        self.as_mut().map_or(vec![], |m| m.named_buffers_mut(prefix))
+    }
+}
+
+impl<M: Module + ?Sized> Module for Box<M> {
+    fn named_parameters(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+        (**self).named_parameters(prefix)
+    }
+
+    fn named_parameters_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+        (**self).named_parameters_mut(prefix)
+    }
+
+    fn named_buffers(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+        (**self).named_buffers(prefix)
+    }
+
+    fn named_buffers_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+        (**self).named_buffers_mut(prefix)
+    }
+}
+
+// Items are named by their index
+impl<M: Module> Module for Vec<M> {
+    fn named_parameters(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+        self.iter()
+            .enumerate()
+            .flat_map(|(i, m)| m.named_parameters(&join_name(prefix, &i.to_string())))
+            .collect()
+    }
+
+    fn named_parameters_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+        self.iter_mut()
+            .enumerate()
+            .flat_map(|(i, m)| m.named_parameters_mut(&join_name(prefix, &i.to_string())))
+            .collect()
+    }
+
+    fn named_buffers(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+        self.iter()
+            .enumerate()
+            .flat_map(|(i, m)| m.named_buffers(&join_name(prefix, &i.to_string())))
+            .collect()
+    }
+
+    fn named_buffers_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+        self.iter_mut()
+            .enumerate()
+            .flat_map(|(i, m)| m.named_buffers_mut(&join_name(prefix, &i.to_string())))
+            .collect()
+    }
+}
+
+macro_rules! impl_module_tuple {
+    ($($t:ident $i:tt),+) => {
+        impl<$($t: Module),+> Module for ($($t,)+) {
+            fn named_parameters(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+                let mut ret = vec![];
+                $(ret.extend(self.$i.named_parameters(&join_name(prefix, stringify!($i))));)+
+                ret
+            }
+
+            fn named_parameters_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+                let mut ret = vec![];
+                $(ret.extend(self.$i.named_parameters_mut(&join_name(prefix, stringify!($i))));)+
+                ret
+            }
+
+            fn named_buffers(&self, prefix: &str) -> Vec<(String, &Tensor)> {
+                let mut ret = vec![];
+                $(ret.extend(self.$i.named_buffers(&join_name(prefix, stringify!($i))));)+
+                ret
+            }
+
+            fn named_buffers_mut(&mut self, prefix: &str) -> Vec<(String, &mut Tensor)> {
+                let mut ret = vec![];
+                $(ret.extend(self.$i.named_buffers_mut(&join_name(prefix, stringify!($i))));)+
+                ret
+            }
+        }
+    };
+}
+
+impl_module_tuple!(A 0, B 1);
+impl_module_tuple!(A 0, B 1, C 2);
+impl_module_tuple!(A 0, B 1, C 2, D 3);
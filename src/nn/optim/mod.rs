@@ -1,21 +1,83 @@
+use std::collections::HashSet;
+
 use crate::prelude::*;
 
+// A model parameter registered with an optimizer. It is a clone of the model's tensor, so it
+// shares its device buffer and grad, and `update` writes into that buffer. The model can be
+// moved or borrowed while the optimizer holds its parameters.
+#[derive(Debug, Clone)]
+pub struct Param(Tensor);
+
+impl From<&mut Tensor> for Param {
+    fn from(t: &mut Tensor) -> Self {
+        if !t.grad_pinned {
+            t.require_grad = true;
+        }
+        // Clones only share a buffer once there is one
+        t.realize();
+        Self(t.clone())
+    }
+}
+
+impl Param {
+    pub fn tensor(&self) -> &Tensor {
+        &self.0
+    }
+
+    pub fn grad(&self) -> Option<Tensor> {
+        self.0.grad.lock().unwrap().clone()
+    }
+
+    pub fn zero_grad(&self) {
+        *self.0.grad.lock().unwrap() = None;
+    }
+
+    // Realizes `x` and swaps its buffer in for the parameter's, every clone of the model tensor
+    // sees the new value
+    pub fn update(&self, x: Tensor) {
+        assert!(self.0.shape() == x.shape(), "parameter update changes shape");
+        let x = if x.dtype() != self.0.dtype() { x.cast(self.0.dtype()) } else { x };
+        let x = x.contiguous().realize();
+        self.0.buffer.set_realized(x.buffer.realized());
+    }
+}
+
+// Frozen tensors, see Tensor::requires_grad_, are left out
+fn collect_params<P: Into<Param>>(params: impl IntoIterator<Item = P>) -> Vec<Param> {
+    let mut seen = HashSet::new();
+    v![p, for p in params.into_iter().map(Into::into), if p.0.require_grad && seen.insert(p.0.id)]
+}
+
 pub trait Optimizer {
-    fn zero_grad(&mut self);
+    fn params(&self) -> &[Param];
     fn realize(&mut self);
     fn step(&mut self);
+
+    fn zero_grad(&mut self) {
+        for p in self.params() {
+            p.zero_grad();
+        }
+    }
+}
+
+fn grad(p: &Param) -> Tensor {
+    p.grad().expect("parameter has no grad, call backward first").realize()
+}
+
+pub fn sgd<P: Into<Param>>(params: impl IntoIterator<Item = P>, lr: f32) -> SGD {
+    SGD::new(params, lr, 0.0, 0.0, false)
 }
 
-pub fn adam<'a>(params: &[*mut Tensor], lr: f32) -> LAMP {
-    LAMP::new(params.to_vec(), lr, 0.9, 0.999, 1e-8, 0.0, true)
+pub fn adam<P: Into<Param>>(params: impl IntoIterator<Item = P>, lr: f32) -> LAMP {
+    LAMP::new(params, lr, 0.9, 0.999, 1e-8, 0.0, true)
 }
 
-pub fn adam_with<'a>(params: &[*mut Tensor], p: &[f32]) -> LAMP {
+pub fn adam_with<P: Into<Param>>(params: impl IntoIterator<Item = P>, p: &[f32]) -> LAMP {
     assert!(p.len() > 0, "need lr");
     let mut default = [0.001, 0.9, 0.999, 1e-8, 0.0];
     default.iter_mut().zip(p).for_each(|(d, p)| *d = *p);
     LAMP::new(
-        params.to_vec(),
+        params,
         default[0],
         default[1],
         default[2],
@@ -24,11 +86,88 @@ pub fn adam_with<'a>(params: &[*mut Tensor], p: &[f32]) -> LAMP {
         true,
     )
 }
+
+// Adam with the weight decay added to the update instead of the gradient
+pub fn adamw<P: Into<Param>>(params: impl IntoIterator<Item = P>, lr: f32, wd: f32) -> LAMP {
+    LAMP::new(params, lr, 0.9, 0.999, 1e-8, wd, true)
+}
+
+pub fn lamb<P: Into<Param>>(params: impl IntoIterator<Item = P>, lr: f32) -> LAMP {
+    LAMP::new(params, lr, 0.9, 0.999, 1e-6, 0.0, false)
+}
+
+pub fn rmsprop<P: Into<Param>>(params: impl IntoIterator<Item = P>, lr: f32) -> RMSprop {
+    RMSprop::new(params, lr, 0.99, 1e-8, 0.0, 0.0)
+}
+
+#[derive(Debug)]
+pub struct SGD {
+    pub(crate) params: Vec<Param>,
+    pub(crate) lr: Tensor,
+    pub(crate) momentum: f32,
+    pub(crate) wd: f32,
+    pub(crate) nesterov: bool,
+    pub(crate) b: Vec<Tensor>,
+}
+
+impl SGD {
+    pub fn new<P: Into<Param>>(
+        params: impl IntoIterator<Item = P>,
+        lr: f32,
+        momentum: f32,
+        wd: f32,
+        nesterov: bool,
+    ) -> Self {
+        let params = collect_params(params);
+        let b = if momentum != 0.0 {
+            v![Tensor::zeros(p.tensor().shape()), for p in params.iter()]
+        } else {
+            vec![]
+        };
+        Self {
+            params,
+            lr: Tensor::from([lr]),
+            momentum,
+            wd,
+            nesterov,
+            b,
+        }
+    }
+}
+
+impl Optimizer for SGD {
+    fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    fn realize(&mut self) {
+        let mut lst = self.b.clone();
+        lst.extend(v![p.tensor().clone(), for p in self.params.iter()]);
+        Tensor::corealize(lst);
+    }
+
+    fn step(&mut self) {
+        for (i, p) in self.params.iter().enumerate() {
+            let t = p.tensor().detach();
+            let mut g = grad(p);
+            if self.wd != 0.0 {
+                g = &g + &(&t * self.wd);
+            }
+            if self.momentum != 0.0 {
+                let b = (&self.b[i] * self.momentum + &g).realize();
+                self.b[i].assign(b);
+                g = if self.nesterov { &g + &(&self.b[i] * self.momentum) } else { self.b[i].clone() };
+            }
+            p.update(&t - &(&self.lr * &g));
+        }
+        self.realize()
+    }
+}
+
 //def __init__(self, params: List[Tensor], lr=0.001, b1=0.9, b2=0.999, eps=1e-6, wd=0.0, adam=False):
 #[derive(Debug)]
 pub struct LAMP {
-    pub(crate) params: Vec<*mut Tensor>,
-    pub(crate) buffers: Vec<*mut Tensor>,
+    pub(crate) params: Vec<Param>,
     pub(crate) lr: Tensor,
     pub(crate) b1: Tensor,
     pub(crate) b2: Tensor,
@@ -41,8 +180,8 @@ pub struct LAMP {
 }
 
 impl LAMP {
-    pub fn new(
-        mut _params: Vec<*mut Tensor>,
+    pub fn new<P: Into<Param>>(
+        params: impl IntoIterator<Item = P>,
         lr: f32,
         b1: f32,
         b2: f32,
@@ -50,105 +189,140 @@ impl LAMP {
         wd: f32,
         adam: bool,
     ) -> Self {
-        unsafe {
-            let mut params = Vec::new();
-            while !_params.is_empty() {
-                let t = _params.pop().unwrap();
-                // if (*t).require_grad {
-                //     params.push(t);
-                // } else {
-                //     buffers.push(t);
-                // }
-                (*t).require_grad = true;
-                params.push(t);
-            }
-            let lr = Tensor::from([lr]);
-            params.dedup_by_key(|t| (*(*t)).id);
-            //buffers.dedup_by_key(|t| (*(*t)).id);
-            let m = params
-                .iter()
-                .map(|t| Tensor::zeros((**t).shape()))
-                .collect();
-            let v = params
-                .iter()
-                .map(|t| Tensor::zeros((**t).shape()))
-                .collect();
-            Self {
-                params,
-                buffers: vec![],
-                lr,
-                b1: Tensor::from([b1]),
-                b2: Tensor::from([b2]),
-                eps,
-                wd,
-                adam,
-                t: Tensor::from([0.]),
-                m,
-                v,
-            }
+        let params = collect_params(params);
+        let m = v![Tensor::zeros(p.tensor().shape()), for p in params.iter()];
+        let v = v![Tensor::zeros(p.tensor().shape()), for p in params.iter()];
+        Self {
+            params,
+            lr: Tensor::from([lr]),
+            b1: Tensor::from([b1]),
+            b2: Tensor::from([b2]),
+            eps,
+            wd,
+            adam,
+            t: Tensor::from([0.]),
+            m,
+            v,
         }
     }
 }
 
 impl Optimizer for LAMP {
-    fn zero_grad(&mut self) {
-        for p in self.params.iter_mut() {
-            unsafe {
-                let p = &(*(*p));
-                *p.grad.lock().unwrap() = None;
-            }
-        }
+    fn params(&self) -> &[Param] {
+        &self.params
     }
 
     fn realize(&mut self) {
-        let mut lst = vec![];
-        for b in self.m.iter() {
-            b.realize();
-        }
-        for b in self.v.iter() {
-            b.realize();
-        }
-        for b in self.buffers.iter() {
-            unsafe {
-                lst.push((**b).clone());
+        let mut lst = vec![self.t.clone()];
+        lst.extend(self.m.iter().cloned());
+        lst.extend(self.v.iter().cloned());
+        lst.extend(v![p.tensor().clone(), for p in self.params.iter()]);
+        Tensor::corealize(lst);
+    }
+
+    fn step(&mut self) {
+        self.t.assign((&self.t + 1.).realize());
+        for (i, p) in self.params.iter().enumerate() {
+            let t = p.tensor().detach();
+            let g = grad(p);
+
+            // self.m[i].assign(self.m[i] * self.b1 + g * (1.0 - self.b1)).realize()
+            // self.v[i].assign(self.v[i] * self.b2 + (g * g) * (1.0 - self.b2)).realize()
+            let mi = (&self.m[i] * &self.b1 + &g * &(1.0 - &self.b1)).realize();
+            let vi = (&self.v[i] * &self.b2 + (&g * &g) * (1.0 - &self.b2)).realize();
+            self.m[i].assign(mi);
+            self.v[i].assign(vi);
+            // m_hat = self.m[i] / (1.0 - self.b1**self.t)
+            let m_hat = &self.m[i] / &(1.0 - self.b1.pow(self.t.clone(), false));
+            // v_hat = self.v[i] / (1.0 - self.b2**self.t)
+            let v_hat = &self.v[i] / &(1.0 - self.b2.pow(self.t.clone(), false));
+            // up = (m_hat / (v_hat.sqrt() + self.eps)) + self.wd * t.detach()
+            let mut up = (m_hat / (v_hat.sqrt() + self.eps)) + &t * self.wd;
+            if !self.adam {
+                // r1 = t.detach().square().sum().sqrt(), r2 = up.square().sum().sqrt()
+                // r = Tensor.where(r1 > 0, Tensor.where(r2 > 0, r1 / r2, 1.0), 1.0)
+                let r1 = (&t * &t).sum([], false).sqrt();
+                let r2 = (&up * &up).sum([], false).sqrt();
+                let zero = Tensor::from([0.]);
+                let one = Tensor::from([1.]);
+                let r = r1._gt(&zero)._where_(&r2._gt(&zero)._where_(&(&r1 / &r2), &one), &one);
+                up = &up * &r;
             }
+            p.update(&t - &(&self.lr * &up));
         }
-        for b in self.params.iter() {
-            unsafe {
-                lst.push((**b).clone());
-            }
+        self.realize()
+    }
+}
+
+#[derive(Debug)]
+pub struct RMSprop {
+    pub(crate) params: Vec<Param>,
+    pub(crate) lr: Tensor,
+    pub(crate) alpha: f32,
+    pub(crate) eps: f32,
+    pub(crate) wd: f32,
+    pub(crate) momentum: f32,
+    pub(crate) v: Vec<Tensor>,
+    pub(crate) b: Vec<Tensor>,
+}
+
+impl RMSprop {
+    pub fn new<P: Into<Param>>(
+        params: impl IntoIterator<Item = P>,
+        lr: f32,
+        alpha: f32,
+        eps: f32,
+        wd: f32,
+        momentum: f32,
+    ) -> Self {
+        let params = collect_params(params);
+        let v = v![Tensor::zeros(p.tensor().shape()), for p in params.iter()];
+        let b = if momentum != 0.0 {
+            v![Tensor::zeros(p.tensor().shape()), for p in params.iter()]
+        } else {
+            vec![]
+        };
+        Self {
+            params,
+            lr: Tensor::from([lr]),
+            alpha,
+            eps,
+            wd,
+            momentum,
+            v,
+            b,
         }
+    }
+}
+
+impl Optimizer for RMSprop {
+    fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    fn realize(&mut self) {
+        let mut lst = self.v.clone();
+        lst.extend(self.b.iter().cloned());
+        lst.extend(v![p.tensor().clone(), for p in self.params.iter()]);
         Tensor::corealize(lst);
     }
 
     fn step(&mut self) {
-        self.t.assign((&self.t + 1.).realize());
-        unsafe {
-            for (i, t) in self.params.iter_mut().enumerate() {
-                let t = &mut (**t);
-                assert!(t.grad.lock().unwrap().is_some());
-                let g = (*t.grad.lock().unwrap()).clone();
-                let mut g = g.unwrap().realize();
-
-                // self.m[i].assign(self.m[i] * self.b1 + g * (1.0 - self.b1)).realize()
-                // self.v[i].assign(self.v[i] * self.b2 + (g * g) * (1.0 - self.b2)).realize()
-                let mi = (&self.m[i] * &self.b1 + &g * &(1.0 - &self.b1)).realize();
-                let vi = (&self.v[i] * &self.b2 + (&g * &g) * (1.0 - &self.b2)).realize();
-                self.m[i].assign(mi);
-                self.v[i].assign(vi);
-                // m_hat = self.m[i] / (1.0 - self.b1**self.t)
-                let m_hat = (&self.m[i] / &(1.0 - self.b1.pow(self.t.clone(), false)));
-                // v_hat = self.v[i] / (1.0 - self.b2**self.t)
-                let v_hat = &self.v[i] / &(1.0 - self.b2.pow(self.t.clone(), false));
-                // up = (m_hat / (v_hat.sqrt() + self.eps)) + self.wd * t.detach()
-                let up = (m_hat / (v_hat.sqrt() + self.eps)) + t.detach() * self.wd;
-                let r = if !self.adam { todo!() } else { 1.0 };
-                //println!("{:?}", tmp.to_vec());
-                t.assign(t.detach() - &(&self.lr * r * up)).realize();
-
-                // just in case _ctx is attach in Function{}.apply() but shouldnt matter, but
-                // why not
+        for (i, p) in self.params.iter().enumerate() {
+            let t = p.tensor().detach();
+            let mut g = grad(p);
+            if self.wd != 0.0 {
+                g = &g + &(&t * self.wd);
+            }
+            let vi = (&self.v[i] * self.alpha + &(&g * &g) * (1.0 - self.alpha)).realize();
+            self.v[i].assign(vi);
+            let mut up = &g / &(self.v[i].sqrt() + self.eps);
+            if self.momentum != 0.0 {
+                let b = (&self.b[i] * self.momentum + &up).realize();
+                self.b[i].assign(b);
+                up = self.b[i].clone();
             }
+            p.update(&t - &(&self.lr * &up));
         }
         self.realize()
     }
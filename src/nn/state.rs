@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail};
+use safetensors::tensor::TensorView;
+use safetensors::{Dtype as SDtype, SafeTensors};
+
+use crate::device::disk::{self, DiskBuffer};
+use crate::prelude::*;
+
+// Keys that did not line up when loading a state dict
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoadReport {
+    pub missing: Vec<String>,
+    pub unexpected: Vec<String>,
+}
+
+// Saving and loading for every `Module`, keyed by the names from `named_parameters` and
+// `named_buffers`
+pub trait StateDict: Module {
+    fn named_tensors(&self) -> Vec<(String, &Tensor)> {
+        let mut ret = self.named_parameters("");
+        ret.extend(self.named_buffers(""));
+        ret
+    }
+
+    fn state_dict(&self) -> BTreeMap<String, Tensor> {
+        BTreeMap::from_iter(v![(k, t.clone()), for (k, t) in self.named_tensors()])
+    }
+
+    // Assigns every tensor in `state` to the one with the same name, cast to its dtype. With
+    // `strict` any missing or unexpected key is an error and nothing gets loaded.
+    fn load_state_dict(
+        &mut self,
+        mut state: BTreeMap<String, Tensor>,
+        strict: bool,
+    ) -> anyhow::Result<LoadReport> {
+        let tensors = self.named_tensors();
+        let mut report = LoadReport::default();
+        for (k, t) in tensors.iter() {
+            match state.get(k) {
+                Some(v) if v.shape() != t.shape() => bail!(
+                    "shape mismatch for {k}, model has {:?} but state dict has {:?}",
+                    t.shape().dims,
+                    v.shape().dims
+                ),
+                Some(_) => (),
+                None => report.missing.push(k.clone()),
+            }
+        }
+        let names = v![k.clone(), for (k, _) in tensors.iter()];
+        report.unexpected = v![k.clone(), for k in state.keys(), if !names.contains(k)];
+        if strict && (report.missing.len() > 0 || report.unexpected.len() > 0) {
+            bail!(
+                "error loading state dict, missing keys: {:?}, unexpected keys: {:?}",
+                report.missing,
+                report.unexpected
+            );
+        }
+        let mut load = |k: &String, t: &mut Tensor| {
+            if let Some(v) = state.remove(k) {
+                let v = v.to(&t.device());
+                let v = if v.dtype() != t.dtype() { v.cast(t.dtype()) } else { v };
+                t.assign(v.realize());
+            }
+        };
+        for (k, t) in self.named_parameters_mut("") {
+            load(&k, t);
+        }
+        for (k, t) in self.named_buffers_mut("") {
+            load(&k, t);
+        }
+        Ok(report)
+    }
+
+    fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        safe_save(&self.state_dict(), path)
+    }
+
+    fn load(&mut self, path: impl AsRef<Path>, strict: bool) -> anyhow::Result<LoadReport> {
+        self.load_state_dict(safe_load(path)?, strict)
+    }
+}
+
+impl<M: Module + ?Sized> StateDict for M {}
+
+fn to_safetensors_dtype(dtype: &Dtype) -> anyhow::Result<SDtype> {
+    Ok(match *dtype {
+        _bool => SDtype::BOOL,
+        float16 => SDtype::F16,
+        bfloat16 => SDtype::BF16,
+        float32 => SDtype::F32,
+        float64 => SDtype::F64,
+        int8 => SDtype::I8,
+        int16 => SDtype::I16,
+        int32 => SDtype::I32,
+        int64 => SDtype::I64,
+        uint8 => SDtype::U8,
+        uint16 => SDtype::U16,
+        uint32 => SDtype::U32,
+        uint64 => SDtype::U64,
+        _ => Err(StormError::UnsupportedDtype {
+            dtype: dtype.to_string(),
+            on: "safetensors".into(),
+        })?,
+    })
+}
+
+fn from_safetensors_dtype(dtype: SDtype) -> anyhow::Result<Dtype> {
+    Ok(match dtype {
+        SDtype::BOOL => _bool,
+        SDtype::F16 => float16,
+        SDtype::BF16 => bfloat16,
+        SDtype::F32 => float32,
+        SDtype::F64 => float64,
+        SDtype::I8 => int8,
+        SDtype::I16 => int16,
+        SDtype::I32 => int32,
+        SDtype::I64 => int64,
+        SDtype::U8 => uint8,
+        SDtype::U16 => uint16,
+        SDtype::U32 => uint32,
+        SDtype::U64 => uint64,
+        _ => Err(StormError::UnsupportedDtype {
+            dtype: format!("{dtype:?}"),
+            on: "storm".into(),
+        })?,
+    })
+}
+
+fn tensor_bytes(t: &Tensor) -> Vec<u8> {
+    let t = t.contiguous().realize();
+    let mut bytes = t.buffer.realized().expect("buffer not realized").to_cpu();
+    bytes.truncate(t.shape().numel() * t.dtype().size);
+    bytes
+}
+
+pub fn safe_save(state: &BTreeMap<String, Tensor>, path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let data = v![(k.clone(), tensor_bytes(t), t), for (k, t) in state.iter()];
+    let mut views = vec![];
+    for (k, bytes, t) in data.iter() {
+        let shape = v![*s as usize, for s in t.shape().dims.iter()];
+        let view = TensorView::new(to_safetensors_dtype(&t.dtype())?, shape, bytes)
+            .map_err(|e| anyhow!("{k}: {e:?}"))?;
+        views.push((k.clone(), view));
+    }
+    safetensors::serialize_to_file(views, &None, path.as_ref())?;
+    Ok(())
+}
+
+// Tensors come back on the DISK device backed by the mapped file, nothing is read until they
+// are moved to a real device and realized
+pub fn safe_load(path: impl AsRef<Path>) -> anyhow::Result<BTreeMap<String, Tensor>> {
+    let data = disk::open(path)?;
+    let bytes = (*data).as_ref();
+    let tensors = SafeTensors::deserialize(bytes)?;
+    let mut ret = BTreeMap::new();
+    for (k, view) in tensors.tensors() {
+        let dtype = from_safetensors_dtype(view.dtype())?;
+        let offset = view.data().as_ptr() as usize - bytes.as_ptr() as usize;
+        let buf = DiskBuffer::new(data.clone(), offset, view.data().len(), dtype);
+        let shape = v![*s as isize, for s in view.shape().iter()];
+        ret.insert(k, Tensor::from_buf(LazyBuffer::from_buffer(Arc::new(buf), &shape)));
+    }
+    Ok(ret)
+}
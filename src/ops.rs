@@ -49,6 +49,26 @@ pub trait Op: 'static + core::fmt::Debug + Send + Sync {
         format!("({a}%{b})")
     }
 
+    fn shr(&self, a: &str, b: &str) -> String {
+        format!("({a}>>{b})")
+    }
+
+    fn and(&self, a: &str, b: &str) -> String {
+        format!("({a}&{b})")
+    }
+
+    fn or(&self, a: &str, b: &str) -> String {
+        format!("({a}|{b})")
+    }
+
+    fn xor(&self, a: &str, b: &str) -> String {
+        format!("({a}^{b})")
+    }
+
+    fn shl(&self, a: &str, b: &str) -> String {
+        format!("({a}<<{b})")
+    }
+
     fn cmpmax(&self, a: &str, b: &str) -> String {
         format!("max({a},{b})")
     }
@@ -97,6 +117,11 @@ pub trait Op: 'static + core::fmt::Debug + Send + Sync {
                     Binary::Mod => self._mod(&args[0], &args[1]),
                     Binary::Max => self.cmpmax(&args[0], &args[1]),
                     Binary::Cmplt => self.cmplt(&args[0], &args[1]),
+                    Binary::Shr => self.shr(&args[0], &args[1]),
+                    Binary::And => self.and(&args[0], &args[1]),
+                    Binary::Or => self.or(&args[0], &args[1]),
+                    Binary::Xor => self.xor(&args[0], &args[1]),
+                    Binary::Shl => self.shl(&args[0], &args[1]),
                 }
             }
             OpType::Reduce(rop) => {
@@ -138,6 +163,12 @@ pub enum Binary {
     Mod,
     Max,
     Cmplt,
+    // Only made by the uop passes and the random number generator, on nonnegative ints
+    Shr,
+    And,
+    Or,
+    Xor,
+    Shl,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -171,6 +202,7 @@ pub enum Load {
     From,
     Contiguous,
     Custom,
+    Gather,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -179,6 +211,8 @@ pub enum Buffer {
     Store,
     Const,
     Mem,
+    Rand,
+    Gather,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -260,9 +294,13 @@ impl LazyOpSrc {
     }
 
     pub fn lb(&self) -> &LazyBuffer {
+        self.try_lb().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_lb(&self) -> Result<&LazyBuffer, StormError> {
         match self {
-            LazyOpSrc::LazyOp(_) => panic!("Lazyop cant turn into lazybuffer"),
-            LazyOpSrc::LazyBuffer(lb) => lb,
+            LazyOpSrc::LazyOp(_) => Err(StormError::Internal("Lazyop cant turn into lazybuffer".into())),
+            LazyOpSrc::LazyBuffer(lb) => Ok(lb),
         }
     }
 
@@ -0,0 +1,203 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Instant;
+
+use crate::prelude::*;
+
+#[derive(Debug, Clone)]
+pub struct KernelRecord {
+    pub name: String,
+    pub device: String,
+    pub global_size: Vec<usize>,
+    pub local_size: Vec<usize>,
+    // Since `start`, in microseconds
+    pub start_us: f64,
+    // From popping the schedule item to the kernel finishing, lookup and compilation included
+    pub wall_ms: f64,
+    // From launching the kernel to the device synchronize returning, launch overhead included
+    pub sync_ms: f64,
+    pub flops: f64,
+    // Bytes read and written
+    pub mem: usize,
+}
+
+impl KernelRecord {
+    pub fn gflops(&self) -> f64 {
+        per_ms(self.flops, self.sync_ms)
+    }
+
+    pub fn gbs(&self) -> f64 {
+        per_ms(self.mem as f64, self.sync_ms)
+    }
+}
+
+// x per ms in billions per second, 0 for kernels too fast for the clock
+fn per_ms(x: f64, ms: f64) -> f64 {
+    if ms > 0. { x / (ms * 1e6) } else { 0. }
+}
+
+thread_local! {
+    // Kernels run on this thread since `start`
+    static PROFILE: RefCell<Option<(Instant, Vec<KernelRecord>)>> = const { RefCell::new(None) };
+}
+
+// Starts recording every kernel run on this thread, from `run_schedule` or a jit replay. Each
+// kernel is followed by a device synchronize so its time is its own, which slows things down.
+pub fn start() {
+    PROFILE.with(|p| p.replace(Some((Instant::now(), vec![]))));
+}
+
+// Stops recording and returns what was recorded since `start`, empty if it wasn't called
+pub fn stop() -> Profile {
+    let kernels = PROFILE
+        .with(|p| p.take())
+        .map(|(_, k)| k)
+        .unwrap_or_default();
+    Profile { kernels }
+}
+
+// Whether kernels need timing, DEBUG=PROFILE prints every kernel without `start`
+pub fn is_enabled() -> bool {
+    PROFILE.with(|p| p.borrow().is_some()) || DEBUG.0.contains("PROFILE")
+}
+
+// Runs prg, waits for it and records it. wall_start is when the kernel's schedule item was popped,
+// or the launch for replays.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run(
+    prg: &dyn Program,
+    device: &dyn Device,
+    name: &str,
+    bufs: &[std::sync::Arc<dyn Buffer>],
+    global_size: &[usize],
+    local_size: &[usize],
+    (flops, mem): (f64, usize),
+    wall_start: Instant,
+) {
+    let launch = Instant::now();
+    prg.run(bufs, global_size, Some(local_size), &[], &[]);
+    device.synchronize();
+    let end = Instant::now();
+    let mut rec = KernelRecord {
+        name: name.to_string(),
+        device: bufs[0].device(),
+        global_size: global_size.to_vec(),
+        local_size: local_size.to_vec(),
+        start_us: 0.,
+        wall_ms: (end - wall_start).as_secs_f64() * 1e3,
+        sync_ms: (end - launch).as_secs_f64() * 1e3,
+        flops,
+        mem,
+    };
+    if DEBUG.0.contains("PROFILE") {
+        println!(
+            "{:<32} {:>16} {:>16} {:>10.3} ms {:>10.2} GFLOPS {:>10.2} GB/s",
+            rec.name,
+            format!("{:?}", rec.global_size),
+            format!("{:?}", rec.local_size),
+            rec.sync_ms,
+            rec.gflops(),
+            rec.gbs()
+        );
+    }
+    PROFILE.with(|p| {
+        if let Some((t0, kernels)) = p.borrow_mut().as_mut() {
+            rec.start_us = (launch - *t0).as_secs_f64() * 1e6;
+            kernels.push(rec);
+        }
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub kernels: Vec<KernelRecord>,
+}
+
+impl Profile {
+    pub fn total_ms(&self) -> f64 {
+        self.kernels.iter().map(|k| k.sync_ms).sum()
+    }
+
+    // One row per kernel and launch size, slowest total first. Rates are over the summed sync
+    // time of all calls.
+    pub fn report(&self) -> String {
+        let mut rows: Vec<(&KernelRecord, usize, f64, f64, usize)> = vec![];
+        let mut idx = HashMap::new();
+        for k in self.kernels.iter() {
+            let key = (&k.name, &k.global_size, &k.local_size);
+            let i = *idx.entry(key).or_insert_with(|| {
+                rows.push((k, 0, 0., 0., 0));
+                rows.len() - 1
+            });
+            let row = &mut rows[i];
+            row.1 += 1;
+            row.2 += k.sync_ms;
+            row.3 += k.flops;
+            row.4 += k.mem;
+        }
+        rows.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let total = self.total_ms();
+        let mut ret = format!(
+            "{:<32} {:>16} {:>16} {:>6} {:>10} {:>6} {:>10} {:>10}\n",
+            "kernel", "global", "local", "calls", "sync_ms", "%", "GFLOPS", "GB/s"
+        );
+        for (k, calls, ms, flops, mem) in rows {
+            writeln!(
+                ret,
+                "{:<32} {:>16} {:>16} {:>6} {:>10.3} {:>6.1} {:>10.2} {:>10.2}",
+                k.name,
+                format!("{:?}", k.global_size),
+                format!("{:?}", k.local_size),
+                calls,
+                ms,
+                if total > 0. { 100. * ms / total } else { 0. },
+                per_ms(flops, ms),
+                per_ms(mem as f64, ms)
+            )
+            .unwrap();
+        }
+        writeln!(ret, "{} kernels in {total:.3} ms", self.kernels.len()).unwrap();
+        ret
+    }
+
+    // Chrome trace-event JSON with one complete event per kernel and one process per device, opens
+    // in Perfetto or chrome://tracing
+    pub fn trace(&self) -> String {
+        let mut pids: Vec<&str> = vec![];
+        let mut events = vec![];
+        for k in self.kernels.iter() {
+            let pid = pids.iter().position(|d| *d == k.device).unwrap_or_else(|| {
+                pids.push(&k.device);
+                pids.len() - 1
+            });
+            events.push(format!(
+                "{{\"name\":\"{}\",\"cat\":\"kernel\",\"ph\":\"X\",\"pid\":{pid},\"tid\":0,\"ts\":{:.3},\"dur\":{:.3},\"args\":{{\"global_size\":{:?},\"local_size\":{:?},\"wall_ms\":{:.6},\"sync_ms\":{:.6},\"GFLOPS\":{:.3},\"GB/s\":{:.3}}}}}",
+                escape(&k.name),
+                k.start_us,
+                k.sync_ms * 1e3,
+                k.global_size,
+                k.local_size,
+                k.wall_ms,
+                k.sync_ms,
+                k.gflops(),
+                k.gbs()
+            ));
+        }
+        for (pid, device) in pids.iter().enumerate() {
+            events.push(format!(
+                "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{pid},\"args\":{{\"name\":\"{}\"}}}}",
+                escape(device)
+            ));
+        }
+        format!("{{\"traceEvents\":[{}]}}", events.join(","))
+    }
+
+    pub fn save_trace(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.trace())
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,62 @@
+use std::cell::Cell;
+
+use crate::prelude::*;
+
+// Threefry2x32-20 from "Parallel Random Numbers: As Easy as 1, 2, 3" (Salmon et al.). It is
+// counter based, word n of a stream only depends on the seed and n, so a tensor gets the same
+// numbers no matter when, where or in which order it is realized. It only needs 32 bit adds,
+// rotates and xors, so kernels compute the same words as the host on every backend.
+pub const ROTATIONS: [u32; 8] = [13, 15, 26, 6, 17, 29, 16, 24];
+pub const PARITY: u32 = 0x1BD1_1BDA;
+
+thread_local! {
+    // (seed, offset of the next free word), seeded from SEED or the OS on first use
+    static STATE: Cell<Option<(u64, u64)>> = const { Cell::new(None) };
+}
+
+pub fn threefry2x32(ctr: [u32; 2], key: [u32; 2]) -> [u32; 2] {
+    let ks = [key[0], key[1], key[0] ^ key[1] ^ PARITY];
+    let [mut x0, mut x1] = [ctr[0].wrapping_add(ks[0]), ctr[1].wrapping_add(ks[1])];
+    for i in 0..5 {
+        for r in ROTATIONS[i % 2 * 4..i % 2 * 4 + 4].iter() {
+            x0 = x0.wrapping_add(x1);
+            x1 = x1.rotate_left(*r) ^ x0;
+        }
+        x0 = x0.wrapping_add(ks[(i + 1) % 3]);
+        x1 = x1.wrapping_add(ks[(i + 2) % 3]).wrapping_add(i as u32 + 1);
+    }
+    [x0, x1]
+}
+
+// Words offset..offset+n of the stream for seed, word i is the first output for counter i
+pub fn threefry_stream(seed: u64, offset: u64, n: usize) -> Vec<u32> {
+    let key = [seed as u32, (seed >> 32) as u32];
+    v![threefry2x32([i as u32, (i >> 32) as u32], key)[0], for i in offset..offset + n as u64]
+}
+
+// Restarts this thread's stream
+pub fn manual_seed(seed: u64) {
+    STATE.with(|s| s.set(Some((seed, 0))));
+}
+
+// Hands out the next n words of this thread's stream as (seed, offset). A draw never crosses a
+// multiple of 2^32 words, so kernels only add the element index to the low word of the counter.
+pub fn reserve(n: usize) -> (u64, u64) {
+    assert!(n as u64 <= 1 << 32, "can't draw {n} words at once");
+    STATE.with(|s| {
+        let (seed, mut offset) = s.get().unwrap_or_else(|| {
+            let seed = getenv::<i64>("SEED", -1);
+            (if seed >= 0 { seed as u64 } else { rand::random() }, 0)
+        });
+        if (offset & 0xFFFF_FFFF) + n as u64 > 1 << 32 {
+            offset = ((offset >> 32) + 1) << 32;
+        }
+        s.set(Some((seed, offset + n as u64)));
+        (seed, offset)
+    })
+}
+
+// Maps a word to [0, n) by multiply-shift, which avoids the modulo bias for small n
+pub fn below(word: u32, n: u64) -> u64 {
+    (word as u64 * n) >> 32
+}
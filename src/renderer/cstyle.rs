@@ -125,6 +125,9 @@ pub trait Renderer: 'static + Send + Sync + Op {
         } else {
             if x.int < 0 {
                 "(".to_string() + &x.int.to_string() + &")"
+            } else if var_dtype.is_unsigned() {
+                // Keeps arithmetic on unsigned values unsigned, 2654435769 alone is a long
+                x.int.to_string() + "u"
             } else {
                 x.int.to_string()
             }
@@ -505,6 +508,10 @@ pub fn uops_to_cstyle(lang: Arc<dyn Renderer>, function_name: &str, uops: &[UOp]
                             lang.render_const(fint, dtype.as_ref().unwrap().clone()),
                         );
                     }
+                    Arg::Idx(i) if dtype.as_ref().is_some_and(|d| d.is_unsigned()) => {
+                        let fint = FloatInt { float: 0.0, int: *i };
+                        r.insert(u.clone(), lang.render_const(fint, dtype.clone().unwrap()));
+                    }
                     Arg::Idx(i) => {
                         r.insert(u.clone(), i.to_string());
                     }
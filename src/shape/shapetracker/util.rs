@@ -37,7 +37,7 @@ pub fn _merge_dims(
     let mut ret = vec![(
         shape[0],
         strides[0],
-        if strides[0] > 0 { shape[0] } else { 0 },
+        if strides[0] != 0 { shape[0] } else { 0 },
     )];
     let mut state = if mask.is_some()
         && strides[0] == 0
@@ -57,7 +57,7 @@ pub fn _merge_dims(
             *ret.last_mut().unwrap() = (
                 ret[ret.len() - 1].0 * sh,
                 st,
-                if st > 0 {
+                if st != 0 {
                     if state == 1 {
                         sh
                     } else {
@@ -68,7 +68,7 @@ pub fn _merge_dims(
                 },
             );
         } else {
-            ret.push((sh, st, if st > 0 { sh } else { 0 }));
+            ret.push((sh, st, if st != 0 { sh } else { 0 }));
         }
         state = if let Some(ref m) = mask {
             if st == 0 && m[i].1 - m[i].0 == 1 {
@@ -337,7 +337,7 @@ impl View {
         } else {
             None
         };
-        View::new(&new_shape, Some(strides), Some(offset), mask)
+        View::new(&new_shape, Some(strides), Some(self.offset + offset), mask)
     }
 
     pub fn minify(&self) -> View {
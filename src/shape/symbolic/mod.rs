@@ -82,7 +82,7 @@ fn _mod(lhs: ArcNode, rhs: ArcNode) -> ArcNode {
         return lhs.to_arc();
     }
     if min < 0 {
-        return &lhs - (min.div_euclid(b) * b).rem_euclid(b);
+        return _mod(&lhs - min.div_euclid(b) * b, rhs);
     }
     create_node(ModNode::new(lhs.to_arc(), rhs))
 }
@@ -679,7 +679,7 @@ impl Node for SumNode {
                 } else {
                     rest.push(x.clone());
                     _gcd = gcd(_gcd, x.b().unwrap().num_val().unwrap());
-                    if x.is_mul() && divisor == 1 && &rhs % &x.b().unwrap() == num(0) {
+                    if x.is_mul() && divisor == 1 && b % x.b().unwrap().num_val().unwrap() == 0 {
                         divisor = x.b().unwrap().num_val().unwrap();
                     }
                 }
@@ -76,7 +76,9 @@ pub trait Function: DynClone + core::fmt::Debug {
         shape: Option<&[isize]>,
         const_: Option<Vec<u8>>,
     ) -> LazyBuffer;
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad;
+    // The backward in tensor ops on the parents, one grad per parent. Run with grad enabled the
+    // result has its own graph and can be differentiated again.
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>>;
     fn parents_mut(&mut self) -> &mut Ctx;
     fn parents_ref(&self) -> &Ctx;
     fn apply(
@@ -103,9 +105,10 @@ pub trait Function: DynClone + core::fmt::Debug {
             shape.as_deref(),
             const_,
         );
-        let require_grad = x.require_grad
-            || y.is_some_and(|t| t.require_grad)
-            || z.is_some_and(|t| t.require_grad);
+        let require_grad = Tensor::grad_enabled()
+            && (x.require_grad
+                || y.is_some_and(|t| t.require_grad)
+                || z.is_some_and(|t| t.require_grad));
         // if self.require_grad: self.parents = tensors
         if require_grad {
             ctx.parents_mut().push(x.clone());
@@ -119,6 +122,7 @@ pub trait Function: DynClone + core::fmt::Debug {
         Tensor {
             buffer: ret_buffer.into(),
             require_grad,
+            grad_pinned: false,
             _ctx: if require_grad {
                 Some(dyn_clone::clone_box(&*ctx))
             } else {
@@ -132,31 +136,6 @@ pub trait Function: DynClone + core::fmt::Debug {
 
 dyn_clone::clone_trait_object!(Function);
 
-#[derive(Debug, Clone)]
-pub enum Grad {
-    One(LazyBuffer),
-    Two(Option<LazyBuffer>, Option<LazyBuffer>),
-}
-
-// impl core::fmt::Debug for Grad {
-//     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-//         match &self {
-//             Grad::Contiguous(x)
-//             | Grad::Sin(x)
-//             | Grad::Log(x)
-//             | Grad::Exp(x)
-//             | Grad::Sqrt(x)
-//             | Grad::Max(x)
-//             | Grad::Sum(x)
-//             | Grad::Sigmoid(x)
-//             | Grad::Relu(x) => write!(f, "{x:?}"),
-//             Grad::Add(x, y) | Grad::Sub(x, y) | Grad::Mul(x, y) | Grad::Div(x, y) => {
-//                 write!(f, "x:{x:?}\ny:{y:?}")
-//             }
-//         }
-//     }
-// }
-
 #[derive(Clone, Debug)]
 pub struct Contiguous {
     pub(crate) ctx: Ctx,
@@ -182,8 +161,8 @@ impl Function for Contiguous {
         x.contiguous()
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(grad.clone())
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(grad.clone())]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -220,8 +199,8 @@ impl Function for ContiguousBackward {
         x.clone()
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(grad.contiguous())
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(grad.contiguous())]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -235,14 +214,12 @@ impl Function for ContiguousBackward {
 
 #[derive(Clone, Debug)]
 pub struct Sin {
-    pub(crate) x: Option<LazyBuffer>,
     pub(crate) ctx: Ctx,
 }
 
 impl Default for Sin {
     fn default() -> Self {
         Self {
-            x: None,
             ctx: Ctx::default(),
         }
     }
@@ -257,18 +234,13 @@ impl Function for Sin {
         shape: Option<&[isize]>,
         const_: Option<Vec<u8>>,
     ) -> LazyBuffer {
-        self.x = Some(x.clone());
         x.e(Unary::Sin, &[], None)
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        let x = self.x.as_ref().unwrap();
-        Grad::One(
-            x.const_like(core::f32::consts::PI / 2.0)
-                .e(Binary::Sub, &[x.clone()], None)
-                .e(Unary::Sin, &[], None)
-                .e(Binary::Mul, &[grad.clone()], None),
-        )
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(
+            (core::f32::consts::PI / 2.0 - &self.ctx[0]).sin() * grad,
+        )]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -282,14 +254,12 @@ impl Function for Sin {
 
 #[derive(Clone, Debug)]
 pub struct Log {
-    pub(crate) x: Option<LazyBuffer>,
     pub(crate) ctx: Ctx,
 }
 
 impl Default for Log {
     fn default() -> Self {
         Self {
-            x: None,
             ctx: Ctx::default(),
         }
     }
@@ -304,7 +274,6 @@ impl Function for Log {
         shape: Option<&[isize]>,
         const_: Option<Vec<u8>>,
     ) -> LazyBuffer {
-        self.x = Some(x.clone());
         x.e(Unary::Log2, &[], None).e(
             Binary::Mul,
             &[x.const_like(2.0f32.log(core::f32::consts::E))],
@@ -312,8 +281,8 @@ impl Function for Log {
         )
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(grad.e(Binary::Div, &[self.x.as_ref().unwrap().clone()], None))
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(grad / &self.ctx[0])]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -327,14 +296,12 @@ impl Function for Log {
 
 #[derive(Clone, Debug)]
 pub struct Exp {
-    pub(crate) ret: Option<LazyBuffer>,
     pub(crate) ctx: Ctx,
 }
 
 impl Default for Exp {
     fn default() -> Self {
         Self {
-            ret: None,
             ctx: Ctx::default(),
         }
     }
@@ -349,19 +316,16 @@ impl Function for Exp {
         shape: Option<&[isize]>,
         const_: Option<Vec<u8>>,
     ) -> LazyBuffer {
-        let ret = x
-            .e(
-                Binary::Mul,
-                &[x.const_like(1f32 / 2.0f32.ln())],
-                None,
-            )
-            .e(Unary::Exp2, &[], None);
-        self.ret = Some(ret.clone());
-        ret
+        x.e(
+            Binary::Mul,
+            &[x.const_like(1f32 / 2.0f32.ln())],
+            None,
+        )
+        .e(Unary::Exp2, &[], None)
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(self.ret.as_ref().unwrap().e(Binary::Mul, &[grad.clone()], None))
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(self.ctx[0].exp() * grad)]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -401,13 +365,8 @@ impl Function for Sqrt {
         self.ret.as_ref().unwrap().clone()
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        let ret = self.ret.as_ref().unwrap();
-        Grad::One(grad.e(
-            Binary::Div,
-            &[ret.e(Binary::Mul, &[ret.const_like(2.0f32)], None)],
-            None,
-        ))
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(grad / &(self.ctx[0].sqrt() * 2.0))]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -460,26 +419,14 @@ impl Function for Sum {
         x.r(Reduce::Sum, shape)
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        // let input_shape = self
-        //     .input_shape
-        //     .as_ref()
-        //     .expect("Sum bwd should have a input_shape");
-        // if input_shape.len() > grad.shape.len() {
-        //     let mut new_grad_shape = grad.shape;
-        //     for _ in 0..input_shape.len() - grad.shape.len() {
-        //         new_grad_shape.push(1);
-        //     }
-        //     grad = grad.reshape(&new_grad_shape);
-        // }
-        Grad::One(
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(
             grad.expand(
-                &self
-                    .input_shape
-                    .as_ref()
+                self.input_shape
+                    .clone()
                     .expect("Sum bwd should have a input_shape"),
             ),
-        )
+        )]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -539,35 +486,18 @@ impl Function for Max {
         ret
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        let x_ref = self.x.as_ref().unwrap();
-        let ret_ref = self.ret.as_ref().unwrap();
-        let max_is_1s = x_ref.const_like(1.0).e(
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        let x = self.x.as_ref().unwrap();
+        let ret = self.ret.as_ref().unwrap();
+        // How the grad is split between tied maxes doesn't depend on x, so it stays a constant
+        let max_is_1s = x.const_like(1.0).e(
             Binary::Sub,
-            &[x_ref.e(Binary::Cmplt, &[ret_ref.expand(&x_ref.shape)], None)],
+            &[x.e(Binary::Cmplt, &[ret.expand(&x.shape)], None)],
             None,
         );
-        // let mut div = max_is_1s.clone();
-        // for (i, (msh, gsh)) in max_is_1s
-        //     .shape()
-        //     .dims
-        //     .iter()
-        //     .zip(grad.shape().dims.iter())
-        //     .enumerate()
-        // {
-        //     if msh != gsh {
-        //         div = div.sum(Some(i as isize), true);
-        //     }
-        // }
-        // let div = div.expand(x_ref.shape());
-        let div = max_is_1s
-            .r(Reduce::Sum, &grad.shape)
-            .expand(&self.x.as_ref().unwrap().shape);
-        Grad::One(max_is_1s.e(Binary::Div, &[div], None).e(
-            Binary::Mul,
-            &[grad.expand(&self.x.as_ref().unwrap().shape)],
-            None,
-        ))
+        let div = max_is_1s.r(Reduce::Sum, &ret.shape).expand(&x.shape);
+        let share = Tensor::from_buf(max_is_1s.e(Binary::Div, &[div], None));
+        vec![Some(share * grad.expand(x.shape.clone()))]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -608,7 +538,7 @@ impl Function for Less {
         )
     }
 
-    fn backward(&mut self, _grad: &LazyBuffer) -> Grad {
+    fn backward(&self, _grad: &Tensor) -> Vec<Option<Tensor>> {
         unreachable!("Less op can not do backward pass")
     }
 
@@ -624,8 +554,6 @@ impl Function for Less {
 #[derive(Clone, Debug)]
 pub struct Add {
     pub(crate) need_input_grad: [bool; 2],
-    pub(crate) x: Option<LazyBuffer>,
-    pub(crate) y: Option<LazyBuffer>,
     pub(crate) ctx: Ctx,
 }
 
@@ -633,8 +561,6 @@ impl Default for Add {
     fn default() -> Self {
         Self {
             need_input_grad: [false, false],
-            x: None,
-            y: None,
             ctx: Ctx::default(),
         }
     }
@@ -656,18 +582,11 @@ impl Function for Add {
         )
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        let x = if self.need_input_grad[0] {
-            Some(grad.clone())
-        } else {
-            None
-        };
-        let y = if self.need_input_grad[1] {
-            Some(grad.clone())
-        } else {
-            None
-        };
-        Grad::Two(x, y)
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![
+            self.need_input_grad[0].then(|| grad.clone()),
+            self.need_input_grad[1].then(|| grad.clone()),
+        ]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -682,8 +601,6 @@ impl Function for Add {
 #[derive(Clone, Debug)]
 pub struct Sub {
     pub(crate) need_input_grad: [bool; 2],
-    pub(crate) x: Option<LazyBuffer>,
-    pub(crate) y: Option<LazyBuffer>,
     pub(crate) ctx: Ctx,
 }
 
@@ -691,8 +608,6 @@ impl Default for Sub {
     fn default() -> Self {
         Self {
             need_input_grad: [false, false],
-            x: None,
-            y: None,
             ctx: Ctx::default(),
         }
     }
@@ -714,18 +629,11 @@ impl Function for Sub {
         )
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        let x = if self.need_input_grad[0] {
-            Some(grad.clone())
-        } else {
-            None
-        };
-        let y = if self.need_input_grad[1] {
-            Some(grad.const_like(0.0).e(Binary::Sub, &[grad.clone()], None))
-        } else {
-            None
-        };
-        Grad::Two(x, y)
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![
+            self.need_input_grad[0].then(|| grad.clone()),
+            self.need_input_grad[1].then(|| -grad),
+        ]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -740,8 +648,6 @@ impl Function for Sub {
 #[derive(Clone, Debug)]
 pub struct Mul {
     pub(crate) need_input_grad: [bool; 2],
-    pub(crate) x: Option<LazyBuffer>,
-    pub(crate) y: Option<LazyBuffer>,
     pub(crate) ctx: Ctx,
 }
 
@@ -749,8 +655,6 @@ impl Default for Mul {
     fn default() -> Self {
         Self {
             need_input_grad: [false, false],
-            x: None,
-            y: None,
             ctx: Ctx::default(),
         }
     }
@@ -765,8 +669,6 @@ impl Function for Mul {
         shape: Option<&[isize]>,
         const_: Option<Vec<u8>>,
     ) -> LazyBuffer {
-        self.x = Some(x.clone());
-        self.y = Some(y.expect("Mul fwd op expects rhs").clone());
         x.e(
             Binary::Mul,
             &[y.expect("Nul fwd op expects rhs").clone()],
@@ -774,28 +676,11 @@ impl Function for Mul {
         )
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        let y = if self.need_input_grad[0] {
-            Some(
-                self.y
-                    .as_ref()
-                    .unwrap()
-                    .e(Binary::Mul, &[grad.clone()], None),
-            )
-        } else {
-            None
-        };
-        let x = if self.need_input_grad[1] {
-            Some(
-                self.x
-                    .as_ref()
-                    .unwrap()
-                    .e(Binary::Mul, &[grad.clone()], None),
-            )
-        } else {
-            None
-        };
-        Grad::Two(y, x)
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![
+            self.need_input_grad[0].then(|| &self.ctx[1] * grad),
+            self.need_input_grad[1].then(|| &self.ctx[0] * grad),
+        ]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -810,8 +695,6 @@ impl Function for Mul {
 #[derive(Clone, Debug)]
 pub struct Div {
     pub(crate) need_input_grad: [bool; 2],
-    pub(crate) x: Option<LazyBuffer>,
-    pub(crate) y: Option<LazyBuffer>,
     pub(crate) ctx: Ctx,
 }
 
@@ -819,8 +702,6 @@ impl Default for Div {
     fn default() -> Self {
         Self {
             need_input_grad: [false, false],
-            x: None,
-            y: None,
             ctx: Ctx::default(),
         }
     }
@@ -835,8 +716,6 @@ impl Function for Div {
         shape: Option<&[isize]>,
         const_: Option<Vec<u8>>,
     ) -> LazyBuffer {
-        self.x = Some(x.clone());
-        self.y = Some(y.expect("Div fwd op expects rhs").clone());
         x.e(
             Binary::Div,
             &[y.expect("Div fwd op expects rhs").clone()],
@@ -844,27 +723,12 @@ impl Function for Div {
         )
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        let x = if self.need_input_grad[0] {
-            Some(grad.e(Binary::Div, &[self.y.as_ref().unwrap().clone()], None))
-        } else {
-            None
-        };
-        let y = if self.need_input_grad[1] {
-            let y_ref = self.y.as_ref().unwrap();
-            Some(
-                grad.e(Unary::Neg, &[], None)
-                    .e(Binary::Mul, &[self.x.as_ref().unwrap().clone()], None)
-                    .e(
-                        Binary::Div,
-                        &[y_ref.e(Binary::Mul, &[y_ref.clone()], None)],
-                        None,
-                    ),
-            )
-        } else {
-            None
-        };
-        Grad::Two(x, y)
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        let (x, y) = (&self.ctx[0], &self.ctx[1]);
+        vec![
+            self.need_input_grad[0].then(|| grad / y),
+            self.need_input_grad[1].then(|| -grad * x / (y * y)),
+        ]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -919,21 +783,9 @@ impl Function for Sigmoid {
         self.ret.as_ref().unwrap().clone()
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        let ret_ref = self.ret.as_ref().unwrap();
-        Grad::One(
-            ret_ref
-                .e(
-                    Binary::Mul,
-                    &[ret_ref.const_like(1.0).e(
-                        Binary::Sub,
-                        &[self.ret.as_ref().unwrap().clone()],
-                        None,
-                    )],
-                    None,
-                )
-                .e(Binary::Mul, &[grad.clone()], None),
-        )
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        let ret = self.ctx[0].sigmoid();
+        vec![Some(&ret * &(1.0 - &ret) * grad)]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -973,15 +825,10 @@ impl Function for Relu {
         self.ret.as_ref().unwrap().clone()
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(
-            self.ret
-                .as_ref()
-                .unwrap()
-                .const_like(0)
-                .e(Binary::Cmplt, &[self.ret.as_ref().unwrap().clone()], None)
-                .e(Binary::Mul, &[grad.clone()], None),
-        )
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        let ret = self.ret.as_ref().unwrap();
+        let mask = ret.const_like(0).e(Binary::Cmplt, std::slice::from_ref(ret), None);
+        vec![Some(Tensor::from_buf(mask) * grad)]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -995,7 +842,6 @@ impl Function for Relu {
 // --------------------------------- Tenary
 #[derive(Clone, Debug)]
 pub struct Where {
-    pub(crate) x: Option<LazyBuffer>,
     pub(crate) ctx: Ctx,
     pub(crate) need_input_grad: [bool; 3],
 }
@@ -1004,7 +850,6 @@ impl Default for Where {
     fn default() -> Self {
         Self {
             need_input_grad: [false, false, false],
-            x: None,
             ctx: Ctx::default(),
         }
     }
@@ -1019,7 +864,6 @@ impl Function for Where {
         shape: Option<&[isize]>,
         const_: Option<Vec<u8>>,
     ) -> LazyBuffer {
-        self.x = Some(x.clone());
         x.e(
             Ternary::Where,
             &[
@@ -1030,23 +874,14 @@ impl Function for Where {
         )
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        // return None, \
-        //        self.x.e(TernaryOps.WHERE, grad_output, grad_output.const(0)) if self.needs_input_grad[1] else None, \
-        //        self.x.e(TernaryOps.WHERE, grad_output.const(0), grad_output) if self.needs_input_grad[2] else None
-        let x = self.x.as_ref().expect("where bwd should have x now");
-        Grad::Two(
-            if self.need_input_grad[1] {
-                Some(x.e(Ternary::Where, &[grad.clone(), grad.const_like(0)], None))
-            } else {
-                None
-            },
-            if self.need_input_grad[2] {
-                Some(x.e(Ternary::Where, &[grad.const_like(0), grad.clone()], None))
-            } else {
-                None
-            },
-        )
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        let cond = self.ctx[0].detach();
+        let zero = Tensor::_const(0.0);
+        vec![
+            None,
+            self.need_input_grad[1].then(|| cond._where_(grad, &zero)),
+            self.need_input_grad[2].then(|| cond._where_(&zero, grad)),
+        ]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -1086,15 +921,22 @@ impl Function for Expand {
         x.expand(shape.expect("Expand mlops expect a shape"))
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(
-            grad.r(
-                Reduce::Sum,
-                self.input_shape
-                    .as_ref()
-                    .expect("Expand bwd expects a input shape"),
-            ),
-        )
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        let input_shape = self
+            .input_shape
+            .as_ref()
+            .expect("Expand bwd expects a input shape");
+        let axis = input_shape
+            .iter()
+            .zip(grad.shape().dims)
+            .enumerate()
+            .filter(|(_, (s, g))| **s != *g)
+            .map(|(i, _)| i as isize)
+            .collect::<Vec<isize>>();
+        if axis.is_empty() {
+            return vec![Some(grad.clone())];
+        }
+        vec![Some(grad.sum(axis, true))]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -1135,14 +977,14 @@ impl Function for Reshape {
         x.reshape(shape.expect("Reshape mlops expect a shape"))
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(
             grad.reshape(
                 self.input_shape
-                    .as_ref()
+                    .clone()
                     .expect("Reshape backward should already have a shape"),
             ),
-        )
+        )]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -1185,15 +1027,14 @@ impl Function for Permute {
         x.permute(self.permute_order.as_ref().unwrap())
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(
-            grad.permute(&argsort::<&[isize]>(
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(
+            grad.permute(argsort(
                 self.permute_order
-                    .as_ref()
-                    .expect("Permute bwd order should not be empty")
-                    .as_ref(),
+                    .clone()
+                    .expect("Permute bwd order should not be empty"),
             )),
-        )
+        )]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -1250,14 +1091,14 @@ impl Function for Pad {
         x.pad(&arg)
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(
-            grad.shrink(
-                self.narg
-                    .as_ref()
-                    .expect("Reshape backward should already have a shape"),
-            ),
-        )
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(Shrink::default().apply(
+            grad,
+            None,
+            None,
+            self.narg.clone(),
+            None,
+        ))]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
@@ -1306,14 +1147,170 @@ impl Function for Shrink {
         x.shrink(&padding)
     }
 
-    fn backward(&mut self, grad: &LazyBuffer) -> Grad {
-        Grad::One(
-            grad.pad(
-                self.narg
-                    .as_ref()
-                    .expect("Reshape backward should already have a shape"),
-            ),
-        )
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(Pad::default().apply(
+            grad,
+            None,
+            None,
+            self.narg.clone(),
+            None,
+        ))]
+    }
+
+    fn parents_mut(&mut self) -> &mut Ctx {
+        &mut self.ctx
+    }
+
+    fn parents_ref(&self) -> &Ctx {
+        &self.ctx
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Flip {
+    pub(crate) arg: Option<Vec<isize>>,
+    pub(crate) ctx: Ctx,
+}
+
+impl Default for Flip {
+    fn default() -> Self {
+        Self {
+            arg: None,
+            ctx: Ctx::default(),
+        }
+    }
+}
+
+// `shape` is the stride per axis, -1 on the flipped axes and 1 elsewhere
+impl Function for Flip {
+    fn forward(
+        &mut self,
+        x: &LazyBuffer,
+        y: Option<&LazyBuffer>,
+        z: Option<&LazyBuffer>,
+        shape: Option<&[isize]>,
+        const_: Option<Vec<u8>>,
+    ) -> LazyBuffer {
+        let arg = shape.expect("Flip mlops expect a stride per axis").to_vec();
+        self.arg = Some(arg);
+        x.stride(self.arg.as_ref().unwrap())
+    }
+
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        vec![Some(Flip::default().apply(
+            grad,
+            None,
+            None,
+            self.arg.clone(),
+            None,
+        ))]
+    }
+
+    fn parents_mut(&mut self) -> &mut Ctx {
+        &mut self.ctx
+    }
+
+    fn parents_ref(&self) -> &Ctx {
+        &self.ctx
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Gather {
+    pub(crate) dim: usize,
+    pub(crate) ctx: Ctx,
+}
+
+impl Default for Gather {
+    fn default() -> Self {
+        Self {
+            dim: 0,
+            ctx: Ctx::default(),
+        }
+    }
+}
+
+// `y` is the index
+impl Function for Gather {
+    fn forward(
+        &mut self,
+        x: &LazyBuffer,
+        y: Option<&LazyBuffer>,
+        z: Option<&LazyBuffer>,
+        shape: Option<&[isize]>,
+        const_: Option<Vec<u8>>,
+    ) -> LazyBuffer {
+        x.gather(y.expect("Gather expect an index"), self.dim)
+    }
+
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        let index = self.ctx[1].detach();
+        let zero = Tensor::zeros(self.ctx[0].shape());
+        vec![Some(zero.scatter_add(self.dim as isize, &index, grad)), None]
+    }
+
+    fn parents_mut(&mut self) -> &mut Ctx {
+        &mut self.ctx
+    }
+
+    fn parents_ref(&self) -> &Ctx {
+        &self.ctx
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Scatter {
+    pub(crate) dim: usize,
+    pub(crate) add: bool,
+    pub(crate) ctx: Ctx,
+}
+
+impl Default for Scatter {
+    fn default() -> Self {
+        Self {
+            dim: 0,
+            add: false,
+            ctx: Ctx::default(),
+        }
+    }
+}
+
+// `y` is the index and `z` the source, already in the dtype of `x`. There is no indexed store, the
+// forward sums the source over a one-hot mask of the index while the backward gathers.
+impl Function for Scatter {
+    fn forward(
+        &mut self,
+        x: &LazyBuffer,
+        y: Option<&LazyBuffer>,
+        z: Option<&LazyBuffer>,
+        shape: Option<&[isize]>,
+        const_: Option<Vec<u8>>,
+    ) -> LazyBuffer {
+        let x = Tensor::from_buf(x.clone());
+        let index = Tensor::from_buf(y.expect("Scatter expect an index").clone());
+        let src = Tensor::from_buf(z.expect("Scatter expect a source").clone());
+        let (src, mask) = x._pre_scatter(self.dim, &index, &src);
+        let sum = (src * &mask).sum([-1], false);
+        let ret = if self.add {
+            &x + &sum
+        } else {
+            let hit = mask.sum([-1], false);
+            hit._gt(&hit.const_like(0.0))._where_(&sum, &x)
+        };
+        ret.buffer
+    }
+
+    fn backward(&self, grad: &Tensor) -> Vec<Option<Tensor>> {
+        let (index, src) = (self.ctx[1].detach(), &self.ctx[2]);
+        let dim = self.dim as isize;
+        let pad = v![(0, (s - i) as usize), for (&s, i) in src.shape().dims.iter().zip(index.shape().dims)];
+        let grad_src = grad.gather(dim, &index).pad(pad, 0);
+        let grad_x = if self.add {
+            grad.clone()
+        } else {
+            grad.scatter(dim, &index, &Tensor::zeros(index.shape()))
+        };
+        vec![Some(grad_x), None, Some(grad_src)]
     }
 
     fn parents_mut(&mut self) -> &mut Ctx {
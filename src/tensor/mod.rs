@@ -11,12 +11,14 @@ use crate::arg::Arg;
 use crate::dtype::_bool;
 use crate::dtype::type_to_dtype;
 use crate::dtype::NumType;
-use crate::lazy::run_schedule;
+use crate::lazy::try_run_schedule;
 use crate::ops::LazyOp;
 use crate::ops::Load;
 use crate::ops::OpType;
 use crate::prelude::*;
 use crate::prelude::*;
+use crate::random;
+use crate::viz;
 use crate::shape::ShapeTracker;
 use crate::tensor::mlops::*;
 use crate::tensor::shape::Shape;
@@ -29,6 +31,23 @@ use std::sync::Mutex;
 
 pub type TensorDefaultType = f32;
 
+thread_local! {
+    // Dropout and batchnorm only use batch statistics and randomness while training
+    static TRAINING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    // Off inside no_grad, ops then don't record their parents
+    static GRAD_ENABLED: std::cell::Cell<bool> = const { std::cell::Cell::new(true) };
+}
+
+// Turns grad tracking off on this thread until it is dropped, see Tensor::no_grad
+#[must_use = "grad tracking comes back as soon as the guard is dropped"]
+pub struct NoGradGuard(bool);
+
+impl Drop for NoGradGuard {
+    fn drop(&mut self) {
+        Tensor::set_grad_enabled(self.0);
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
 pub struct TensorId(pub(crate) usize);
 
@@ -41,6 +60,8 @@ pub(crate) fn tensor_id() -> TensorId {
 pub struct Tensor {
     pub buffer: LazyBuffer,
     pub require_grad: bool,
+    // Set by requires_grad_, optimizers keep the choice instead of turning grads on
+    pub(crate) grad_pinned: bool,
     pub grad: Arc<Mutex<Option<Tensor>>>,
     pub _ctx: Option<Box<dyn Function>>,
     pub id: TensorId,
@@ -55,6 +76,16 @@ impl Tensor {
         self.buffer.dtype.clone()
     }
 
+    pub fn to(&self, device: &str) -> Self {
+        let mut ret = Self::from_buf(self.buffer.copy_to_device(device));
+        ret.require_grad = self.require_grad;
+        ret.grad_pinned = self.grad_pinned;
+        if let Some(grad) = self.grad.lock().unwrap().as_ref() {
+            *ret.grad.lock().unwrap() = Some(grad.to(device));
+        }
+        ret
+    }
+
     pub fn shape(&self) -> Shape {
         self.buffer.shape.clone().into()
     }
@@ -66,6 +97,7 @@ impl Tensor {
     pub fn from_buf(buf: LazyBuffer) -> Self {
         Self {
             require_grad: false,
+            grad_pinned: false,
             grad: Arc::default(),
             _ctx: None,
             id: tensor_id(),
@@ -80,12 +112,13 @@ impl Tensor {
             .map(|e| TensorDefaultType::from_f64(e.to_f64().unwrap()).unwrap())
             .collect::<Vec<TensorDefaultType>>();
         let buffer = if data.len() == 1 {
-            LazyBuffer::_const(data[0], dtype::type_to_dtype::<TensorDefaultType>())
+            LazyBuffer::_const(data[0], dtype::type_to_dtype::<TensorDefaultType>(), &DEVICE.name())
         } else {
             LazyBuffer::from_cpu(data)
         };
         Self {
             require_grad: false,
+            grad_pinned: false,
             _ctx: None,
             id: tensor_id(),
             grad: Arc::new(Mutex::new(None)),
@@ -101,6 +134,7 @@ impl Tensor {
         buffer.dtype = dtype;
         Self {
             require_grad: false,
+            grad_pinned: false,
             _ctx: None,
             id: tensor_id(),
             grad: Arc::new(Mutex::new(None)),
@@ -118,10 +152,7 @@ impl Tensor {
 
     pub fn to_vec_t<T: NumType>(&self) -> Vec<T> {
         let buffer = self.contiguous().realize();
-        let mut bytes = (*buffer.buffer.device_buffer)
-            .as_ref()
-            .expect("buffer not realized")
-            .to_cpu();
+        let mut bytes = buffer.buffer.realized().expect("buffer not realized").to_cpu();
         let mut ret = vec![];
         for b in bytes
             .windows(std::mem::size_of::<T>())
@@ -143,10 +174,7 @@ impl Tensor {
             std::any::type_name::<T>().split("::").last().unwrap()
         );
         let buffer = self.contiguous().realize();
-        let mut bytes = (*buffer.buffer.device_buffer)
-            .as_ref()
-            .expect("buffer not realized")
-            .to_cpu();
+        let mut bytes = buffer.buffer.realized().expect("buffer not realized").to_cpu();
         let mut ret = vec![];
         for b in bytes
             .windows(std::mem::size_of::<T>())
@@ -168,6 +196,7 @@ impl Tensor {
             op,
             &[size as isize],
             dtype,
+            &DEVICE.name(),
             {
                 if args.is_some() {
                     args
@@ -191,7 +220,7 @@ impl Tensor {
 
     pub fn _const<N: NumType>(value: N) -> Self {
         let value = value.to_f32().unwrap();
-        Self::from_buf(LazyBuffer::_const(value, float32))
+        Self::from_buf(LazyBuffer::_const(value, float32, &DEVICE.name()))
     }
 
     pub fn const_like<T: NumType>(&self, const_value: T) -> Self {
@@ -214,23 +243,42 @@ impl Tensor {
             .expand(shape.dims)
     }
 
+    // Restarts this thread's random stream, after the same seed the same calls give the same
+    // numbers on every backend
+    pub fn manual_seed(seed: u64) {
+        random::manual_seed(seed)
+    }
+
     pub fn rand<S: Into<Shape>>(shape: S) -> Self {
-        let shape = shape.into();
+        Self::_rand(shape.into(), None)
+    }
+
+    // Uniform integers in [low, high), stored in the default float type
+    pub fn randint<S: Into<Shape>>(shape: S, low: isize, high: isize) -> Self {
+        assert!(
+            low < high && high - low <= u32::MAX as isize,
+            "randint needs low < high with at most 2^32 - 1 values, got [{low}, {high})"
+        );
+        Self::_rand(shape.into(), Some((low, high)))
+    }
+
+    // The draw takes its words of the stream when it is created, so realizing it later or on
+    // another device doesn't change its values. The seed and offset go to the kernel in a buffer,
+    // so every draw of the same shape runs the same kernel.
+    fn _rand(shape: Shape, range: Option<(isize, isize)>) -> Self {
+        let dtype = type_to_dtype::<TensorDefaultType>();
+        let words = if range.is_some() { 1 } else { dtype.size.div_ceil(4) };
+        let (seed, offset) = random::reserve(shape.numel() * words);
+        let key = LazyBuffer::from_cpu(vec![seed as u32, (seed >> 32) as u32, offset as u32, (offset >> 32) as u32]);
+        let args = range.map_or(vec![], |(low, high)| vec![Arg::Idx(low), Arg::Idx(high)]);
         Self::from_buf(LazyBuffer::new(
-            crate::shape::ShapeTracker::from_shape(&shape.dims),
+            &DEVICE.name(),
+            ShapeTracker::from_shape(&shape.dims),
             OpType::Load(Load::Rand),
-            LazyOp::new(OpType::Load(Load::Rand), vec![], None),
-            type_to_dtype::<TensorDefaultType>(),
+            LazyOp::new(OpType::Load(Load::Rand), vec![key.into()], Some(args)),
+            dtype,
             None,
         ))
-        // Self::_load(
-        //     OpType::Load(Load::Rand),
-        //     shape.numel(),
-        //     type_to_dtype::<TensorDefaultType>(),
-        //     None,
-        //     None,
-        // )
-        // .reshape(shape)
     }
 
     pub fn randn<S: Into<Shape>>(shape: S) -> Self {
@@ -242,6 +290,53 @@ impl Tensor {
         ret
     }
 
+    // 1 with the probability in self, 0 otherwise
+    pub fn bernoulli(&self) -> Self {
+        Self::rand(self.shape())._lt(self)
+    }
+
+    // Draws num_samples int32 class indices from each row of unnormalized weights (1 or 2 dims). With
+    // replacement it inverts the cdf, without it takes the top k of the log weights plus Gumbel
+    // noise.
+    pub fn multinomial(&self, num_samples: usize, replacement: bool) -> Self {
+        assert!(
+            (1..=2).contains(&self.ndim()) && num_samples > 0,
+            "multinomial needs 1 or 2 dims and num_samples > 0, got {} and {num_samples}",
+            self.shape()
+        );
+        let w = if self.ndim() == 1 { self.unsqueeze(0) } else { self.clone() };
+        let [rows, classes] = w.shape().dims[..] else { unreachable!() };
+        let ret = if replacement {
+            let cdf = w._cumsum(1);
+            let cdf = &cdf / &cdf.shrink([(0, rows as usize), (classes as usize - 1, classes as usize)]);
+            Self::rand([num_samples as isize, rows, 1])
+                .expand([num_samples as isize, rows, classes])
+                ._ge(&cdf)
+                .sum([2], false)
+                .permute([1, 0])
+                .cast(int32)
+        } else {
+            assert!(num_samples as isize <= classes, "can't draw {num_samples} of {classes} classes without replacement");
+            let gumbel = -(-Self::rand(w.shape()).log()).log();
+            (w.log() + gumbel).topk(num_samples, 1, true).1
+        };
+        if self.ndim() == 1 {
+            return ret.reshape([num_samples as isize]);
+        }
+        ret
+    }
+
+    // A random permutation of 0..n, shuffled on the host with words from the same stream
+    pub fn randperm(n: usize) -> Self {
+        let (seed, offset) = random::reserve(n);
+        let w = random::threefry_stream(seed, offset, n);
+        let mut perm = (0..n).collect::<Vec<usize>>();
+        for i in (1..n).rev() {
+            perm.swap(i, random::below(w[i], i as u64 + 1) as usize);
+        }
+        Self::from(v![p as f32, for p in perm])
+    }
+
     pub fn normal<S: Into<Shape>>(shape: S, mean: f32, std: f32) -> Self {
         Self::randn(shape) * std + mean
     }
@@ -281,19 +376,71 @@ impl Tensor {
         todo!()
     }
 
+    pub fn training() -> bool {
+        TRAINING.with(|t| t.get())
+    }
+
+    // Switches train/eval mode for this thread and returns the previous mode
+    pub fn set_training(training: bool) -> bool {
+        TRAINING.with(|t| t.replace(training))
+    }
+
+    pub fn grad_enabled() -> bool {
+        GRAD_ENABLED.with(|g| g.get())
+    }
+
+    // Turns grad tracking on or off for this thread and returns the previous setting
+    pub fn set_grad_enabled(enabled: bool) -> bool {
+        GRAD_ENABLED.with(|g| g.replace(enabled))
+    }
+
+    // Ops run while the guard lives don't build a graph, results don't require grad and their
+    // inputs aren't kept alive for backward
+    pub fn no_grad() -> NoGradGuard {
+        NoGradGuard(Self::set_grad_enabled(false))
+    }
+
+    // Marks the tensor as trainable or frozen, an optimizer given a frozen tensor skips it
+    pub fn requires_grad_(&mut self, requires_grad: bool) -> &mut Self {
+        self.require_grad = requires_grad;
+        self.grad_pinned = true;
+        self
+    }
+
     pub fn dropout(&self, p: Option<f32>) -> Self {
         // mask = (Tensor.rand(*self.shape, requires_grad=False, device=self.device) >= p).cast(dtypes.bool)
         // return self * mask * (1/(1.0 - p))
         let p = if p.is_some() { p.unwrap() } else { 0.2 };
+        if !Self::training() || p == 0.0 {
+            return self.clone();
+        }
         let mask = Self::rand(self.shape())._ge(&Tensor::from([p]));
         self * &mask * (1.0 / (1.0 - p))
     }
 
     // ------------ Movement
     pub fn reshape<S: Into<Shape>>(&self, shape: S) -> Self {
+        self.try_reshape(shape).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    // At most one dim can be -1, it takes whatever is left of the numel
+    pub fn try_reshape<S: Into<Shape>>(&self, shape: S) -> Result<Self, StormError> {
         let shape = shape.into().dims;
         let numel = self.shape().numel() as isize;
-        Reshape::default().apply(self, None, None, Some(v![if s == -1 { -numel / shape.iter().product::<isize>()} else { s }, for (i, &s) in shape.iter().enumerate()]), None)
+        let infer = shape.iter().filter(|&&s| s == -1).count();
+        let known = shape.iter().filter(|&&s| s != -1).product::<isize>();
+        if infer > 1
+            || shape.iter().any(|&s| s < -1)
+            || (infer == 0 && known != numel)
+            || (infer == 1 && (known == 0 || numel % known != 0))
+        {
+            return Err(StormError::ShapeMismatch {
+                op: "reshape",
+                lhs: self.shape().dims,
+                rhs: shape,
+            });
+        }
+        Ok(Reshape::default().apply(self, None, None, Some(v![if s == -1 { -numel / shape.iter().product::<isize>()} else { s }, for (i, &s) in shape.iter().enumerate()]), None))
     }
 
     pub fn expand<S: Into<Shape>>(&self, shape: S) -> Self {
@@ -304,6 +451,13 @@ impl Tensor {
         Permute::default().apply(self, None, None, Some(shape.into().dims), None)
     }
 
+    pub fn flip<S: Into<Vec<isize>>>(&self, axis: S) -> Self {
+        let ndim = self.shape().len() as isize;
+        let axis = v![if a < 0 { a + ndim } else { a }, for a in axis.into()];
+        let arg = v![if axis.contains(&i) { -1 } else { 1 }, for i in 0..ndim];
+        Flip::default().apply(self, None, None, Some(arg), None)
+    }
+
     pub fn shrink<A: Into<Vec<(usize, usize)>>>(&self, arg: A) -> Self {
         let arg = arg.into();
         if !arg
@@ -321,20 +475,19 @@ impl Tensor {
         Shrink::default().apply(self, None, None, Some(flatten_p), None)
     }
 
-    pub fn pad<A: Into<Vec<(usize, usize)>>>(&self, arg: A, const_value: impl NumType) -> Self {
+    pub fn pad<A: Into<Vec<(usize, usize)>>, N: NumType>(&self, arg: A, const_value: N) -> Self {
+        let arg = arg.into();
         let flatten_p: Vec<isize> = arg
-            .into()
             .iter()
             .map(|(p1, p2)| vec![*p1 as isize, *p2 as isize])
             .flatten()
             .collect();
-        Pad::default().apply(
-            self,
-            None,
-            None,
-            Some(flatten_p.into()),
-            Some(const_value._to_le_bytes()),
-        )
+        let ret = Pad::default().apply(self, None, None, Some(flatten_p.into()), None);
+        if const_value.is_zero() {
+            return ret;
+        }
+        // Pad only fills with zeros, the rest of the value comes from a padded mask
+        ret + Self::ones(self.shape()).pad(arg, 0)._where(N::zero(), const_value)
     }
 
     pub fn pad2d<P: Into<Vec<usize>>>(&self, padding: P, const_value: impl NumType) -> Self {
@@ -551,18 +704,108 @@ impl Tensor {
         self._argmax(None, false)
     }
 
+    // Returns (values, int32 indices) along dim. The values come out of a bitonic network built from
+    // maximum/minimum, so they have a backward. The indices go through the same network, swapped
+    // by the same comparisons, and break ties between equal values so ties keep their order.
+    #[rustfmt::skip]
+    pub fn sort(&self, dim: isize, descending: bool) -> (Self, Self) {
+        let ndim = self.ndim();
+        let dim = if dim < 0 { dim + ndim as isize } else { dim } as usize;
+        let shape = self.shape().dims;
+        let n = shape[dim] as usize;
+        let stages = n.next_power_of_two().trailing_zeros() as usize;
+        // Splits axis d, which has size 2, in its two halves
+        let halves = |x: &Self, d: usize| {
+            let mut top = v![(0, s as usize), for &s in x.shape().dims.iter()];
+            let mut bottom = top.clone();
+            (top[d], bottom[d]) = ((0, 1), (1, 2));
+            (x.shrink(top), x.shrink(bottom))
+        };
+        // The sort axis is padded to a power of two and split into one axis of 2 per stage
+        let padded = v![if i == dim { 1 << stages } else { s }, for (i, &s) in shape.iter().enumerate()];
+        let split = |x: Self| x.reshape([&shape[..dim], &vec![2; stages][..], &shape[dim + 1..]].concat());
+        // Padding holds the dtype's last value in sort order, ties with it are broken by the index
+        let (min, max) = self.dtype().min_max();
+        let fill = Self::from_buf(LazyBuffer::_const(if descending { min } else { max }, self.dtype(), &self.device()));
+        let pad = v![if i == dim { (0, (1 << stages) - n) } else { (0, 0) }, for i in 0..ndim];
+        let mut x = split(Self::ones(self.shape()).pad(pad.clone(), 0)._where_(&self.pad(pad, 0), &fill));
+        let mut idx = split(
+            Self::arange((1 << stages) as f32)
+                .reshape(v![if i == dim { 1 << stages } else { 1 }, for i in 0..ndim])
+                .expand(padded.clone()),
+        );
+        for stage in 1..=stages {
+            // Every other block is flipped so all the comparisons point the same way
+            let crossover = (dim + stages).saturating_sub(stage + 1);
+            let flip_axes = v![a as isize, for a in crossover + 1..=crossover + stage];
+            let flip = |x: &Self| {
+                let (blue, green) = halves(x, crossover);
+                blue.cat(&[green.flip(flip_axes.clone())], Some(crossover as isize))
+            };
+            if stage != stages {
+                (x, idx) = (flip(&x).contiguous(), flip(&idx).contiguous());
+            }
+            for substage in (0..stage).rev() {
+                let partner = dim + stages - substage - 1;
+                let (top, bottom) = halves(&x, partner);
+                let (top_idx, bottom_idx) = halves(&idx, partner);
+                // The bottom goes first when it sorts before the top, or ties with it and came first
+                let (t, b) = (top.detach(), bottom.detach());
+                let swap = if descending { t._lt(&b) } else { b._lt(&t) } + t._eq(&b) * bottom_idx._lt(&top_idx);
+                idx = swap._where_(&bottom_idx, &top_idx).cat(&[swap._where_(&top_idx, &bottom_idx)], Some(partner as isize)).contiguous();
+                let (larger, smaller) = (top.maximum(&bottom), top.minimum(&bottom));
+                x = if descending { larger.cat(&[smaller], Some(partner as isize)) } else { smaller.cat(&[larger], Some(partner as isize)) }.contiguous();
+            }
+            if stage != stages {
+                (x, idx) = (flip(&x), flip(&idx));
+            }
+        }
+        let unsplit = |x: Self| {
+            x.reshape(padded.clone())
+                .shrink(v![if i == dim { (0, n) } else { (0, s as usize) }, for (i, &s) in shape.iter().enumerate()])
+        };
+        (unsplit(x), unsplit(idx).cast(int32))
+    }
+
+    pub fn argsort(&self, dim: isize, descending: bool) -> Self {
+        self.sort(dim, descending).1
+    }
+
+    // The k largest (or smallest) values along dim and their indices, in sorted order
+    pub fn topk(&self, k: usize, dim: isize, largest: bool) -> (Self, Self) {
+        let dim = if dim < 0 { dim + self.ndim() as isize } else { dim } as usize;
+        assert!(k as isize <= self.shape().dims[dim], "topk k={k} is larger than {}", self.shape());
+        let (values, idx) = self.sort(dim as isize, largest);
+        let arg = v![if i == dim { (0, k) } else { (0, s as usize) }, for (i, &s) in self.shape().dims.iter().enumerate()];
+        (values.shrink(arg.clone()), idx.shrink(arg))
+    }
+
+    // The k-th smallest value along dim, counting from 1 like torch
+    pub fn kthvalue(&self, k: usize, dim: isize, keepdim: bool) -> (Self, Self) {
+        let dim = if dim < 0 { dim + self.ndim() as isize } else { dim } as usize;
+        assert!(k >= 1 && k as isize <= self.shape().dims[dim], "kthvalue k={k} is out of range for {}", self.shape());
+        let (values, idx) = self.sort(dim as isize, false);
+        let arg = v![if i == dim { (k - 1, k) } else { (0, s as usize) }, for (i, &s) in self.shape().dims.iter().enumerate()];
+        let (values, idx) = (values.shrink(arg.clone()), idx.shrink(arg));
+        if keepdim {
+            return (values, idx);
+        }
+        (values.squeeze(dim as isize), idx.squeeze(dim as isize))
+    }
+
     pub fn matmul(&self, w: &Self) -> Self {
+        self.try_matmul(w).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_matmul(&self, w: &Self) -> Result<Self, StormError> {
         let n1 = self.shape().len();
         let n2 = w.shape().len();
-        {
-            let tmp = -(n2.min(2) as isize);
-            assert!(n1 != 0 && n2 != 0);
-            assert!(
-                self.shape()[-1] == w.shape()[tmp],
-                "{}[-1] != {}[{tmp}]",
-                self.shape(),
-                w.shape()
-            );
+        if n1 == 0 || n2 == 0 || self.shape()[-1] != w.shape()[-(n2.min(2) as isize)] {
+            return Err(StormError::ShapeMismatch {
+                op: "matmul",
+                lhs: self.shape().dims,
+                rhs: w.shape().dims,
+            });
         }
         //x = self.reshape(*self.shape[0:-1], *[1]*min(n1-1, n2-1, 1), self.shape[-1])
         let x = self.reshape(
@@ -585,7 +828,101 @@ impl Tensor {
             )
             .transpose(-1, -(n2.min(2) as isize));
         let upper_type = least_upper_dtype(&[x.dtype(), w.dtype()]);
-        (x * w).sum([-1], false).cast(upper_type)
+        Ok((x * w).sum([-1], false).cast(upper_type))
+    }
+
+    // Tensor::einsum("bhqd,bhkd->bhqk", &[&q, &k]), every operand is permuted to sorted letter
+    // order and expanded to all letters, then multiplied and summed over the letters not in the
+    // output. Without "->" the output is the letters that appear once, sorted, like numpy.
+    pub fn einsum(formula: &str, xs: &[&Self]) -> Self {
+        Self::try_einsum(formula, xs).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_einsum(formula: &str, xs: &[&Self]) -> Result<Self, StormError> {
+        let err = |msg: String| StormError::InvalidArgument { op: "einsum", msg };
+        let formula: String = formula.chars().filter(|c| !c.is_whitespace()).collect();
+        let (lhs, output) = match formula.split_once("->") {
+            Some((lhs, rhs)) => (lhs, rhs.chars().collect::<Vec<char>>()),
+            None => {
+                let mut once = v![c, for c in formula.chars(), if c != ',' && formula.matches(c).count() == 1];
+                once.sort();
+                (formula.as_str(), once)
+            }
+        };
+        let inputs = v![s.chars().collect::<Vec<char>>(), for s in lhs.split(',')];
+        if inputs.len() != xs.len() {
+            return Err(err(format!("{formula} has {} operands, got {} tensors", inputs.len(), xs.len())));
+        }
+        if let Some(c) = inputs.iter().flatten().chain(&output).find(|c| !c.is_ascii_alphabetic()) {
+            return Err(err(format!("unsupported subscript {c:?} in {formula}")));
+        }
+        let mut letter_val = std::collections::BTreeMap::<char, isize>::new();
+        let mut xs_ = vec![];
+        for (letters, x) in izip!(inputs, xs) {
+            if letters.len() != x.shape().len() {
+                return Err(err(format!("{} has {} dims, got {} subscripts", x.shape(), x.shape().len(), letters.len())));
+            }
+            let (mut letters, mut x) = (letters, (*x).clone());
+            // A repeated letter takes the diagonal of its two axes
+            while let Some((i, j)) = (0..letters.len()).find_map(|i| (i + 1..letters.len()).find(|&j| letters[j] == letters[i]).map(|j| (i, j))) {
+                if x.shape()[i as isize] != x.shape()[j as isize] {
+                    return Err(StormError::ShapeMismatch { op: "einsum", lhs: x.shape().dims, rhs: vec![x.shape()[i as isize], x.shape()[j as isize]] });
+                }
+                x = x._diagonal(i, j);
+                letters.remove(j);
+            }
+            for (&l, &s) in izip!(&letters, &x.shape().dims) {
+                if *letter_val.entry(l).or_insert(s) != s {
+                    return Err(StormError::ShapeMismatch { op: "einsum", lhs: vec![letter_val[&l]], rhs: vec![s] });
+                }
+            }
+            xs_.push((letters, x));
+        }
+        if let Some(c) = output.iter().enumerate().find_map(|(i, c)| (!letter_val.contains_key(c) || output[..i].contains(c)).then_some(c)) {
+            return Err(err(format!("output subscript {c:?} in {formula} is repeated or not in any operand")));
+        }
+        let xs_ = v![{
+            let mut order = (0..letters.len()).collect::<Vec<usize>>();
+            order.sort_by_key(|&i| letters[i]);
+            x.permute(order)
+                .reshape(v![if letters.contains(l) { s } else { 1 }, for (l, &s) in letter_val.iter()])
+                .expand(v![s, for &s in letter_val.values()])
+        }, for (letters, x) in xs_];
+        let ret = xs_[1..].iter().fold(xs_[0].clone(), |acc, x| acc * x);
+        let axis = v![i as isize, for (i, l) in letter_val.keys().enumerate(), if !output.contains(l)];
+        let ret = if axis.is_empty() { ret } else { ret.sum(axis, false) };
+        if output.len() < 2 {
+            return Ok(ret);
+        }
+        // The remaining axes are in sorted letter order, put them in the order of the output
+        let mut sorted = output.clone();
+        sorted.sort();
+        Ok(ret.permute(v![sorted.iter().position(|s| s == l).unwrap(), for l in &output]))
+    }
+
+    // Moves the diagonal of axes i < j into axis i, the trick is that the diagonal of a n*n
+    // matrix is the first column once it's flattened, padded by n and viewed as n*(n+1)
+    fn _diagonal(&self, i: usize, j: usize) -> Self {
+        let n = self.shape()[i as isize];
+        let ndim = self.shape().len();
+        let mut order = v![a, for a in 0..ndim, if a != i && a != j];
+        order.extend([i, j]);
+        let x = self.permute(order.clone());
+        let rest = x.shape().dims[..ndim - 2].to_vec();
+        let mut pads = vec![(0, 0); ndim - 1];
+        pads[ndim - 2] = (0, n as usize);
+        let mut slc = v![(0, s as usize), for &s in &rest];
+        slc.extend([(0, n as usize), (0, 1)]);
+        let x = x
+            .reshape([rest.clone(), vec![n * n]].concat())
+            .pad(pads, 0)
+            .reshape([rest.clone(), vec![n, n + 1]].concat())
+            .shrink(slc)
+            .reshape([rest, vec![n]].concat());
+        // The diagonal is last, move it back to where i was
+        let mut back = (0..ndim - 2).collect::<Vec<usize>>();
+        back.insert(i, ndim - 2);
+        x.permute(back)
     }
 
     pub fn broadcast_r(x: &Self, y: &Self) -> (Self, Self) {
@@ -661,29 +998,152 @@ impl Tensor {
 
     // self._pool(make_pair(kernel_size), stride if stride is not None else kernel_size, dilation).max(axis=tuple(range(0-len(make_pair(kernel_size)), 0)))
     pub fn max_pool2d(&self) -> Self {
-        self._max_pool2d(None, None, None)
+        self._max_pool2d(2, 2, 1, 0, false)
     }
 
     pub fn _max_pool2d(
         &self,
-        kernel_size: Option<usize>,
-        stride: Option<usize>,
-        dilation: Option<usize>,
+        kernel_size: impl IntoAxes,
+        stride: impl IntoAxes,
+        dilation: impl IntoAxes,
+        padding: impl IntoAxes,
+        ceil_mode: bool,
+    ) -> Self {
+        self._max_pool::<2>(kernel_size, stride, dilation, padding, ceil_mode)
+    }
+
+    pub fn avg_pool2d(&self) -> Self {
+        self._avg_pool2d(2, 2, 1, 0, false, true)
+    }
+
+    pub fn _avg_pool2d(
+        &self,
+        kernel_size: impl IntoAxes,
+        stride: impl IntoAxes,
+        dilation: impl IntoAxes,
+        padding: impl IntoAxes,
+        ceil_mode: bool,
+        count_include_pad: bool,
+    ) -> Self {
+        self._avg_pool::<2>(kernel_size, stride, dilation, padding, ceil_mode, count_include_pad)
+    }
+
+    pub fn adaptive_avg_pool2d(&self, output_size: impl IntoAxes) -> Self {
+        self.adaptive_avg_pool::<2>(output_size)
+    }
+
+    pub fn adaptive_max_pool2d(&self, output_size: impl IntoAxes) -> Self {
+        self.adaptive_max_pool::<2>(output_size)
+    }
+
+    // Max pooling over the last D axes, `padding` goes on both sides of each
+    pub fn _max_pool<const D: usize>(
+        &self,
+        kernel_size: impl IntoAxes,
+        stride: impl IntoAxes,
+        dilation: impl IntoAxes,
+        padding: impl IntoAxes,
+        ceil_mode: bool,
+    ) -> Self {
+        let (k_, s_, d_) = (kernel_size.into_axes(D), stride.into_axes(D), dilation.into_axes(D));
+        let pads = self._pool_pads(&k_, &s_, &d_, &padding.into_axes(D), ceil_mode);
+        self.pad(vec![vec![(0, 0); self.ndim() - D], pads].concat(), f32::NEG_INFINITY)
+            ._pool(k_, s_, d_)
+            .max(v![-1 - i as isize, for i in 0..D], false)
+    }
+
+    // Average pooling over the last D axes. With `count_include_pad` the padding counts towards
+    // the size of a window, the extra windows of `ceil_mode` never do.
+    pub fn _avg_pool<const D: usize>(
+        &self,
+        kernel_size: impl IntoAxes,
+        stride: impl IntoAxes,
+        dilation: impl IntoAxes,
+        padding: impl IntoAxes,
+        ceil_mode: bool,
+        count_include_pad: bool,
     ) -> Self {
-        let kernel_size = kernel_size.unwrap_or(2);
-        let stride = stride.unwrap_or(2);
-        let dilation = dilation.unwrap_or(1);
-        self._pool([kernel_size, kernel_size], stride, dilation)
-            .max(v![i, for i in (-2..0)], false)
+        let (k_, s_, d_) = (kernel_size.into_axes(D), stride.into_axes(D), dilation.into_axes(D));
+        let p_ = padding.into_axes(D);
+        let pads = self._pool_pads(&k_, &s_, &d_, &p_, ceil_mode);
+        let axis = v![-1 - i as isize, for i in 0..D];
+        let x = self.pad(vec![vec![(0, 0); self.ndim() - D], pads.clone()].concat(), 0)._pool(k_.clone(), s_.clone(), d_.clone());
+        if count_include_pad && !ceil_mode {
+            return x.mean(axis, false);
+        }
+        // The size of every window, which broadcasts over the leading axes
+        let i_ = v![*s as usize, for s in self.shape().dims[self.ndim() - D..].iter()];
+        let ones = if count_include_pad {
+            let padded = v![i + 2 * p, for (i, p) in izip!(i_.iter(), p_.iter())];
+            Tensor::ones(padded).pad(v![(0, r - p), for (&p, &(_, r)) in izip!(p_.iter(), pads.iter())], 0)
+        } else {
+            Tensor::ones(i_).pad(pads, 0)
+        };
+        x.sum(axis.clone(), false) / ones._pool(k_, s_, d_).sum(axis, false)
+    }
+
+    // Pools the last D axes down to `output_size` with windows spread like torch does
+    pub fn adaptive_avg_pool<const D: usize>(&self, output_size: impl IntoAxes) -> Self {
+        self._adaptive_pool(output_size.into_axes(D), false)
+    }
+
+    pub fn adaptive_max_pool<const D: usize>(&self, output_size: impl IntoAxes) -> Self {
+        self._adaptive_pool(output_size.into_axes(D), true)
     }
 
+    // Max and mean over a box are the max and mean of the ones over each side, so this pools
+    // one axis at a time. Window j of an axis is [floor(j*i/o), ceil((j+1)*i/o)).
+    fn _adaptive_pool(&self, o_: Vec<usize>, max: bool) -> Self {
+        let reduce = |x: &Tensor, axis: isize, keepdim: bool| {
+            if max { x.max([axis], keepdim) } else { x.mean([axis], keepdim) }
+        };
+        let mut x = self.clone();
+        for (j, &o) in o_.iter().enumerate() {
+            let axis = self.ndim() - o_.len() + j;
+            let i = x.shape().dims[axis] as usize;
+            x = if i % o == 0 {
+                let k = i / o;
+                reduce(&x.transpose(axis as isize, -1)._pool([k], k, 1), -1, false).transpose(axis as isize, -1)
+            } else {
+                let windows = v![{
+                    let mut arg = v![(0, s as usize), for s in x.shape().dims];
+                    arg[axis] = (w * i / o, ((w + 1) * i + o - 1) / o);
+                    reduce(&x.shrink(arg), axis as isize, true)
+                }, for w in 0..o];
+                windows[0].cat(&windows[1..], Some(axis as isize))
+            };
+        }
+        x
+    }
+
+    // Padding of the last k_.len() axes, `padding` on both sides plus whatever `ceil_mode` needs on
+    // the right to keep a last partial window. That window has to start inside the input or the
+    // left padding.
+    fn _pool_pads(&self, k_: &[usize], s_: &[usize], d_: &[usize], p_: &[usize], ceil_mode: bool) -> Vec<(usize, usize)> {
+        let i_ = &self.shape().dims[self.ndim() - k_.len()..];
+        v![{
+            let i = i as usize;
+            let span = d * (k - 1) + 1;
+            assert!(i + 2 * p >= span, "pooling window {span} is bigger than the padded input {}", i + 2 * p);
+            let extra = if ceil_mode {
+                let mut o = (i + 2 * p - span + s - 1) / s + 1;
+                if (o - 1) * s >= i + p {
+                    o -= 1;
+                }
+                ((o - 1) * s + span).saturating_sub(i + 2 * p)
+            } else {
+                0
+            };
+            (p, p + extra)
+        }, for (&i, &k, &s, &d, &p) in izip!(i_.iter(), k_.iter(), s_.iter(), d_.iter(), p_.iter())]
+    }
 
     #[rustfmt::skip]
-    pub fn _pool<S: Into<Shape>>(&self, k_: S, stride: usize, dilation: usize) -> Self {
+    pub fn _pool<S: Into<Shape>>(&self, k_: S, stride: impl IntoAxes, dilation: impl IntoAxes) -> Self {
         let self_shape = self.shape();
         let k_ = k_.into().dims.iter().map(|n| *n).collect::<Vec<isize>>();
-        let d_ = vec![dilation as isize;k_.len()];
-        let s_ = vec![stride as isize;k_.len()];
+        let d_ = v![d as isize, for d in dilation.into_axes(k_.len())];
+        let s_ = v![s as isize, for s in stride.into_axes(k_.len())];
         assert!(self_shape.len() >= k_.len(), "can't pool {self_shape:?} with {k_:?}");
         assert!(k_.len() == s_.len() && s_.len() == d_.len(), "stride/dilation mismatch kernel:{k_:?} stride:{s_:?} dilation:{d_:?}");
         let slc_prefix: Vec<(isize, isize)> = self_shape.dims[0..self_shape.len() - k_.len()]
@@ -761,14 +1221,15 @@ impl Tensor {
         self._conv2d(weigth, None, 1, 1, 1, [0])
     }
 
+    // Takes any number of spatial axes, the kernel sizes are the trailing axes of the weight
     #[rustfmt::skip]
     pub fn _conv2d<V: Into<Vec<usize>>>(
         &self,
         weight: &Self,
         bias: Option<&Self>,
         groups: usize,
-        stride: usize,
-        dilation: usize,
+        stride: impl IntoAxes,
+        dilation: impl IntoAxes,
         padding: V,
     ) -> Self {
         let [bs, cin_] = self.shape().dims[..2] else {
@@ -834,6 +1295,62 @@ impl Tensor {
         }.contiguous().contiguous_backward()
     }
 
+    // The weight is (cin, cout / groups, *kernel) like torch, `padding` is removed from both sides
+    // of the output and `output_padding` is added to the end of it
+    #[rustfmt::skip]
+    pub fn conv_transpose2d(
+        &self,
+        weight: &Self,
+        bias: Option<&Self>,
+        groups: usize,
+        stride: impl IntoAxes,
+        dilation: impl IntoAxes,
+        padding: impl IntoAxes,
+        output_padding: impl IntoAxes,
+    ) -> Self {
+        let hw = v![*k as usize, for k in weight.shape().dims[2..].iter()];
+        let n = hw.len();
+        let stride = stride.into_axes(n);
+        let dilation = dilation.into_axes(n);
+        let padding = padding.into_axes(n);
+        let output_padding = output_padding.into_axes(n);
+        let [cin, rcout] = weight.shape().dims[..2] else {
+            panic!()
+        };
+        let (cin, rcout) = (cin as usize, rcout as usize);
+        assert!(
+            cin % groups == 0 && self.shape().len() == weight.shape().len() && self.shape()[1] as usize == cin,
+            "Input Tensor shape {} does not match the shape of the weights {}",
+            self.shape(),
+            weight.shape()
+        );
+        // (cin, cout/groups, *HW) -> (groups*cout/groups, cin/groups, *HW) with the kernel flipped
+        let w = weight
+            .reshape(vec![vec![groups, cin / groups, rcout], hw.clone()].concat())
+            .permute(vec![vec![0, 2, 1], v![3 + i, for i in 0..n]].concat())
+            .flip(v![3 + i as isize, for i in 0..n])
+            .reshape(vec![vec![groups * rcout, cin / groups], hw.clone()].concat());
+        let [bs, c] = self.shape().dims[..2] else {
+            panic!()
+        };
+        let (bs, c) = (bs as usize, c as usize);
+        let mut x = self.clone();
+        if stride.iter().any(|s| *s > 1) {
+            // (k) -> (k, 1) -> (k, s) -> (k*s) -> (k*s - (s-1)), s-1 zeros between the inputs
+            let i_ = v![*i as usize, for i in x.shape().dims[2..].iter()];
+            x = x.reshape(vec![vec![bs, c], v![[i, 1], for &i in i_.iter()].concat()].concat())
+                .pad(vec![vec![(0, 0); 2], v![[(0, 0), (0, s - 1)], for &s in stride.iter()].concat()].concat(), 0)
+                .reshape(vec![vec![bs, c], v![i * s, for (&i, &s) in izip!(i_.iter(), stride.iter())]].concat())
+                .shrink(vec![vec![(0, bs), (0, c)], v![(0, i * s - (s - 1)), for (&i, &s) in izip!(i_.iter(), stride.iter())]].concat());
+        }
+        // Pads (k-1)*d - p before and (k-1)*d - p + output_padding after, negative amounts crop
+        let i_ = x.shape().dims[2..].to_vec();
+        let slc = v![(-(((k - 1) * d) as isize - p as isize), i + ((k - 1) * d) as isize - p as isize + op as isize),
+            for (&i, &k, &d, &p, &op) in izip!(i_.iter(), hw.iter(), dilation.iter(), padding.iter(), output_padding.iter())];
+        x.slice(vec![vec![(0, bs as isize), (0, c as isize)], slc].concat(), 0)
+            ._conv2d(&w, bias, groups, 1, dilation, [0])
+    }
+
     pub fn t(&self) -> Self {
         self.transpose(1, 0)
     }
@@ -863,6 +1380,8 @@ impl Tensor {
         ret.push(node.clone());
     }
 
+    // Adds the gradient of this scalar to .grad of everything it was computed from that requires
+    // grad
     pub fn backward(&mut self) {
         assert!(
             self.shape().len() == 1,
@@ -870,104 +1389,27 @@ impl Tensor {
             self.shape()
         );
         (*self.grad.lock().unwrap()) = Some(Tensor::_const(1f32));
-        let deepwalked = self.deepwalk().into_iter();
-        let _deepwalked_len = deepwalked.len();
-        for mut t0 in deepwalked.rev() {
-            let t0g_clone = (*t0.grad.lock().unwrap())
-                .as_ref()
-                .expect("t0 should have a grad")
-                .buffer
-                .clone();
-            let grads = match t0._ctx.as_mut().unwrap().backward(&t0g_clone) {
-                Grad::One(g) => vec![Some(Tensor {
-                    buffer: g.into(),
-                    require_grad: false,
-                    grad: Arc::default(),
-                    _ctx: None,
-                    id: tensor_id(),
-                })],
-                Grad::Two(mut g1, mut g2) => {
-                    let mut out = vec![];
-                    out.push(if let Some(g) = g1.take() {
-                        // if g.to_vec().iter().any(|n| n.is_nan()) {
-                        //     panic!("g has NaN")
-                        // } else {
-                        //     println!("{:?}", g);
-                        // }
-                        Some(Tensor {
-                            buffer: g.into(),
-                            require_grad: false,
-                            grad: Arc::default(),
-                            _ctx: None,
-                            id: tensor_id(),
-                        })
-                    } else {
-                        None
-                    });
-                    out.push(if let Some(g) = g2.take() {
-                        // if g.to_vec().iter().any(|n| n.is_nan()) {
-                        //     panic!("g has NaN")
-                        // } else {
-                        //     println!("{:?}", g);
-                        // }
-                        Some(Tensor {
-                            buffer: g.into(),
-                            require_grad: false,
-                            grad: Arc::default(),
-                            _ctx: None,
-                            id: tensor_id(),
-                        })
-                    } else {
-                        None
-                    });
-                    out
-                }
-            };
-            assert!(t0._ctx.as_ref().unwrap().parents_ref().len() == grads.len());
-            for (t, g) in t0
-                ._ctx
-                .as_mut()
-                .unwrap()
-                .parents_mut()
-                .iter()
-                .zip(grads.iter())
-            {
-                if g.is_none() || !t.require_grad {
-                    continue;
-                }
-                let g = g.as_ref().unwrap();
-                assert!(
-                    t.shape() == g.shape(),
-                    "grad shape must match tensor shape, {} != {} \n {} {t}\n {} {g}",
-                    g.shape(),
-                    t.shape(),
-                    t.buffer.id,
-                    g.buffer.id,
-                );
-                let mut t_grad = t.grad.lock().unwrap();
-                if t_grad.is_none() {
-                    *t_grad = Some(g.clone());
-                } else {
-                    *t_grad = Some(g + (*t_grad).as_ref().unwrap());
+        let mut seen = HashSet::new();
+        let mut targets = vec![];
+        for t in self.deepwalk() {
+            for p in t._ctx.as_ref().unwrap().parents_ref().iter() {
+                if p.require_grad && seen.insert(p.id) {
+                    targets.push(p.clone());
                 }
-                // if (*t_grad)
-                //     .as_ref()
-                //     .unwrap()
-                //     .to_vec()
-                //     .iter()
-                //     .any(|n| n.is_nan())
-                // {
-                //     panic!("g has NaN")
-                // } else {
-                //     println!("{:?}", g);
-                // }
             }
-            t0._ctx = None;
+        }
+        let grads = crate::autograd::grad(&[&*self], &v![t, for t in targets.iter()], None, false);
+        for (t, g) in targets.iter().zip(grads) {
+            let mut t_grad = t.grad.lock().unwrap();
+            *t_grad = Some(match t_grad.take() {
+                Some(prev) => g + prev,
+                None => g,
+            });
         }
     }
 
     pub fn add(&self, rhs: &Self) -> Self {
-        if rhs.is_const() && rhs.get_const_val().unwrap().is_zero() {
+        if rhs.is_const() && !rhs.require_grad && rhs.get_const_val().unwrap().is_zero() {
             return self.clone();
         }
         let (a, b) = Tensor::broadcast(&self, &rhs);
@@ -979,7 +1421,7 @@ impl Tensor {
     }
 
     pub fn sub(&self, rhs: &Self) -> Self {
-        if rhs.is_const() && rhs.get_const_val().unwrap().is_zero() {
+        if rhs.is_const() && !rhs.require_grad && rhs.get_const_val().unwrap().is_zero() {
             return self.clone();
         }
         let (a, b) = Tensor::broadcast(&self, &rhs);
@@ -991,7 +1433,7 @@ impl Tensor {
     }
 
     pub fn mul(&self, rhs: &Self) -> Self {
-        if rhs.is_const() && rhs.get_const_val().unwrap().is_one() {
+        if rhs.is_const() && !rhs.require_grad && rhs.get_const_val().unwrap().is_one() {
             return self.clone();
         }
         let (a, b) = Tensor::broadcast(&self, &rhs);
@@ -1067,23 +1509,19 @@ impl Tensor {
     }
 
     // self is pred
+    // Labels of -1 are ignored
     pub fn sparse_categorical_crossentropy(&self, y: &Self) -> Self {
-        let loss_mark = y._ne(&y.full_like(-1.0));
-        //y_counter = Tensor.arange(self.shape[-1], requires_grad=False, device=self.device).unsqueeze(0).expand(Y.numel(), self.shape[-1])
-        let mut y_counter = Self::arange(self.shape()[-1] as f32);
-        y_counter = y_counter
-            .unsqueeze(0)
-            .expand([y.shape().numel(), self.shape()[-1] as usize]);
-        // y = ( (y_counter == Y.flatten().reshape(-1, 1)) .where(-1.0, 0) * loss_mask.reshape(-1, 1)) .reshape(*Y.shape, self.shape[-1])
-        let mut y_rsh = y.shape();
-        y_rsh.dims.push(self.shape()[-1]);
-
-        let yy = (y_counter
-            ._eq(&y.flatten().reshape([y.shape().numel(), 1]))
-            ._where(-1.0, 0.0)
-            * loss_mark.reshape([loss_mark.shape().numel(), 1]))
-        .reshape(y_rsh);
-        (self.log_softmax() * yy).sum([], false) / loss_mark.sum([], false)
+        let loss_mask = y._ne(&y.full_like(-1.0));
+        // The leading axes of self broadcast to the shape of y
+        let x = self.log_softmax();
+        let x = x
+            .reshape(vec![vec![1; (y.ndim() + 1).saturating_sub(x.ndim())], x.shape().dims].concat())
+            .expand(vec![y.shape().dims, vec![self.shape()[-1]]].concat());
+        let picked = x.gather(-1, &y.unsqueeze(-1)).squeeze(-1);
+        // Labels that aren't one of the classes, -1 among them, pick nothing
+        let classes = y.full_like(self.shape()[-1] as f32);
+        let hit = y._eq(&y.cast(int32).float()) * y._ge(&y.full_like(0.0)) * y._lt(&classes);
+        -(picked * hit).sum([], false) / loss_mask.sum([], false)
     }
 
     pub fn bce(&self, y: &Self) -> Self {
@@ -1193,24 +1631,50 @@ impl Tensor {
     }
 
     pub fn realize(&self) -> Self {
+        self.try_realize().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_realize(&self) -> Result<Self, StormError> {
         let mut seen = HashSet::new();
         let mut ret = self.clone();
-        run_schedule(ret.buffer.schedule(&mut seen));
-        ret
+        let mut sched = ret.buffer.schedule(&mut seen);
+        if DEBUG.0.contains("GRAPH") {
+            viz::debug_save(&[&ret.buffer], sched.make_contiguous());
+        }
+        try_run_schedule(sched)?;
+        Ok(ret)
     }
 
     pub fn corealize(list: Vec<Tensor>) {
+        Self::try_corealize(list).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub fn try_corealize(list: Vec<Tensor>) -> Result<(), StormError> {
         let mut seen = HashSet::new();
         let mut sched = std::collections::VecDeque::new();
-        for t in list {
+        for t in list.iter() {
             sched.extend(t.buffer.schedule(&mut seen));
         }
-        run_schedule(sched);
+        if DEBUG.0.contains("GRAPH") {
+            viz::debug_save(&v![&t.buffer, for t in list.iter()], sched.make_contiguous());
+        }
+        try_run_schedule(sched)
+    }
+
+    // What realizing this tensor would allocate, without running anything
+    pub fn memory_plan(&self) -> crate::memory::MemoryPlan {
+        crate::memory::plan(&crate::lazy::create_schedule(vec![&self.buffer], None))
+    }
+
+    // Writes the graph behind this tensor as DOT, with the kernels realizing it would run
+    pub fn export_graph(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        viz::save(&[&self.buffer], path)
     }
 
     pub fn detach(&self) -> Self {
         Self {
             require_grad: false,
+            grad_pinned: false,
             grad: Arc::default(),
             _ctx: None,
             id: tensor_id(),
@@ -1301,6 +1765,27 @@ impl Tensor {
         y.mul(&((&y * &y).mean(axis, true) + eps).rsqrt())
     }
 
+    // Normalizes over `axis` 1 given the mean and inverse std of each channel
+    pub fn batchnorm(
+        &self,
+        weight: Option<&Tensor>,
+        bias: Option<&Tensor>,
+        mean: &Tensor,
+        invstd: &Tensor,
+    ) -> Self {
+        let shape = vec![vec![1, -1], vec![1; self.shape().len() - 2]].concat();
+        let mut x = self - &mean.reshape(shape.clone());
+        if let Some(w) = weight {
+            x = &x * &w.reshape(shape.clone());
+        }
+        let ret = &x * &invstd.reshape(shape.clone());
+        if let Some(b) = bias {
+            &ret + &b.reshape(shape)
+        } else {
+            ret
+        }
+    }
+
     pub fn scaled_dot_product_attention(
         &self,
         key: &Tensor,
@@ -1382,8 +1867,171 @@ impl Tensor {
         .unwrap()
     }
 
+    // Numpy style indexing, missing trailing axes are taken whole. An integer drops its axis, a
+    // range shrinks it and an integer tensor picks elements along it. The tensor indices broadcast
+    // together and their shape takes the place of the axes they index, or goes in front when
+    // those axes aren't next to each other.
     pub fn index<I: Into<IndexRange>, V: Into<Vec<I>>>(&self, idxs: V) -> Tensor {
-        todo!()
+        let idxs = v![i.into(), for i in idxs.into()];
+        let shape = self.shape().dims;
+        assert!(
+            idxs.len() <= shape.len(),
+            "too many indices ({}) for shape {shape:?}",
+            idxs.len()
+        );
+        let n = idxs.len();
+        let mut shrink = v![(0, s as usize), for &s in shape.iter()];
+        // Axes left after the integer indices, and the tensors indexing them by position in those
+        let mut keep = vec![];
+        let mut tensors = vec![];
+        for (i, idx) in idxs.into_iter().enumerate() {
+            match idx {
+                IndexRange::Isize(k) => {
+                    let k = if k < 0 { k + shape[i] } else { k };
+                    assert!(
+                        0 <= k && k < shape[i],
+                        "index {k} is out of bounds for axis {i} with size {}",
+                        shape[i]
+                    );
+                    shrink[i] = (k as usize, k as usize + 1);
+                    continue;
+                }
+                IndexRange::Range(r) => {
+                    let end = r.end.min(shape[i] as usize);
+                    shrink[i] = (r.start.min(end), end);
+                }
+                IndexRange::RangeFull => (),
+                IndexRange::Tensor(t) => tensors.push((keep.len(), t)),
+            }
+            keep.push(i);
+        }
+        keep.extend(n..shape.len());
+        let x = self.shrink(shrink.clone());
+        let x = x.reshape(if keep.is_empty() { vec![1] } else { v![(shrink[i].1 - shrink[i].0) as isize, for &i in keep.iter()] });
+        if tensors.is_empty() {
+            return x;
+        }
+
+        let xs = x.shape().dims;
+        let dims = v![*d, for (d, _) in tensors.iter()];
+        let rest = v![i, for i in 0..xs.len(), if !dims.contains(&i)];
+        let mut b: Vec<isize> = vec![];
+        for (_, t) in tensors.iter() {
+            let ts = t.shape().dims;
+            let n = b.len().max(ts.len());
+            let at = |s: &[isize], i: usize| if i + s.len() < n { 1 } else { s[i + s.len() - n] };
+            b = v![at(&b, i).max(at(&ts, i)), for i in 0..n];
+        }
+        let nb = b.len();
+        // The indexed axes go in front as one, a gather along it picks every position of the
+        // broadcast indices at once
+        let rest_shape = v![xs[i], for &i in rest.iter()];
+        let mut n = v![xs[*d], for (d, _) in tensors.iter()].iter().product::<isize>();
+        let x = x.permute(vec![dims.clone(), rest.clone()].concat()).reshape(vec![vec![n], rest_shape.clone()].concat());
+        let mut flat: Option<Tensor> = None;
+        for (d, t) in tensors.iter() {
+            n /= xs[*d];
+            let t = t._wrap_index(xs[*d]) * n as f32;
+            let ts = t.shape().dims;
+            let t = t.reshape(vec![vec![1; nb - ts.len()], ts].concat()).expand(b.clone());
+            flat = Some(match flat {
+                Some(flat) => flat + t,
+                None => t,
+            });
+        }
+        let numel = b.iter().product::<isize>();
+        let flat = flat.unwrap().reshape(vec![vec![numel], vec![1; rest.len()]].concat());
+        let flat = flat.expand(vec![vec![numel], rest_shape.clone()].concat());
+        let ret = x.gather(0, &flat).reshape(vec![b.clone(), rest_shape].concat());
+        if dims.windows(2).all(|w| w[1] == w[0] + 1) && dims[0] > 0 {
+            // The indexed axes were next to each other, put their result back where they were
+            let order = vec![v![nb + i, for i in 0..dims[0]], v![i, for i in 0..nb], v![nb + i, for i in dims[0]..rest.len()]].concat();
+            return ret.permute(order);
+        }
+        ret
+    }
+
+    // Appends an axis of size `num_classes` that is one at the class given by each element
+    pub fn one_hot(&self, num_classes: usize) -> Self {
+        self.float().unsqueeze(-1)._eq(&Tensor::arange(num_classes as f32))
+    }
+
+    // Index values as float with the ones below zero counted from the end of an axis of `size`
+    fn _wrap_index(&self, size: isize) -> Self {
+        let t = self.float();
+        t._lt(&t.const_like(0.0))._where_(&(&t + size as f32), &t)
+    }
+
+    // Picks along `dim` at the positions given by `index`, for dim 0 that is
+    // out[i][j] = self[index[i][j]][j]. `index` has as many axes as self and may be smaller on the
+    // axes other than `dim`. Indices below zero count from the end of `dim`, like everywhere else
+    // an integer tensor indexes, and the kernel clamps the ones still out of range.
+    pub fn gather(&self, dim: isize, index: &Self) -> Self {
+        let dim = if dim < 0 { dim + self.ndim() as isize } else { dim } as usize;
+        let (shape, ish) = (self.shape().dims, index.shape().dims);
+        assert!(
+            ish.len() == shape.len() && (0..ish.len()).all(|d| d == dim || ish[d] <= shape[d]),
+            "gather index {} must have as many axes as {} and be no larger outside axis {dim}",
+            index.shape(),
+            self.shape()
+        );
+        Gather {
+            dim,
+            ..Default::default()
+        }
+        .apply(self, Some(index), None, None, None)
+    }
+
+    // Copy of self with `src` written at the positions `index` gives along `dim`, the inverse of
+    // `gather`. Like torch the result is undefined where an index repeats.
+    pub fn scatter(&self, dim: isize, index: &Self, src: &Self) -> Self {
+        self._scatter(dim, index, src, false)
+    }
+
+    // Like `scatter` but adds `src` to self, repeated indices add up
+    pub fn scatter_add(&self, dim: isize, index: &Self, src: &Self) -> Self {
+        self._scatter(dim, index, src, true)
+    }
+
+    fn _scatter(&self, dim: isize, index: &Self, src: &Self, add: bool) -> Self {
+        let dim = if dim < 0 { dim + self.ndim() as isize } else { dim } as usize;
+        assert!(
+            index.ndim() == self.ndim() && src.ndim() == self.ndim(),
+            "scatter index {} and src {} must have as many axes as {}",
+            index.shape(),
+            src.shape(),
+            self.shape()
+        );
+        Scatter {
+            dim,
+            add,
+            ..Default::default()
+        }
+        .apply(self, Some(index), Some(&src.cast(self.dtype())), None, None)
+    }
+
+    // `src` and a one-hot mask of `index`, both shaped like self with an extra last axis over the
+    // size of `index` along `dim`
+    pub(crate) fn _pre_scatter(&self, dim: usize, index: &Self, src: &Self) -> (Self, Self) {
+        let size = self.shape().dims[dim];
+        let ish = index.shape().dims;
+        let src = src
+            .shrink(v![(0, i as usize), for &i in ish.iter()])
+            .unsqueeze(-1)
+            .expand(vec![ish.clone(), vec![size]].concat())
+            .transpose(-1, dim as isize);
+        let mask = index._wrap_index(size).one_hot(size as usize).cast(self.dtype()).transpose(-1, dim as isize);
+        let pad = v![(0, if d == dim { 0 } else { (s - ish[d]) as usize }), for (d, &s) in self.shape().dims.iter().enumerate()];
+        let pad = vec![pad, vec![(0, 0)]].concat();
+        (src.pad(pad.clone(), 0), mask.pad(pad, 0))
+    }
+
+    pub fn squeeze(&self, dim: isize) -> Self {
+        let dim = if dim < 0 { dim + self.ndim() as isize } else { dim } as usize;
+        if self.shape().dims[dim] != 1 {
+            return self.clone();
+        }
+        self.reshape(v![s, for (i, &s) in self.shape().dims.iter().enumerate(), if i != dim])
     }
 
     pub fn chunk(&self, num_chunks: usize, dim: Option<isize>) -> Vec<Tensor> {
@@ -1471,6 +2119,19 @@ pub enum IndexRange {
     RangeFull,
     Range(std::ops::Range<usize>),
     Isize(isize),
+    Tensor(Tensor),
+}
+
+impl From<Tensor> for IndexRange {
+    fn from(value: Tensor) -> Self {
+        IndexRange::Tensor(value)
+    }
+}
+
+impl From<&Tensor> for IndexRange {
+    fn from(value: &Tensor) -> Self {
+        IndexRange::Tensor(value.clone())
+    }
 }
 
 impl From<isize> for IndexRange {
@@ -73,6 +73,39 @@ impl<I: PrimInt + ToPrimitive> From<&[I]> for Shape {
     }
 }
 
+// A per-axis argument such as a stride, either one value used for every axis or one per axis
+pub trait IntoAxes {
+    fn into_axes(self, n: usize) -> Vec<usize>;
+}
+
+impl IntoAxes for usize {
+    fn into_axes(self, n: usize) -> Vec<usize> {
+        vec![self; n]
+    }
+}
+
+impl IntoAxes for Vec<usize> {
+    fn into_axes(self, n: usize) -> Vec<usize> {
+        if self.len() == 1 {
+            return vec![self[0]; n];
+        }
+        assert!(self.len() == n, "expected {n} values, one per axis, got {self:?}");
+        self
+    }
+}
+
+impl<const D: usize> IntoAxes for [usize; D] {
+    fn into_axes(self, n: usize) -> Vec<usize> {
+        self.to_vec().into_axes(n)
+    }
+}
+
+impl IntoAxes for &[usize] {
+    fn into_axes(self, n: usize) -> Vec<usize> {
+        self.to_vec().into_axes(n)
+    }
+}
+
 impl Index<isize> for Shape {
     type Output = isize;
     fn index(&self, index: isize) -> &Self::Output {
@@ -0,0 +1,144 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::lazy::{create_schedule, LazyBufferId};
+use crate::ops::{OpType, ScheduleItem};
+use crate::prelude::*;
+
+fn color(optype: &OpType) -> &'static str {
+    match optype {
+        OpType::Load(_) => "#FFFFa0",
+        OpType::Unary(_) | OpType::Binary(_) | OpType::Ternary(_) => "#c0c0c0",
+        OpType::Reduce(_) => "#8080ff",
+        OpType::Movement(_) => "#80ff80",
+        OpType::Buffer(_) => "#FF8080",
+    }
+}
+
+// Which schedule item each unrealized buffer is computed in. A kernel owns everything between its
+// output and the buffers it loads, the views it reads through and the consts it inlines included.
+fn kernels(schedule: &[ScheduleItem]) -> HashMap<LazyBufferId, usize> {
+    let outs = HashSet::<LazyBufferId>::from_iter(schedule.iter().map(|si| si.out.id));
+    let mut ret = HashMap::new();
+    for (k, si) in schedule.iter().enumerate() {
+        let mut stack = vec![&si.out];
+        while let Some(lb) = stack.pop() {
+            if ret.contains_key(&lb.id) {
+                continue;
+            }
+            ret.insert(lb.id, k);
+            for src in lb.lazyop.src.iter() {
+                let view = src.lb();
+                let base = view.base_ref();
+                if view != base {
+                    ret.entry(view.id).or_insert(k);
+                }
+                if !base.is_realized() && !outs.contains(&base.id) {
+                    stack.push(base);
+                }
+            }
+        }
+    }
+    ret
+}
+
+// The graph behind outs as DOT, one node per buffer and view labeled with its op, shape, dtype and
+// whether it is realized. Nodes that schedule computes are grouped into one cluster per kernel.
+pub fn to_dot(outs: &[&LazyBuffer], schedule: &[ScheduleItem]) -> String {
+    let kernel = kernels(schedule);
+    let mut nodes = Vec::new();
+    let mut edges = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = outs.to_vec();
+    while let Some(lb) = stack.pop() {
+        if !seen.insert(lb.id) {
+            continue;
+        }
+        nodes.push(lb);
+        if let Some(base) = lb._base.as_deref() {
+            edges.push((base.id, lb.id));
+            stack.push(base);
+            continue;
+        }
+        for src in lb.lazyop.src.iter() {
+            edges.push((src.lb().id, lb.id));
+            stack.push(src.lb());
+        }
+    }
+    nodes.sort_by_key(|lb| lb.id);
+
+    let mut clusters: Vec<Vec<&LazyBuffer>> = vec![vec![]; schedule.len()];
+    let mut rest = vec![];
+    for lb in nodes {
+        match kernel.get(&lb.id) {
+            Some(&k) => clusters[k].push(lb),
+            None => rest.push(lb),
+        }
+    }
+    let node = |lb: &LazyBuffer, k: Option<usize>| {
+        let realized = lb.is_realized();
+        let mut label = format!(
+            "{:?}\\n{:?} {}",
+            lb.lazyop.optype, lb.shape, lb.dtype.type_name
+        );
+        if realized {
+            label += "\\nrealized";
+        }
+        if let Some(k) = k {
+            write!(label, "\\nkernel {k}").unwrap();
+        }
+        format!(
+            "  n{} [label=\"{}\", fillcolor=\"{}\", style=\"{}\"];\n",
+            lb.id,
+            label,
+            color(&lb.lazyop.optype),
+            if realized { "filled,bold" } else { "filled" }
+        )
+    };
+
+    let mut dot = String::from("digraph G {\n  node [shape=box];\n");
+    for (k, (si, lbs)) in schedule.iter().zip(clusters).enumerate() {
+        writeln!(
+            dot,
+            "  subgraph cluster_{k} {{\n  label=\"kernel {k}: {:?}\";",
+            si.ast.optype
+        )
+        .unwrap();
+        for lb in lbs {
+            dot += &node(lb, Some(k));
+        }
+        dot += "  }\n";
+    }
+    for lb in rest {
+        dot += &node(lb, None);
+    }
+    for (from, to) in edges {
+        writeln!(dot, "  n{from} -> n{to};").unwrap();
+    }
+    dot += "}\n";
+    dot
+}
+
+// Schedules outs without running anything and writes the graph to path
+pub fn save(outs: &[&LazyBuffer], path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    std::fs::write(path, to_dot(outs, &create_schedule(outs.to_vec(), None)))
+}
+
+// DEBUG=GRAPH writes every schedule about to run to $GRAPHPATH/graph_<n>.dot, the temp dir by
+// default
+pub(crate) fn debug_save(outs: &[&LazyBuffer], schedule: &[ScheduleItem]) {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    if schedule.is_empty() {
+        return;
+    }
+    let dir = getenv("GRAPHPATH", std::env::temp_dir().display().to_string());
+    let path = std::path::Path::new(&dir).join(format!(
+        "graph_{}.dot",
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    match std::fs::write(&path, to_dot(outs, schedule)) {
+        Ok(()) => println!("saved graph to {}", path.display()),
+        Err(e) => println!("couldn't save graph to {}: {e}", path.display()),
+    }
+}
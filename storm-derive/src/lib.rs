@@ -0,0 +1,179 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Field, Fields, Ident, LitStr};
+
+// Field options given with `#[module(...)]`
+#[derive(Default)]
+struct FieldOpts {
+    skip: bool,
+    buffer: bool,
+    rename: Option<String>,
+}
+
+fn field_opts(field: &Field) -> syn::Result<FieldOpts> {
+    let mut opts = FieldOpts::default();
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("module")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                opts.skip = true;
+            } else if meta.path.is_ident("buffer") {
+                opts.buffer = true;
+            } else if meta.path.is_ident("rename") {
+                opts.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else {
+                return Err(meta.error("expected `skip`, `buffer` or `rename = \"...\"`"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(opts)
+}
+
+// One statement per field extending `ret` with the tensors of that field, `access` is how the
+// field is reached (`&self.x`, `&mut self.x` or a binding from a match)
+fn extend_fields(
+    fields: &Fields,
+    access: &[TokenStream2],
+    method: &Ident,
+    buffers: bool,
+    transparent: bool,
+) -> syn::Result<Vec<TokenStream2>> {
+    let mut ret = vec![];
+    for (i, (field, access)) in fields.iter().zip(access).enumerate() {
+        let opts = field_opts(field)?;
+        if opts.skip || (opts.buffer && !buffers) {
+            continue;
+        }
+        // The tensors of a buffer field are buffers
+        let method = if opts.buffer {
+            format_ident!("{}", method.to_string().replace("buffers", "parameters"))
+        } else {
+            method.clone()
+        };
+        let name = match (&opts.rename, &field.ident) {
+            (Some(n), _) => n.clone(),
+            (None, Some(ident)) => ident.to_string().trim_start_matches("r#").to_string(),
+            (None, None) => i.to_string(),
+        };
+        let prefix = if transparent {
+            quote!(prefix.to_string())
+        } else {
+            quote!(::storm::nn::module::join_name(prefix, #name))
+        };
+        ret.push(quote! {
+            ret.extend(::storm::nn::Module::#method(#access, &#prefix));
+        });
+    }
+    Ok(ret)
+}
+
+fn method_body(
+    input: &DeriveInput,
+    method: &Ident,
+    buffers: bool,
+    mutable: bool,
+) -> syn::Result<TokenStream2> {
+    let rf = if mutable { quote!(&mut) } else { quote!(&) };
+    match &input.data {
+        Data::Struct(s) => {
+            let access = match &s.fields {
+                Fields::Named(f) => f
+                    .named
+                    .iter()
+                    .map(|f| {
+                        let ident = f.ident.as_ref().unwrap();
+                        quote!(#rf self.#ident)
+                    })
+                    .collect::<Vec<_>>(),
+                _ => (0..s.fields.len())
+                    .map(|i| {
+                        let i = syn::Index::from(i);
+                        quote!(#rf self.#i)
+                    })
+                    .collect(),
+            };
+            let stmts = extend_fields(&s.fields, &access, method, buffers, false)?;
+            Ok(quote! {
+                let mut ret = vec![];
+                #(#stmts)*
+                ret
+            })
+        }
+        Data::Enum(e) => {
+            let mut arms = vec![];
+            for variant in e.variants.iter() {
+                let ident = &variant.ident;
+                let bindings = (0..variant.fields.len())
+                    .map(|i| format_ident!("f{i}"))
+                    .collect::<Vec<_>>();
+                let access = bindings.iter().map(|b| quote!(#b)).collect::<Vec<_>>();
+                // A variant wrapping a single module is named like the module itself
+                let transparent = variant.fields.len() == 1;
+                let stmts = extend_fields(&variant.fields, &access, method, buffers, transparent)?;
+                let pattern = match &variant.fields {
+                    Fields::Named(f) => {
+                        let names = f.named.iter().map(|f| f.ident.as_ref().unwrap());
+                        quote!(Self::#ident { #(#names: #bindings),* })
+                    }
+                    Fields::Unnamed(_) => quote!(Self::#ident(#(#bindings),*)),
+                    Fields::Unit => quote!(Self::#ident),
+                };
+                arms.push(quote!(#pattern => { #(#stmts)* }));
+            }
+            Ok(quote! {
+                let mut ret = vec![];
+                #[allow(unused_variables)]
+                match self {
+                    #(#arms)*
+                }
+                ret
+            })
+        }
+        Data::Union(_) => Err(syn::Error::new(
+            Span::call_site(),
+            "Module can not be derived for unions",
+        )),
+    }
+}
+
+// Implements `storm::nn::Module` by chaining the fields, each named after the field. Every field
+// has to implement `Module`, config that holds no tensors needs `#[module(skip)]`.
+// `#[module(buffer)]` marks state that is saved but not trained and `#[module(rename = "...")]`
+// changes the name a field is saved under.
+#[proc_macro_derive(Module, attributes(module))]
+pub fn derive_module(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let methods = [
+        ("named_parameters", false, false),
+        ("named_parameters_mut", false, true),
+        ("named_buffers", true, false),
+        ("named_buffers_mut", true, true),
+    ];
+    let mut fns = vec![];
+    for (method, buffers, mutable) in methods {
+        let method = Ident::new(method, Span::call_site());
+        let body = match method_body(&input, &method, buffers, mutable) {
+            Ok(b) => b,
+            Err(e) => return e.to_compile_error().into(),
+        };
+        let (rf, ret) = if mutable {
+            (quote!(&mut self), quote!(&mut ::storm::tensor::Tensor))
+        } else {
+            (quote!(&self), quote!(&::storm::tensor::Tensor))
+        };
+        fns.push(quote! {
+            fn #method(#rf, prefix: &str) -> Vec<(String, #ret)> {
+                #body
+            }
+        });
+    }
+    quote! {
+        impl #impl_generics ::storm::nn::Module for #name #ty_generics #where_clause {
+            #(#fns)*
+        }
+    }
+    .into()
+}
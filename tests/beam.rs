@@ -0,0 +1,39 @@
+// Own test binary, BEAM is read from the environment every time a kernel is built.
+use storm::codegen::kernel::{Opt, OptOps};
+use storm::codegen::linearizer::Linearizer;
+use storm::codegen::optimizer::beam_search;
+use storm::prelude::*;
+
+#[test]
+fn beam_matmul() {
+    let dir = std::env::temp_dir().join(format!("storm_beam_{}", std::process::id()));
+    std::env::set_var("CACHE_DIR", &dir);
+    let a = Tensor::from(v![i as f32, for i in 0..64]).reshape([8, 8]);
+    let b = Tensor::from(v![(i % 5) as f32, for i in 0..64]).reshape([8, 8]);
+    let expected = v![
+        v![(k + 8 * i) as f32 * ((k * 8 + j) % 5) as f32, for k in 0..8].iter().sum::<f32>(),
+        for j in 0..8, for i in 0..8
+    ];
+    assert!(a.matmul(&b).to_vec() == expected);
+    // The kernel built without BEAM is not reused once it is set
+    std::env::set_var("BEAM", "2");
+    assert!(a.matmul(&b).to_vec() == expected);
+    assert!(std::fs::read_dir(dir.join(DEVICE.name().to_lowercase()).join("beam")).is_ok());
+
+    // The winner's opts are kept on the kernel
+    std::env::set_var("IGNORE_BEAM_CACHE", "1");
+    let t = a.sum([1], false);
+    let si = t.buffer.schedule(&mut Default::default()).pop_back().unwrap();
+    let lin = beam_search(&**DEVICE, si.ast.clone(), 2);
+    assert!(lin.kernel.applied_opts_cache.as_ref() == Some(&lin.kernel.applied_opts));
+
+    // An opt that doesn't apply is an error and leaves the kernel as it was
+    let mut k = Linearizer::new(si.ast, Some(DEVICE.linearizer_opts())).kernel;
+    let before = format!("{:?}", (&k.sts, k.upcasted, k.local_dims));
+    for (op, axis, amt) in [(OptOps::UPCAST, 7, 2), (OptOps::UPCAST, 0, 3), (OptOps::PADTO, 0, 64)] {
+        assert!(k.try_apply_opt(Opt { op, axis: Some(axis), amt: Some(amt) }).is_err());
+    }
+    assert!(before == format!("{:?}", (&k.sts, k.upcasted, k.local_dims)));
+    assert!(k.applied_opts.is_empty());
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,60 @@
+// Own test binary, CACHE_DIR and DISKCACHE are read from the environment.
+use storm::device::diskcache::{self, DiskCacheEntry};
+use storm::prelude::*;
+
+fn f32s(b: &[u8]) -> Vec<f32> {
+    v![f32::from_le_bytes(x.try_into().unwrap()), for x in b.chunks(4)]
+}
+
+#[test]
+fn clang_roundtrip() {
+    let dir = std::env::temp_dir().join(format!("storm_diskcache_{}", std::process::id()));
+    std::env::set_var("CACHE_DIR", &dir);
+    let device = get_device("CLANG").unwrap();
+    let src = "void add_one(void** bufs, const int* gid, const int* lid) {
+  float* out = bufs[0];
+  const float* in = bufs[1];
+  out[gid[0]] = in[gid[0]] + 1.0f;
+}";
+    let entry = DiskCacheEntry {
+        name: "add_one".into(),
+        prg_str: src.into(),
+        global_size: vec![4, 1, 1],
+        local_size: vec![1, 1, 1],
+        binary: device.compile("add_one", src).unwrap().unwrap(),
+    };
+    diskcache::store(&*device, "key", &entry);
+    assert!(diskcache::load(&*device, "other key").is_none());
+    let loaded = diskcache::load(&*device, "key").unwrap();
+    assert!(loaded.name == entry.name && loaded.prg_str == entry.prg_str);
+    assert!(loaded.global_size == entry.global_size && loaded.local_size == entry.local_size);
+    assert!(loaded.binary == entry.binary);
+
+    let prg = device.load(&loaded.name, &loaded.binary).unwrap();
+    let (out, inp) = (device.alloc(4, float32), device.alloc(4, float32));
+    device.copyin(&v![x.to_le_bytes(), for x in [1f32, 2., 3., 4.]].concat(), &*inp);
+    prg.run(&[out.clone(), inp], &loaded.global_size, Some(&loaded.local_size), &[], &[]);
+    assert!(f32s(&out.to_cpu()) == [2., 3., 4., 5.]);
+
+    // A corrupt entry is a miss and gets removed, here global_size's length is cut to 20 bytes
+    let file = std::fs::read_dir(dir.join("clang")).unwrap().next().unwrap().unwrap().path();
+    let mut bytes = std::fs::read(&file).unwrap();
+    let sizes = [24u64.to_le_bytes(), 4u64.to_le_bytes()].concat();
+    let at = bytes.windows(16).position(|w| w == sizes).unwrap();
+    bytes[at] = 20;
+    std::fs::write(&file, bytes).unwrap();
+    assert!(diskcache::load(&*device, "key").is_none() && !file.exists());
+
+    // A binary that doesn't load is an error, not a panic
+    assert!(device.load("add_one", b"not a shared object").is_err());
+    assert!(get_device("INTERPRETER").unwrap().load("add_one", &loaded.binary).is_err());
+
+    // Kernels come back from disk with the in-memory cache emptied
+    std::env::set_var("DISKCACHE", "1");
+    let t = Tensor::from([1., 2., 3.]).to("CLANG");
+    assert!((&t * 2.0 + 1.0).to_vec() == [3., 5., 7.]);
+    storm::lazy::KERNEL_CACHED.lock().unwrap().clear();
+    assert!((&t * 2.0 + 1.0).to_vec() == [3., 5., 7.]);
+    assert!(std::fs::read_dir(dir.join("clang")).unwrap().count() > 0);
+    std::fs::remove_dir_all(&dir).ok();
+}
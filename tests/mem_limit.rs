@@ -0,0 +1,30 @@
+// Own test binary, MEM_LIMIT applies to every schedule the process runs.
+use storm::prelude::*;
+
+#[test]
+fn mem_limit() {
+    let a = Tensor::from(v![i as f32 / 16., for i in 0..16]).reshape([4, 4]).realize();
+    let chain = || {
+        let mut hs = vec![a.clone()];
+        for _ in 0..4 {
+            hs.push(hs.last().unwrap().matmul(&a).relu());
+        }
+        hs
+    };
+    let peak = *chain()[4].memory_plan().peak.values().next().unwrap();
+    std::env::set_var("MEM_LIMIT", peak.to_string());
+    // Nothing else holds the intermediates, so they share as planned
+    let h = chain().pop().unwrap();
+    assert!(h.try_realize().is_ok());
+
+    // Held intermediates can't be written over, the extra buffer is counted against the limit
+    let hs = chain();
+    assert!(matches!(
+        hs[4].try_realize(),
+        Err(StormError::OutOfMemory { bytes, .. }) if bytes == peak + 64
+    ));
+    std::env::set_var("MEM_LIMIT", (peak + 64).to_string());
+    let hs = chain();
+    assert!(hs[4].try_realize().is_ok());
+    std::env::remove_var("MEM_LIMIT");
+}
@@ -0,0 +1,45 @@
+// Own test binary, the allocator's counters are shared by the whole process.
+use storm::prelude::*;
+
+#[test]
+fn allocator_stats() {
+    let device = DEVICE.name();
+    let stats = || storm::memory_stats().remove(&device).unwrap_or_default();
+    let start = stats();
+    let a = DEVICE.alloc(1000, float32);
+    let s = stats();
+    assert!(s.in_use == start.in_use + 4000 && s.allocs == start.allocs + 1);
+    assert!(s.peak >= s.in_use);
+    drop(a);
+    let s = stats();
+    assert!(s.in_use == start.in_use && s.cached == start.cached + 4000);
+
+    // Same size comes back out of the cache
+    let b = DEVICE.alloc(1000, float32);
+    let s = stats();
+    assert!(s.cache_hits == start.cache_hits + 1 && s.cached == start.cached);
+    drop(b);
+    storm::empty_cache();
+    assert!(stats().cached == 0);
+
+    // Over the cap the least recently freed go first
+    let limit = storm::cache_limit();
+    storm::set_cache_limit(10000);
+    let bufs = v![DEVICE.alloc(1000 + i, float32), for i in 0..4];
+    drop(bufs);
+    assert!(stats().cached == 4008 + 4012);
+    let allocs = stats().allocs;
+    let c = DEVICE.alloc(1000, float32);
+    let d = DEVICE.alloc(1003, float32);
+    assert!(stats().allocs == allocs + 1 && stats().cached == 4008);
+    drop((c, d));
+    storm::set_cache_limit(limit);
+
+    let before = stats();
+    let t = Tensor::from(v![i as f32, for i in 0..256]);
+    assert!((&t + &t).sum([], false).to_vec() == [65280.]);
+    let after = stats();
+    assert!(after.peak >= before.in_use + 1024 && after.allocs > before.allocs);
+    storm::empty_cache();
+    assert!(stats().cached == 0);
+}
@@ -354,3 +354,17 @@ mod test_index_expr_2d {
         s.teardown();
     }
 }
+
+#[test]
+fn test_reshape_flipped() {
+    let st = ShapeTracker::from_shape(&[2, 3]).stride(&[-1, -1]);
+    let st = st.reshape(&[6]).reshape(&[2, 3]);
+    assert!(st.views.len() == 1);
+    assert!(st.views[0].strides == [-3, -1] && st.views[0].offset == 5, "{st:?}");
+}
+
+#[test]
+fn test_flip_shrunk() {
+    let st = ShapeTracker::from_shape(&[2, 2, 2]).shrink(&[(1, 2), (0, 2), (0, 2)]).stride(&[1, -1, 1]);
+    assert!(st.views[0].strides == [0, -2, 1] && st.views[0].offset == 6, "{st:?}");
+}
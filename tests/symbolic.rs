@@ -343,6 +343,13 @@ fn test_mod_factor_negative() {
     );
 }
 
+#[test]
+fn test_mod_negative_mul() {
+    // The terms that are multiples of 2 all drop out, including the negative one
+    helper_test_variable(sum(&[num(6), var("a", 0, 1) * -2, var("b", 0, 1)]) % 2, 0, 1, "b");
+    helper_test_variable(var("a", 0, 3) * -3 % 3, 0, 0, "0");
+}
+
 #[test]
 fn test_sum_combine_num() {
     helper_test_variable(sum(&[num(29), var("a", 0, 10), num(-23)]), 6, 16, "(6+a)");
@@ -1,3 +1,5 @@
+// These run on whichever backend `DEVICE` picks, `DEVICE=INTERPRETER` checks them against the
+// reference uop interpreter.
 use storm::{prelude::*, nn::Conv2d};
 
 #[test]
@@ -189,7 +191,7 @@ fn conv2d() {
         "{r:?}"
     );
 
-    let a = Conv2d::new(cin, cout, conv, None, [0], None, None, None);
+    let a = Conv2d::new(cin, cout, conv, 1, [0], 1, None, None);
     let t = Tensor::randn([1,3,28,28]);
     assert!(a.call(&t).to_vec() == t.conv2d(&a.weights).to_vec())
 }
@@ -287,5 +289,775 @@ fn pad2d() {
             0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0.,
             0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0., 0.
         ]
-    )
+    );
+    assert!(Tensor::from([1., 2.]).pad([(1, 2)], -1.).to_vec() == [-1., 1., 2., -1., -1.]);
+    assert!(Tensor::from([1., 2.]).pad2d([1, 0], f32::NEG_INFINITY).to_vec() == [f32::NEG_INFINITY, 1., 2.]);
+}
+
+#[test]
+fn to_device() {
+    let a = Tensor::from([1., 2., 3.]).to("INTERPRETER");
+    assert!(a.device() == "INTERPRETER");
+    let b = (a * 2.0 + 1.0).to(&DEVICE.name());
+    assert!(b.device() == DEVICE.name());
+    approx_eq!(b, [3., 5., 7.]);
+    // get_device opens only the backend registered under a name, which must report that name
+    for (name, _) in storm::device::DEVICES.iter() {
+        if let Ok(device) = get_device(name) {
+            assert!(device.name() == *name);
+        }
+    }
+}
+
+#[test]
+fn jit_replay() {
+    let w = std::cell::RefCell::new(Tensor::from([1., 2., 3.]).realize());
+    let mut jit = TensorJit::new(|x: &[Tensor]| {
+        let mut w = w.borrow_mut();
+        let out = (&*w * &x[0]).sum([], false);
+        let new = (&*w + &(&x[0] * 2.0)).realize();
+        w.assign(new);
+        vec![out]
+    });
+    let mut expected_w = [1f32, 2., 3.];
+    for i in 0..6 {
+        let x = [i as f32, i as f32 + 1., i as f32 + 2.];
+        let out = jit.call(&[Tensor::from(x)]);
+        let expected_out = izip!(expected_w.iter(), x.iter()).map(|(a, b)| a * b).sum::<f32>();
+        expected_w = [0, 1, 2].map(|j| expected_w[j] + x[j] * 2.);
+        assert!(out[0].to_vec() == [expected_out]);
+        assert!(w.borrow().to_vec() == expected_w);
+    }
+}
+
+#[test]
+fn jit_rejects_rand() {
+    let err = std::cell::RefCell::new(None);
+    let mut jit = TensorJit::new(|x: &[Tensor]| match (&x[0] + &Tensor::rand([3])).try_realize() {
+        Ok(out) => vec![out],
+        Err(e) => {
+            *err.borrow_mut() = Some(e);
+            vec![x[0].clone()]
+        }
+    });
+    for _ in 0..2 {
+        jit.call(&[Tensor::from([1., 2., 3.])]);
+    }
+    assert!(matches!(err.into_inner(), Some(StormError::InvalidArgument { op: "jit", .. })));
+}
+
+#[test]
+fn state_dict_roundtrip() {
+    use storm::nn::{state::safe_load, BatchNorm2d, Linear};
+    let path = std::env::temp_dir().join(format!("storm_state_{}.safetensors", std::process::id()));
+    let l = Linear::new(3, 2, None);
+    l.save(&path).unwrap();
+    let mut l2 = Linear::new(3, 2, None);
+    assert!(l2.load(&path, true).unwrap() == Default::default());
+    assert!(l.weights.to_vec() == l2.weights.to_vec());
+    assert!(l.bias.unwrap().to_vec() == l2.bias.unwrap().to_vec());
+
+    let mut state = safe_load(&path).unwrap();
+    assert!(state["weight"].device() == "DISK");
+    assert!(state["weight"].to(&DEVICE.name()).to_vec() == l.weights.to_vec());
+    // Disk tensors are only read, kernels on them are an error instead of a missing device
+    assert!(get_device("DISK").unwrap().name() == "DISK");
+    let doubled = (&state["weight"] * 2.0).try_realize();
+    assert!(matches!(doubled, Err(StormError::InvalidArgument { op: "DISK", .. })));
+    state.insert("extra".into(), Tensor::from([1., 2.]).cast(float16));
+    state.insert("weight".into(), Tensor::ones([2, 3]).cast(float16));
+    state.remove("bias");
+    let mut l3 = Linear::new(3, 2, None);
+    assert!(l3.load_state_dict(state.clone(), true).is_err());
+    let report = l3.load_state_dict(state, false).unwrap();
+    assert!(report.missing == ["bias"] && report.unexpected == ["extra"]);
+    assert!(l3.weights.dtype() == float32 && l3.weights.to_vec() == [1.; 6]);
+
+    let bn = BatchNorm2d::new(4, None, None, None, None);
+    assert!(bn.state_dict().len() == 5);
+    std::fs::remove_file(&path).ok();
+}
+
+#[test]
+fn try_errors() {
+    let a = Tensor::from(v![i as f32, for i in 0..6]);
+    assert!(matches!(a.try_reshape([4, -1]), Err(StormError::ShapeMismatch { op: "reshape", .. })));
+    assert!(matches!(a.try_reshape([-1, -1]), Err(StormError::ShapeMismatch { .. })));
+    assert!(a.try_reshape([-1, 2]).unwrap().shape().dims == [3, 2]);
+    let b = Tensor::ones([4, 2]);
+    assert!(matches!(a.reshape([2, 3]).try_matmul(&b), Err(StormError::ShapeMismatch { op: "matmul", .. })));
+    assert!(a.reshape([3, 2]).try_matmul(&b.reshape([2, 4])).unwrap().try_realize().is_ok());
+    assert!(matches!(DEVICE.try_build("bad", "not a kernel"), Err(StormError::CompileError { .. }) | Err(StormError::Internal(_))));
+    assert!(matches!(get_device("NOPE"), Err(StormError::NoDevice(_))));
+}
+
+#[test]
+fn optimizers() {
+    // The model is moved after its weights are registered
+    struct Trainer<O: Optimizer> {
+        w: Tensor,
+        optim: O,
+    }
+    fn trainer<O: Optimizer>(make: impl Fn(&mut Tensor) -> O) -> Trainer<O> {
+        let mut w = Tensor::from([1., 2., 3.]);
+        let optim = make(&mut w);
+        Trainer { w, optim }
+    }
+    fn step<O: Optimizer>(t: &mut Trainer<O>) {
+        let mut loss = (&t.w * &Tensor::from([1., -2., 0.5])).sum([], false);
+        t.optim.zero_grad();
+        loss.backward();
+        t.optim.step();
+    }
+    let close = |t: &Tensor, expected: [f32; 3]| {
+        izip!(t.to_vec(), expected).all(|(a, b)| (a - b).abs() < 1e-5)
+    };
+
+    // b = 0.9 * b + g, w -= 0.1 * (g + 0.9 * b)
+    let mut t = trainer(|w| SGD::new([w], 0.1, 0.9, 0.0, true));
+    let kept = t.w.clone();
+    step(&mut t);
+    step(&mut t);
+    let s = 1.9 + 2.71;
+    assert!(close(&t.w, [1. - 0.1 * s, 2. + 0.2 * s, 3. - 0.05 * s]));
+    // Clones of the model tensor share the updated buffer
+    assert!(close(&kept, [1. - 0.1 * s, 2. + 0.2 * s, 3. - 0.05 * s]));
+
+    // The first adam step is lr * sign(g), the decay is applied to the weights directly
+    let mut t = trainer(|w| adamw([w], 0.01, 0.1));
+    step(&mut t);
+    assert!(close(&t.w, [1. - 0.01 * 1.1, 2. + 0.01 * 0.8, 3. - 0.01 * 1.3]));
+
+    // v = 0.01 * g * g on the first step
+    let mut t = trainer(|w| rmsprop([w], 0.01));
+    step(&mut t);
+    assert!(close(&t.w, [0.9, 2.1, 2.9]));
+}
+
+#[test]
+fn batchnorm() {
+    use storm::nn::{BatchNorm1d, BatchNorm2d};
+    let close = |a: Vec<f32>, b: &[f32]| izip!(a, b).all(|(a, b)| (a - b).abs() < 1e-4);
+    let x = Tensor::from(v![i as f32, for i in 0..8]).reshape([2, 2, 1, 2]);
+    let mut bn = BatchNorm2d::new(2, None, None, None, None);
+
+    let prev = Tensor::set_training(true);
+    let out = bn.call(&x);
+    let std = (4.25f32 + 1e-5).sqrt();
+    assert!(close(out.to_vec(), &v![(i as f32 - [2.5, 4.5][i / 2 % 2]) / std, for i in 0..8]));
+    let running_var = 0.9 + 0.1 * 4.25 * 4. / 3.;
+    assert!(close(bn.running_mean.to_vec(), &[0.25, 0.45]));
+    assert!(close(bn.running_var.to_vec(), &[running_var; 2]));
+    assert!(bn.num_batches_tracked.to_vec() == [1.]);
+    assert!(x.dropout(Some(0.5)).to_vec() != x.to_vec());
+
+    Tensor::set_training(false);
+    let out = bn.call(&x);
+    let std = (running_var + 1e-5).sqrt();
+    assert!(close(out.to_vec(), &v![(i as f32 - [0.25, 0.45][i / 2 % 2]) / std, for i in 0..8]));
+    assert!(bn.num_batches_tracked.to_vec() == [1.]);
+    assert!(x.dropout(Some(0.5)).to_vec() == x.to_vec());
+
+    let mut bn1 = BatchNorm1d::new(2, None, None, None, None);
+    Tensor::set_training(true);
+    let out = bn1.call(&x.reshape([4, 2]));
+    assert!(close(out.mean([0], false).to_vec(), &[0., 0.]));
+    Tensor::set_training(prev);
+}
+
+#[test]
+#[should_panic(expected = "more than 1 value per channel")]
+fn batchnorm_one_value_per_channel() {
+    use storm::nn::BatchNorm1d;
+    Tensor::set_training(true);
+    BatchNorm1d::new(2, None, None, None, None).call(&Tensor::from([1f32, 2.]).reshape([1, 2]));
+}
+
+#[test]
+fn module_derive() {
+    use storm::nn::{BatchNorm2d, Linear};
+    #[derive(Module)]
+    enum Block {
+        Linear(Linear),
+        Norm { norm: BatchNorm2d, scale: Tensor },
+    }
+    #[derive(Module)]
+    struct Net {
+        blocks: Vec<(Block, Option<Linear>)>,
+        #[module(rename = "head.weight")]
+        head: Tensor,
+        #[module(buffer)]
+        step: Tensor,
+        #[module(skip)]
+        config: Vec<usize>,
+        #[module(skip)]
+        hidden: usize,
+    }
+    let mut net = Net {
+        blocks: vec![
+            (Block::Linear(Linear::new(2, 2, None)), None),
+            (
+                Block::Norm { norm: BatchNorm2d::new(2, None, None, None, None), scale: Tensor::ones([2]) },
+                Some(Linear::new(2, 2, Some(false))),
+            ),
+        ],
+        head: Tensor::ones([2]),
+        step: Tensor::zeros([1]),
+        config: vec![1],
+        hidden: 2,
+    };
+    let names = v![k, for (k, _) in net.named_parameters("")];
+    assert!(
+        names
+            == [
+                "blocks.0.0.weight",
+                "blocks.0.0.bias",
+                "blocks.1.0.norm.weight",
+                "blocks.1.0.norm.bias",
+                "blocks.1.0.scale",
+                "blocks.1.1.weight",
+                "head.weight",
+            ]
+    );
+    let buffers = v![k, for (k, _) in net.named_buffers("model")];
+    assert!(
+        buffers
+            == [
+                "model.blocks.1.0.norm.running_mean",
+                "model.blocks.1.0.norm.running_var",
+                "model.blocks.1.0.norm.num_batches_tracked",
+                "model.step",
+            ]
+    );
+    assert!(net.parameters().len() == 7 && net.buffers().len() == 4);
+    assert!(net.config == [1] && net.hidden == 2);
+    assert!(net.state_dict().len() == 11);
+
+    let prev = Tensor::training();
+    net.train();
+    assert!(Tensor::training());
+    net.eval();
+    assert!(!Tensor::training());
+    Tensor::set_training(prev);
+}
+
+#[test]
+fn conv_transpose() {
+    use storm::nn::{Conv1d, Conv3d, ConvTranspose2d};
+    let close = |a: Vec<f32>, b: &[f32]| a.len() == b.len() && izip!(a, b).all(|(a, b)| (a - b).abs() < 1e-3);
+
+    // Per-axis stride, padding and output_padding, two groups of one channel
+    let x = Tensor::from(v![i as f32, for i in 1..19]).reshape([1, 2, 3, 3]);
+    let w = Tensor::from(v![i as f32 - 6., for i in 0..12]).reshape([2, 1, 2, 3]);
+    let r = x.conv_transpose2d(&w, None, 2, [2, 1], 1, [1, 0], [1, 0]);
+    assert!(r.shape() == [1, 2, 5, 5].into());
+    assert!(close(r.to_vec(), &[
+        -3., -8., -14., -8., -3., -24., -50., -77., -50., -24., -12., -23., -32., -17., -6.,
+        -42., -83., -122., -77., -36., -21., -38., -50., -26., -9., 30., 73., 130., 103., 60.,
+        0., 13., 40., 43., 30., 39., 94., 166., 130., 75., 0., 16., 49., 52., 36., 48., 115.,
+        202., 157., 90.,
+    ]));
+
+    // The transposed conv is the adjoint of the conv, so each is the gradient of the other
+    let mut x = Tensor::randn([1, 4, 7, 7]);
+    let mut y = Tensor::randn([1, 3, 4, 4]);
+    let w = Tensor::randn([3, 4, 3, 3]);
+    x.require_grad = true;
+    y.require_grad = true;
+    let mut loss = (x._conv2d(&w, None, 1, 2, 1, [1]) * &y.detach()).sum([], false);
+    loss.backward();
+    let xt = y.conv_transpose2d(&w, None, 1, 2, 1, 1, 0);
+    assert!(close(x.grad.lock().unwrap().as_ref().unwrap().to_vec(), &xt.to_vec()));
+    let mut loss = (y.conv_transpose2d(&w, None, 1, 2, 1, 1, 0) * &x.detach()).sum([], false);
+    loss.backward();
+    let yt = x._conv2d(&w, None, 1, 2, 1, [1]);
+    assert!(close(y.grad.lock().unwrap().as_ref().unwrap().to_vec(), &yt.to_vec()));
+
+    let mut c = Conv1d::new(2, 2, 3, 2, [1], 2, None, None);
+    c.weights = Tensor::from(v![i as f32 - 3., for i in 0..12]).reshape([2, 2, 3]);
+    let x = Tensor::from(v![i as f32, for i in 1..15]).reshape([1, 2, 7]);
+    assert!(close(c.call(&x).to_vec(), &[23., 17., -11., 179., 287., 193.]));
+
+    let c = Conv3d::new(2, 4, 3, [2, 1, 2], [1], 1, None, Some(true));
+    assert!(c.call(&Tensor::randn([1, 2, 5, 5, 5])).shape() == [1, 4, 3, 5, 3].into());
+    let up = ConvTranspose2d::new(4, 2, 3, 2, 1, [1, 0], [1, 2], None, None);
+    assert!(up.call(&Tensor::randn([2, 4, 5, 5])).shape() == [2, 2, 10, 11].into());
+    assert!(up.named_parameters("").len() == 2);
+}
+
+#[test]
+fn gather_scatter() {
+    let grad = |t: &Tensor| t.grad.lock().unwrap().as_ref().unwrap().to_vec();
+    let mut x = Tensor::from(v![i as f32, for i in 0..6]).reshape([2, 3]);
+    x.require_grad = true;
+    let idx = Tensor::from([2., 0., 1., 1.]).reshape([2, 2]);
+    let g = x.gather(1, &idx);
+    assert!(g.to_vec() == [2., 0., 4., 4.]);
+    assert!(x.gather(0, &Tensor::from([1., 0.]).reshape([1, 2])).to_vec() == [3., 1.]);
+    // Negative indices count from the end like in index, an int32 index picks the same
+    assert!(x.gather(1, &Tensor::from([-1., -3.]).reshape([2, 1])).to_vec() == [2., 3.]);
+    assert!(x.gather(1, &idx.cast(int32)).to_vec() == [2., 0., 4., 4.]);
+    g.sum([], false).backward();
+    assert!(grad(&x) == [1., 0., 1., 0., 2., 0.]);
+
+    let mut x = Tensor::from(v![i as f32, for i in 0..6]).reshape([2, 3]);
+    let mut src = Tensor::from([10., 20., 30., 40.]).reshape([2, 2]);
+    x.require_grad = true;
+    src.require_grad = true;
+    let s = x.scatter(1, &Tensor::from([2., 0., 1., 0.]).reshape([2, 2]), &src);
+    assert!(s.to_vec() == [20., 1., 10., 40., 30., 5.]);
+    s.sum([], false).backward();
+    assert!(grad(&x) == [0., 1., 0., 0., 0., 1.]);
+    assert!(grad(&src) == [1., 1., 1., 1.]);
+    assert!(x.scatter_add(1, &idx, &src).to_vec() == [20., 1., 12., 3., 74., 5.]);
+    let s = x.scatter_add(1, &Tensor::from([-1.]).reshape([1, 1]), &Tensor::from([10.]).reshape([1, 1]));
+    assert!(s.to_vec() == [0., 1., 12., 3., 4., 5.]);
+}
+
+#[test]
+fn fancy_index() {
+    use storm::tensor::IndexRange;
+    let t = Tensor::from(v![i as f32, for i in 0..24]).reshape([2, 3, 4]);
+    let r = t.index([IndexRange::from(1), (1..3).into(), (..).into()]);
+    assert!(r.shape() == [2, 4].into() && r.to_vec() == v![i as f32, for i in 16..24]);
+    assert!(t.index([-1]).to_vec() == v![i as f32, for i in 12..24]);
+
+    // The tensor indices replace their axis in place
+    let r = t.index([IndexRange::RangeFull, Tensor::from([2., 0.]).into()]);
+    assert!(r.shape() == [2, 2, 4].into());
+    assert!(r.to_vec() == [v![i as f32, for i in 8..12], v![i as f32, for i in 0..4], v![i as f32, for i in 20..24], v![i as f32, for i in 12..16]].concat());
+    let r = t.index([IndexRange::RangeFull, Tensor::from([0., 2.]).into(), Tensor::from([1., 3.]).into()]);
+    assert!(r.to_vec() == [1., 11., 13., 23.]);
+    let r = t.index([IndexRange::RangeFull, Tensor::from([0., 2.]).reshape([2, 1]).into(), Tensor::from([1., 3.]).into()]);
+    assert!(r.shape() == [2, 2, 2].into() && r.to_vec() == [1., 3., 9., 11., 13., 15., 21., 23.]);
+    // ...and go in front when they are apart, negative indices count from the end
+    let r = t.index([Tensor::from([1., 0.]).into(), IndexRange::RangeFull, Tensor::from([3., -1.]).into()]);
+    assert!(r.shape() == [2, 3].into() && r.to_vec() == [15., 19., 23., 3., 7., 11.]);
+
+    let mut emb = storm::nn::Embedding::new(4, 2);
+    emb.weight = Tensor::from(v![i as f32, for i in 0..8]).reshape([4, 2]);
+    emb.weight.require_grad = true;
+    let out = emb.call(&Tensor::from([3., 0., 3.]).reshape([1, 3]));
+    assert!(out.shape() == [1, 3, 2].into() && out.to_vec() == [6., 7., 0., 1., 6., 7.]);
+    out.sum([], false).backward();
+    assert!(emb.weight.grad.lock().unwrap().as_ref().unwrap().to_vec() == [1., 1., 0., 0., 0., 0., 2., 2.]);
+}
+
+#[test]
+fn pooling() {
+    let close = |a: Tensor, b: &[f32]| {
+        let a = a.to_vec();
+        a.len() == b.len() && izip!(a, b).all(|(a, b)| (a - b).abs() < 1e-4)
+    };
+    let x = Tensor::from(v![i as f32, for i in 1..26]).reshape([1, 1, 5, 5]);
+    assert!(close(x._max_pool2d(2, 2, 1, 0, true), &[7., 9., 10., 17., 19., 20., 22., 24., 25.]));
+    assert!(close(x._max_pool2d([2, 3], [1, 2], 1, 0, false), &[8., 10., 13., 15., 18., 20., 23., 25.]));
+    assert!(close(x._max_pool2d(2, 1, 2, 0, false), &[13., 14., 15., 18., 19., 20., 23., 24., 25.]));
+    // The padding never wins the max, not even over negative inputs
+    let r = (-&x)._max_pool2d(3, 2, 1, 1, false);
+    assert!(close(r, &[-1., -2., -4., -6., -7., -9., -16., -17., -19.]));
+
+    let r = x._avg_pool2d(3, 2, 1, 1, false, false);
+    assert!(close(r, &[4., 5.5, 7., 11.5, 13., 14.5, 19., 20.5, 22.]));
+    let r = x._avg_pool2d(3, 2, 1, 1, false, true);
+    assert!(close(r * 9., &[16., 33., 28., 69., 117., 87., 76., 123., 88.]));
+    // The windows ceil_mode adds only count what they cover of the padded input
+    assert!(close(x._avg_pool2d(2, 2, 1, 0, true, true), &[4., 6., 7.5, 14., 16., 17.5, 21.5, 23.5, 25.]));
+    let r = x._avg_pool2d(2, 2, 1, 1, true, true);
+    assert!(close(r, &[0.25, 1.25, 2.25, 4.25, 10., 12., 9.25, 20., 22.]));
+
+    assert!(close(x.adaptive_avg_pool2d([3, 2]), &[4.5, 6.5, 12., 14., 19.5, 21.5]));
+    assert!(close(x.adaptive_max_pool2d(2), &[13., 15., 23., 25.]));
+    let r = x.shrink([(0, 1), (0, 1), (0, 4), (0, 4)]).adaptive_avg_pool2d(1);
+    assert!(r.shape() == [1, 1, 1, 1].into() && close(r, &[10.]));
+
+    let y = Tensor::from(v![i as f32, for i in 0..6]).reshape([1, 1, 6]);
+    assert!(close(y._avg_pool::<1>(3, 3, 1, 0, false, true), &[1., 4.]));
+    let z = Tensor::randn([1, 2, 4, 6, 6]);
+    assert!(z._max_pool::<3>(2, 2, 1, 0, false).shape() == [1, 2, 2, 3, 3].into());
+    assert!(z.adaptive_avg_pool::<3>([1, 2, 3]).shape() == [1, 2, 1, 2, 3].into());
+
+    // Every input is in one 2x2 window and gets a quarter of its grad
+    let mut x = Tensor::randn([1, 2, 4, 4]);
+    x.require_grad = true;
+    x.avg_pool2d().sum([], false).backward();
+    assert!(close(x.grad.lock().unwrap().clone().unwrap(), &[0.25; 32]));
+}
+
+#[test]
+fn einsum() {
+    let close = |a: &Tensor, b: &Tensor| a.shape() == b.shape() && izip!(a.to_vec(), b.to_vec()).all(|(x, y)| (x - y).abs() < 1e-4);
+    let a = Tensor::from(v![i as f32, for i in 0..6]).reshape([2, 3]);
+    let b = Tensor::from(v![(i as f32) / 2., for i in 0..12]).reshape([3, 4]);
+    assert!(close(&Tensor::einsum("ij,jk->ik", &[&a, &b]), &a.matmul(&b)));
+    assert!(close(&Tensor::einsum("ij, jk", &[&a, &b]), &a.matmul(&b)));
+    assert!(close(&Tensor::einsum("ij,jk->ki", &[&a, &b]), &a.matmul(&b).transpose(0, 1)));
+    assert!(close(&Tensor::einsum("ij->ji", &[&a]), &a.transpose(0, 1)));
+    assert!(Tensor::einsum("ij->", &[&a]).to_vec() == [15.]);
+    assert!(Tensor::einsum("ij->j", &[&a]).to_vec() == [3., 5., 7.]);
+    assert!(Tensor::einsum("i,j->ij", &[&Tensor::from([1., 2.]), &Tensor::from([3., 4., 5.])]).to_vec() == [3., 4., 5., 6., 8., 10.]);
+
+    let m = Tensor::from(v![i as f32, for i in 0..9]).reshape([3, 3]);
+    assert!(Tensor::einsum("ii->i", &[&m]).to_vec() == [0., 4., 8.]);
+    assert!(Tensor::einsum("ii", &[&m]).to_vec() == [12.]);
+    let bm = Tensor::from(v![i as f32, for i in 0..18]).reshape([3, 2, 3]);
+    assert!(Tensor::einsum("ibi->b", &[&bm]).to_vec() == [0. + 7. + 14., 3. + 10. + 17.]);
+
+    let q = Tensor::from(v![(i % 7) as f32 / 7., for i in 0..48]).reshape([2, 2, 3, 4]);
+    let k = Tensor::from(v![(i % 5) as f32 / 5., for i in 0..80]).reshape([2, 2, 5, 4]);
+    assert!(close(&Tensor::einsum("bhqd,bhkd->bhqk", &[&q, &k]), &q.matmul(&k.transpose(-2, -1))));
+
+    let grad = |t: &Tensor| t.grad.lock().unwrap().as_ref().unwrap().to_vec();
+    let mut x = a.detach();
+    let mut y = b.detach();
+    x.require_grad = true;
+    y.require_grad = true;
+    Tensor::einsum("ij,jk->", &[&x, &y]).backward();
+    assert!(grad(&x) == v![v![b.to_vec()[j * 4..j * 4 + 4].iter().sum::<f32>(), for j in 0..3], for _ in 0..2].concat());
+    assert!(grad(&y) == v![[3., 5., 7.][j], for _ in 0..4, for j in 0..3]);
+
+    assert!(matches!(Tensor::try_einsum("ij,jk->ik", &[&a]), Err(StormError::InvalidArgument { op: "einsum", .. })));
+    assert!(matches!(Tensor::try_einsum("ij,jk->iz", &[&a, &b]), Err(StormError::InvalidArgument { .. })));
+    assert!(matches!(Tensor::try_einsum("ij,kj->ik", &[&a, &b]), Err(StormError::ShapeMismatch { op: "einsum", .. })));
+}
+
+#[test]
+fn sort() {
+    let t = Tensor::rand([3, 7]);
+    let data = t.to_vec();
+    let (values, idx) = t.sort(-1, false);
+    assert!(values.shape() == [3, 7].into() && idx.shape() == [3, 7].into());
+    let (values, idx) = (values.to_vec(), idx.to_vec_t::<i32>());
+    for r in 0..3 {
+        let row = &data[r * 7..r * 7 + 7];
+        let mut expected = row.to_vec();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!(values[r * 7..r * 7 + 7] == expected[..]);
+        assert!(v![row[i as usize], for &i in &idx[r * 7..r * 7 + 7]] == expected);
+    }
+    let (values, idx) = t.sort(0, true);
+    for c in 0..7 {
+        let mut expected = v![data[r * 7 + c], for r in 0..3];
+        expected.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        assert!(v![values.to_vec()[r * 7 + c], for r in 0..3] == expected);
+        assert!(v![data[i as usize * 7 + c], for i in v![idx.to_vec_t::<i32>()[r * 7 + c], for r in 0..3]] == expected);
+    }
+
+    // Ties keep their order
+    let t = Tensor::from([3., 1., 3., 2., 1.]);
+    assert!(t.argsort(0, false).to_vec_t::<i32>() == [1, 4, 3, 0, 2]);
+    assert!(t.argsort(0, true).to_vec_t::<i32>() == [0, 2, 3, 1, 4]);
+    let data = v![(i * 7 % 5) as f32, for i in 0..13];
+    for descending in [false, true] {
+        let mut expected = v![i, for i in 0..13];
+        expected.sort_by(|&a, &b| {
+            let (a, b) = (data[a as usize], data[b as usize]);
+            if descending { b.total_cmp(&a) } else { a.total_cmp(&b) }
+        });
+        assert!(Tensor::from(data.clone()).argsort(0, descending).to_vec_t::<i32>() == expected);
+    }
+
+    let (values, idx) = t.topk(2, 0, true);
+    assert!(values.to_vec() == [3., 3.] && idx.to_vec_t::<i32>() == [0, 2]);
+    let (values, idx) = t.topk(3, -1, false);
+    assert!(values.to_vec() == [1., 1., 2.] && idx.to_vec_t::<i32>() == [1, 4, 3]);
+    let m = Tensor::from([5., 2., 8., 1., 4., 7., 0., 9.]).reshape([2, 4]);
+    let (values, idx) = m.kthvalue(2, 1, false);
+    assert!(values.shape() == [2].into() && values.to_vec() == [2., 4.] && idx.to_vec_t::<i32>() == [1, 0]);
+    assert!(m.kthvalue(1, 0, true).0.shape() == [1, 4].into());
+
+    assert!(idx.dtype() == int32);
+    // Padding takes the dtype's extremes, integers have no infinity
+    assert!(int32.min_max() == (i32::MIN.to_string(), i32::MAX.to_string()));
+    assert!(float16.min_max() == ("-inf".to_string(), "inf".to_string()));
+
+    let mut x = Tensor::from([0.5, -1., 2., 0.]);
+    x.require_grad = true;
+    let (values, _) = x.topk(2, 0, true);
+    (values * Tensor::from([1., 10.])).sum([], false).backward();
+    assert!(x.grad.lock().unwrap().as_ref().unwrap().to_vec() == [10., 0., 1., 0.]);
+}
+
+#[test]
+fn random() {
+    use storm::random::{below, threefry2x32, threefry_stream};
+    // Random123 known answers
+    assert!(threefry2x32([0; 2], [0; 2]) == [0x6b200159, 0x99ba4efe]);
+    assert!(threefry2x32([u32::MAX; 2], [u32::MAX; 2]) == [0x1cb996fc, 0xbb002be7]);
+    assert!(threefry2x32([0x243f6a88, 0x85a308d3], [0x13198a2e, 0x03707344]) == [0xc4923a9c, 0x483df7a0]);
+
+    // Values only depend on the seed and the draw order, not on when or where they are realized,
+    // and the kernel computes the same words as the host
+    Tensor::manual_seed(7);
+    let a = Tensor::rand([3, 5]);
+    let b = Tensor::rand([3, 5]);
+    let (b, a) = (b.to_vec(), a.to_vec());
+    let expected = v![(w >> 8) as f32 / (1 << 24) as f32, for w in threefry_stream(7, 0, 15)];
+    assert!(a == expected && b != a);
+    let expected = v![(w >> 8) as f32 / (1 << 24) as f32, for w in threefry_stream(7, 15, 15)];
+    assert!(b == expected);
+    assert!(a.iter().all(|&x| (0.0..1.0).contains(&x)));
+    Tensor::manual_seed(7);
+    assert!(Tensor::rand([3, 5]).to_vec() == a && Tensor::rand([3, 5]).to_vec() == b);
+    Tensor::manual_seed(8);
+    assert!(Tensor::rand([3, 5]).to_vec() != a);
+
+    let r = Tensor::randint([1000], -3, 4).to_vec();
+    assert!(r.iter().all(|&x| x.fract() == 0. && (-3. ..4.).contains(&x)));
+    assert!((-3..4).all(|i| r.contains(&(i as f32))));
+    Tensor::manual_seed(5);
+    let r = Tensor::randint([64], -1 << 20, 3 << 20).to_vec();
+    let expected = v![((-1 << 20) + below(w, 4 << 20) as i64) as f32, for w in threefry_stream(5, 0, 64)];
+    assert!(r == expected);
+
+    assert!(Tensor::zeros([64]).bernoulli().to_vec().iter().all(|&x| x == 0.));
+    assert!(Tensor::ones([64]).bernoulli().to_vec().iter().all(|&x| x == 1.));
+    let mean = Tensor::full([2000], 0.3).bernoulli().mean([], false).to_vec()[0];
+    assert!((0.25..0.35).contains(&mean), "{mean}");
+
+    let s = Tensor::from([0., 1., 0., 3.]).multinomial(2000, true).to_vec_t::<i32>();
+    assert!(s.len() == 2000 && s.iter().all(|&x| x == 1 || x == 3));
+    let threes = s.iter().filter(|&&x| x == 3).count() as f32 / 2000.;
+    assert!((0.7..0.8).contains(&threes), "{threes}");
+    let w = Tensor::from([1., 0., 2., 0., 5., 0., 1., 1., 1., 0.]).reshape([2, 5]);
+    let s = w.multinomial(3, false);
+    assert!(s.shape() == [2, 3].into());
+    let s = s.to_vec_t::<i32>();
+    for (r, expected) in [[0, 2, 4], [1, 2, 3]].iter().enumerate() {
+        let mut row = s[r * 3..r * 3 + 3].to_vec();
+        row.sort();
+        assert!(row == expected);
+    }
+    assert!(w.multinomial(4, true).shape() == [2, 4].into());
+
+    Tensor::manual_seed(3);
+    let mut p = Tensor::randperm(10).to_vec();
+    Tensor::manual_seed(3);
+    assert!(Tensor::randperm(10).to_vec() == p);
+    p.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert!(p == v![i as f32, for i in 0..10]);
+
+    let prev = Tensor::set_training(true);
+    let x = Tensor::ones([4, 8]);
+    Tensor::manual_seed(11);
+    let d = x.dropout(Some(0.5)).to_vec();
+    Tensor::manual_seed(11);
+    assert!(x.dropout(Some(0.5)).to_vec() == d);
+    Tensor::set_training(prev);
+}
+
+#[test]
+fn no_grad() {
+    let mut x = Tensor::from([1., 2., 3.]);
+    x.require_grad = true;
+    {
+        let _guard = Tensor::no_grad();
+        let y = (&x * &x).sum([], false);
+        assert!(!y.require_grad && y._ctx.is_none() && !Tensor::grad_enabled());
+        assert!(y.to_vec() == [14.]);
+    }
+    assert!(Tensor::grad_enabled());
+    let mut y = (&x * &x).sum([], false);
+    assert!(y.require_grad);
+    y.backward();
+    assert!(x.grad.lock().unwrap().as_ref().unwrap().to_vec() == [2., 4., 6.]);
+
+    // Guards nest and restore the outer setting
+    let outer = Tensor::no_grad();
+    drop(Tensor::no_grad());
+    assert!(!Tensor::grad_enabled());
+    drop(outer);
+    assert!(Tensor::grad_enabled());
+
+    // A frozen tensor stays frozen when handed to an optimizer, which then skips it
+    let mut w = Tensor::from([1., 2.]);
+    let mut frozen = Tensor::from([3., 4.]);
+    frozen.requires_grad_(false);
+    let mut optim = sgd([&mut w, &mut frozen], 0.5);
+    assert!(w.require_grad && !frozen.require_grad && optim.params().len() == 1);
+    let mut loss = (&w * &frozen).sum([], false);
+    assert!(loss.require_grad);
+    optim.zero_grad();
+    loss.backward();
+    optim.step();
+    assert!(w.to_vec() == [-0.5, 0.] && frozen.to_vec() == [3., 4.]);
+    assert!(frozen.grad.lock().unwrap().is_none());
+}
+
+#[test]
+fn autograd() {
+    use storm::autograd::{grad, hvp, jvp, try_grad, vjp};
+    let close = |a: &[f32], b: &[f32]| a.len() == b.len() && izip!(a, b).all(|(a, b)| (a - b).abs() < 1e-4);
+
+    // d/dx x^3 = 3x^2, d2/dx2 = 6x
+    let mut x = Tensor::from([1., 2., 3.]);
+    x.require_grad = true;
+    let y = (&x * &x * &x).sum([], false);
+    let g = grad(&[&y], &[&x], None, true).remove(0);
+    assert!(g.require_grad && close(&g.to_vec(), &[3., 12., 27.]));
+    let gg = grad(&[&g.sum([], false)], &[&x], None, false).remove(0);
+    assert!(!gg.require_grad && close(&gg.to_vec(), &[6., 12., 18.]));
+    assert!(x.grad.lock().unwrap().is_none());
+
+    // Matches backward on first order
+    let mut a = Tensor::from([0.5, -1., 2., 0.25]).reshape([2, 2]);
+    let mut b = Tensor::from([1., 0.5, -0.5, 2.]).reshape([2, 2]);
+    a.require_grad = true;
+    b.require_grad = true;
+    let f = |a: &Tensor, b: &Tensor| ((a.sin() + 2.0) * b.exp() / (a.matmul(b).relu() + 1.0)).log().max([1], false).sum([], false);
+    let mut out = f(&a, &b);
+    let gs = grad(&[&out], &[&a, &b], None, false);
+    out.backward();
+    assert!(close(&gs[0].to_vec(), &a.grad.lock().unwrap().as_ref().unwrap().to_vec()));
+    assert!(close(&gs[1].to_vec(), &b.grad.lock().unwrap().as_ref().unwrap().to_vec()));
+
+    // H v of sum(sigmoid(x)) with v = 1 is sigmoid''(x) = s(1 - s)(1 - 2s)
+    let (_, h) = hvp(|x| x[0].sigmoid().sum([], false), &[&x], &[&Tensor::ones([3])]);
+    let s = v![1. / (1. + (-x).exp()), for x in [1f32, 2., 3.]];
+    assert!(close(&h[0].to_vec(), &v![s * (1. - s) * (1. - 2. * s), for s in s]));
+
+    // J t of x W is t W, v^T J of x * x is 2 x v
+    let w = Tensor::from([1., 2., 3., 4., 5., 6.]).reshape([2, 3]);
+    let t = Tensor::from([1., -1.]).reshape([1, 2]);
+    let (out, j) = jvp(|x| x[0].matmul(&w), &[&x.shrink([(0, 2)]).reshape([1, 2])], &[&t]);
+    assert!(close(&out.to_vec(), &[9., 12., 15.]) && close(&j.to_vec(), &[-3., -3., -3.]));
+    let (_, v) = vjp(|x| &x[0] * &x[0], &[&x], &Tensor::from([1., 0., -1.]));
+    assert!(close(&v[0].to_vec(), &[2., 0., -6.]));
+
+    // A gradient penalty trains the weights through the input gradient: with g = 1 W^T,
+    // d/dW sum(g^2) is 2 g broadcast over the columns
+    let mut w = Tensor::from([1., 2., 3., 4., 5., 6.]).reshape([2, 3]);
+    w.require_grad = true;
+    let (_, g) = vjp(|x| x[0].matmul(&w), &[&Tensor::from([0.5, -0.5]).reshape([1, 2])], &Tensor::ones([1, 3]));
+    let mut penalty = (&g[0] * &g[0]).sum([], false);
+    assert!(close(&penalty.to_vec(), &[36. + 225.]));
+    penalty.backward();
+    assert!(close(&w.grad.lock().unwrap().as_ref().unwrap().to_vec(), &[12., 12., 12., 30., 30., 30.]));
+
+    assert!(try_grad(&[&x], &[&x], None, false).is_err());
+    assert!(try_grad(&[&x], &[&x], Some(&[&Tensor::ones([2])]), false).is_err());
+    let unused = Tensor::from([1., 2.]);
+    assert!(grad(&[&y], &[&unused], None, false)[0].to_vec() == [0., 0.]);
+}
+
+#[test]
+fn export_graph() {
+    let a = Tensor::from([1., 2., 3., 4.]).reshape([2, 2]);
+    let y = (&a - &a.max([1], true).expand([2, 2])).exp().sum([], false);
+    let path = std::env::temp_dir().join(format!("storm_export_graph_{}.dot", std::process::id()));
+    y.export_graph(&path).unwrap();
+    let dot = std::fs::read_to_string(&path).unwrap();
+    // The max has to be realized before the rest can read it twice
+    assert!(dot.starts_with("digraph G {") && dot.contains("cluster_0") && dot.contains("cluster_1"));
+    assert!(dot.contains("Reduce(Max)\\n[2, 1] f32\\nkernel 0") && dot.contains("Reduce(Sum)\\n[1, 1] f32\\nkernel 1"));
+    assert!(dot.contains("Load(From)\\n[4] f32\\nrealized\", fillcolor=\"#FFFFa0\", style=\"filled,bold\""));
+
+    // Exporting doesn't realize anything
+    assert!(!y.buffer.is_realized());
+    y.realize();
+    y.export_graph(&path).unwrap();
+    let dot = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(!dot.contains("cluster") && dot.contains("realized"));
+}
+
+#[test]
+fn profiler() {
+    use storm::profiler;
+    let a = Tensor::from([1., 2., 3., 4., 5., 6.]).reshape([2, 3]);
+    let b = Tensor::from([1., 0., -1., 2., 0.5, 1.]).reshape([3, 2]);
+    a.realize();
+    b.realize();
+    profiler::start();
+    let c = a.matmul(&b).relu().sum([], false);
+    assert!(c.to_vec() == [25.5]);
+    let profile = profiler::stop();
+    assert!(!profile.kernels.is_empty() && profiler::stop().kernels.is_empty());
+    // The matmul is 2x2 outputs of 3 multiply-adds
+    assert!(profile.kernels.iter().map(|k| k.flops).sum::<f64>() >= 12.);
+    for k in profile.kernels.iter() {
+        assert!(k.sync_ms >= 0. && k.wall_ms >= k.sync_ms && k.mem > 0);
+    }
+    // A kernel faster than the clock has no rate rather than an infinite one
+    let mut fast = profile.kernels[0].clone();
+    fast.sync_ms = 0.;
+    assert!(fast.gflops() == 0. && fast.gbs() == 0.);
+    let fast = profiler::Profile { kernels: vec![fast] };
+    assert!(!fast.report().contains("inf") && !fast.report().contains("NaN"));
+    let report = profile.report();
+    assert!(report.starts_with("kernel") && report.contains("sync_ms") && report.contains("GFLOPS"));
+    assert!(report.lines().count() == profile.kernels.len() + 2);
+
+    let path = std::env::temp_dir().join(format!("storm_profiler_{}.json", std::process::id()));
+    profile.save_trace(&path).unwrap();
+    let trace = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(trace.starts_with("{\"traceEvents\":[") && trace.ends_with("]}"));
+    assert!(trace.matches("\"ph\":\"X\"").count() == profile.kernels.len());
+    assert!(trace.contains(&format!("\"args\":{{\"name\":\"{}\"}}", profile.kernels[0].device)));
+}
+
+#[test]
+fn memory_plan() {
+    let a = Tensor::from((0..16).map(|x| x as f32 / 16.).collect::<Vec<f32>>()).reshape([4, 4]);
+    a.realize();
+    let mut expected = vec![];
+    let mut h = a.clone();
+    for _ in 0..4 {
+        h = h.matmul(&a).relu().realize();
+        expected.push(h.to_vec());
+    }
+
+    let mut h = a.clone();
+    for _ in 0..4 {
+        h = h.matmul(&a).relu();
+    }
+    let plan = h.memory_plan();
+    // The third intermediate goes into the first's arena, the result keeps its own buffer
+    assert!(plan.assignment == [Some(0), Some(1), Some(0), None]);
+    assert!(plan.arenas[0].members == [0, 2] && plan.arenas[0].bytes() == 64);
+    let (device, &peak) = plan.peak.iter().next().unwrap();
+    // a and two 4x4s at every step, against a and all four without sharing
+    assert!(peak == 3 * 64 && plan.unshared[device] == 5 * 64);
+    assert!(plan.check(peak).is_ok());
+    assert!(matches!(
+        plan.check(peak - 1),
+        Err(StormError::OutOfMemory { bytes, .. }) if bytes == peak
+    ));
+    assert!(h.to_vec() == expected[3]);
+
+    // Intermediates still held by a tensor aren't written over
+    let mut hs = vec![a.clone()];
+    for _ in 0..4 {
+        hs.push(hs.last().unwrap().matmul(&a).relu());
+    }
+    hs[4].realize();
+    for (h, e) in izip!(hs[1..].iter(), expected.iter()) {
+        assert!(h.to_vec() == *e);
+    }
+}
+
+// DEVICE is read once per process, so this runs tests of this file again in a child process with
+// it set
+fn run_on(device: &str, tests: &[&str]) {
+    let out = std::process::Command::new(std::env::current_exe().unwrap())
+        .env("DEVICE", device)
+        .args(tests)
+        .arg("--exact")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(out.status.success(), "{stdout}{}", String::from_utf8_lossy(&out.stderr));
+    assert!(stdout.contains(&format!("test result: ok. {} passed", tests.len())), "{stdout}");
+}
+
+#[test]
+fn clang_backend() {
+    run_on("CLANG", &["sum_axis", "matmul", "conv2d", "test_softmax", "max_test", "pad2d"]);
+}
+
+#[test]
+fn interpreter_backend() {
+    let tests = ["sum_axis", "matmul", "pool", "conv2d", "lt", "where_test", "max_test", "pooling", "sort"];
+    run_on("INTERPRETER", &tests);
 }
@@ -0,0 +1,311 @@
+use storm::arg::Arg;
+use storm::codegen::linearizer::{UOp, UOps};
+use storm::codegen::optimizer::*;
+use storm::ops::{Binary, OpType, Ternary, Unary};
+use storm::prelude::*;
+
+// Builds a kernel uop by uop, each call appends and returns the new uop
+#[derive(Default)]
+struct Kernel(Vec<UOp>);
+
+impl Kernel {
+    fn push(&mut self, uop: UOps, dtype: Option<Dtype>, vin: &[&UOp], args: Vec<Arg>) -> UOp {
+        self.0.push(UOp::new(uop, dtype, v![(*x).clone(), for x in vin], args));
+        self.0.last().unwrap().clone()
+    }
+
+    fn global(&mut self, name: &str) -> UOp {
+        self.push(UOps::DEFINE_GLOBAL, Some(float32), &[], vec![Arg::Str(name.into())])
+    }
+
+    fn special(&mut self, name: &str) -> UOp {
+        self.push(UOps::SPECIAL, Some(int32), &[], vec![Arg::Usize(0), Arg::Str(name.into()), Arg::Usize(16)])
+    }
+
+    fn c(&mut self, val: &str, dtype: Dtype) -> UOp {
+        self.push(UOps::CONST, Some(dtype), &[], vec![Arg::Str(val.into())])
+    }
+
+    fn alu(&mut self, op: OpType, dtype: Dtype, vin: &[&UOp]) -> UOp {
+        self.push(UOps::ALU, Some(dtype), vin, vec![Arg::OpType(op)])
+    }
+
+    fn store(&mut self, buf: &UOp, idx: &UOp, val: &UOp) -> UOp {
+        self.push(UOps::STORE, None, &[buf, idx, val], vec![])
+    }
+}
+
+fn check(pass: UOpPass, k: &Kernel, golden: &str) {
+    let out = uops_listing(&pass(&k.0));
+    let lines = |s: &str| v![l.trim().to_string(), for l in s.lines(), if !l.trim().is_empty()];
+    assert!(lines(&out) == lines(golden), "\n{}\nbecame\n{out}", uops_listing(&k.0));
+}
+
+#[test]
+fn fold_constants_pass() {
+    let mut k = Kernel::default();
+    let (buf, idx) = (k.global("data0"), k.c("0", int32));
+    let (a, b) = (k.c("2.0", float32), k.c("3.0", float32));
+    let prod = k.alu(OpType::Binary(Binary::Mul), float32, &[&a, &b]);
+    let neg = k.alu(OpType::Unary(Unary::Neg), float32, &[&prod]);
+    k.store(&buf, &idx, &neg);
+    // Ints truncate like C, division by zero stays for the device
+    let (a, b) = (k.c("-7", int32), k.c("2", int32));
+    let div = k.alu(OpType::Binary(Binary::Div), int32, &[&a, &b]);
+    let zero = k.c("0", int32);
+    let by_zero = k.alu(OpType::Binary(Binary::Div), int32, &[&a, &zero]);
+    let lt = k.alu(OpType::Binary(Binary::Cmplt), int32, &[&b, &a]);
+    k.store(&buf, &div, &by_zero);
+    k.store(&buf, &lt, &idx);
+    // Unsigned bit ops wrap like C
+    let (x, four) = (k.c("4026531841", uint32), k.c("4", uint32));
+    let shl = k.alu(OpType::Binary(Binary::Shl), uint32, &[&x, &four]);
+    let xor = k.alu(OpType::Binary(Binary::Xor), uint32, &[&shl, &four]);
+    let or = k.alu(OpType::Binary(Binary::Or), uint32, &[&xor, &x]);
+    k.store(&buf, &idx, &or);
+    // u64 past i64::MAX isn't clamped
+    let (top, sixty) = (k.c("9223372036854775808", uint64), k.c("60", uint64));
+    let shr = k.alu(OpType::Binary(Binary::Shr), uint64, &[&top, &sixty]);
+    k.store(&buf, &idx, &shr);
+    check(fold_constants, &k, "
+          0 DEFINE_GLOBAL f32   [] data0
+          1 CONST         i32   [] 0
+          2 CONST         f32   [] -6.0
+          3 STORE               [0, 1, 2]
+          4 CONST         i32   [] -7
+          5 CONST         i32   [] -3
+          6 CONST         i32   [] 0
+          7 ALU           i32   [4, 6] Div
+          8 CONST         i32   [] 0
+          9 STORE               [0, 5, 7]
+         10 STORE               [0, 8, 1]
+         11 CONST         u32   [] 4026531861
+         12 STORE               [0, 1, 11]
+         13 CONST         u64   [] 8
+         14 STORE               [0, 1, 13]
+    ");
+}
+
+#[test]
+fn simplify_identities_pass() {
+    let mut k = Kernel::default();
+    let (buf, gidx) = (k.global("data0"), k.special("gidx0"));
+    let x = k.push(UOps::LOAD, Some(float32), &[&buf, &gidx], vec![]);
+    let (zero, one) = (k.c("0.0", float32), k.c("1.0", float32));
+    let a = k.alu(OpType::Binary(Binary::Add), float32, &[&zero, &x]);
+    let a = k.alu(OpType::Binary(Binary::Mul), float32, &[&a, &one]);
+    let a = k.alu(OpType::Binary(Binary::Sub), float32, &[&a, &zero]);
+    let a = k.alu(OpType::Binary(Binary::Div), float32, &[&a, &one]);
+    let a = k.alu(OpType::Binary(Binary::Max), float32, &[&a, &a]);
+    let a = k.alu(OpType::Unary(Unary::Neg), float32, &[&a]);
+    let a = k.alu(OpType::Unary(Unary::Neg), float32, &[&a]);
+    let t = k.c("1", _bool);
+    let a = k.alu(OpType::Ternary(Ternary::Where), float32, &[&t, &a, &zero]);
+    k.store(&buf, &gidx, &a);
+    // x*0 only folds for ints, an int plus a float zero is a float
+    let i0 = k.c("0", int32);
+    let m = k.alu(OpType::Binary(Binary::Mul), int32, &[&gidx, &i0]);
+    let f = k.alu(OpType::Binary(Binary::Add), float32, &[&gidx, &zero]);
+    let xz = k.alu(OpType::Binary(Binary::Mul), float32, &[&x, &zero]);
+    let w = k.alu(OpType::Ternary(Ternary::Where), float32, &[&x, &f, &f]);
+    k.store(&buf, &m, &w);
+    k.store(&buf, &gidx, &xz);
+    check(simplify_identities, &k, "
+          0 DEFINE_GLOBAL f32   [] data0
+          1 SPECIAL       i32   [] 0 gidx0 16
+          2 LOAD          f32   [0, 1]
+          3 CONST         f32   [] 0.0
+          4 STORE               [0, 1, 2]
+          5 CONST         i32   [] 0
+          6 ALU           f32   [1, 3] Add
+          7 ALU           f32   [2, 3] Mul
+          8 STORE               [0, 5, 6]
+          9 STORE               [0, 1, 7]
+    ");
+}
+
+#[test]
+fn reduce_strength_pass() {
+    let mut k = Kernel::default();
+    let (buf, gidx) = (k.global("data0"), k.special("gidx0"));
+    let (four, eight, three) = (k.c("4", int32), k.c("8", int32), k.c("3", int32));
+    let div = k.alu(OpType::Binary(Binary::Div), int32, &[&gidx, &four]);
+    let md = k.alu(OpType::Binary(Binary::Mod), int32, &[&gidx, &eight]);
+    let idx = k.alu(OpType::Binary(Binary::Add), int32, &[&div, &md]);
+    // Shifting a negative int would round down instead of toward zero
+    let neg = k.alu(OpType::Unary(Unary::Neg), int32, &[&gidx]);
+    let neg_div = k.alu(OpType::Binary(Binary::Div), int32, &[&neg, &four]);
+    let odd = k.alu(OpType::Binary(Binary::Mod), int32, &[&gidx, &three]);
+    let x = k.push(UOps::LOAD, Some(float32), &[&buf, &idx], vec![]);
+    let (quarter, third) = (k.c("4.0", float32), k.c("3.0", float32));
+    let a = k.alu(OpType::Binary(Binary::Div), float32, &[&x, &quarter]);
+    let b = k.alu(OpType::Binary(Binary::Div), float32, &[&x, &third]);
+    k.store(&buf, &neg_div, &a);
+    k.store(&buf, &odd, &b);
+    check(reduce_strength, &k, "
+          0 DEFINE_GLOBAL f32   [] data0
+          1 SPECIAL       i32   [] 0 gidx0 16
+          2 CONST         i32   [] 4
+          3 CONST         i32   [] 3
+          4 CONST         i32   [] 2
+          5 ALU           i32   [1, 4] Shr
+          6 CONST         i32   [] 7
+          7 ALU           i32   [1, 6] And
+          8 ALU           i32   [5, 7] Add
+          9 ALU           i32   [1] Neg
+         10 ALU           i32   [9, 2] Div
+         11 ALU           i32   [1, 3] Mod
+         12 LOAD          f32   [0, 8]
+         13 CONST         f32   [] 3.0
+         14 CONST         f32   [] 0.25
+         15 ALU           f32   [12, 14] Mul
+         16 ALU           f32   [12, 13] Div
+         17 STORE               [0, 10, 15]
+         18 STORE               [0, 11, 16]
+    ");
+
+    // 2^-15 is subnormal in f16, so dividing by 2^15 stays a division
+    let mut k = Kernel::default();
+    let (buf, gidx) = (k.global("data0"), k.special("gidx0"));
+    let x = k.push(UOps::LOAD, Some(float16), &[&buf, &gidx], vec![]);
+    let (big, small) = (k.c("32768.0", float16), k.c("16384.0", float16));
+    let a = k.alu(OpType::Binary(Binary::Div), float16, &[&x, &big]);
+    let b = k.alu(OpType::Binary(Binary::Div), float16, &[&x, &small]);
+    k.store(&buf, &gidx, &a);
+    k.store(&buf, &gidx, &b);
+    check(reduce_strength, &k, "
+          0 DEFINE_GLOBAL f32   [] data0
+          1 SPECIAL       i32   [] 0 gidx0 16
+          2 LOAD          f16   [0, 1]
+          3 CONST         f16   [] 32768.0
+          4 ALU           f16   [2, 3] Div
+          5 CONST         f16   [] 6.103515625e-5
+          6 ALU           f16   [2, 5] Mul
+          7 STORE               [0, 1, 4]
+          8 STORE               [0, 1, 6]
+    ");
+}
+
+#[test]
+fn eliminate_common_pass() {
+    let mut k = Kernel::default();
+    let (buf, gidx) = (k.global("data0"), k.special("gidx0"));
+    let (two, two_again) = (k.c("2", int32), k.c("2", int32));
+    let a = k.alu(OpType::Binary(Binary::Mul), int32, &[&gidx, &two]);
+    let b = k.alu(OpType::Binary(Binary::Mul), int32, &[&gidx, &two_again]);
+    let (start, end) = (k.c("0", int32), k.c("4", int32));
+    let lp = k.push(UOps::LOOP, Some(int32), &[&start, &end], vec![]);
+    let inner = k.alu(OpType::Binary(Binary::Mul), int32, &[&gidx, &two]);
+    let idx = k.alu(OpType::Binary(Binary::Add), int32, &[&lp, &inner]);
+    let x = k.push(UOps::LOAD, Some(float32), &[&buf, &idx], vec![]);
+    let y = k.push(UOps::LOAD, Some(float32), &[&buf, &idx], vec![]);
+    k.store(&buf, &a, &x);
+    k.store(&buf, &b, &y);
+    let in_loop = k.alu(OpType::Binary(Binary::Add), int32, &[&gidx, &end]);
+    k.store(&buf, &in_loop, &x);
+    k.push(UOps::END, None, &[&lp], vec![]);
+    // Computed inside the loop, which doesn't cover what comes after it
+    let after = k.alu(OpType::Binary(Binary::Add), int32, &[&gidx, &end]);
+    k.store(&buf, &after, &y);
+    check(eliminate_common, &k, "
+          0 DEFINE_GLOBAL f32   [] data0
+          1 SPECIAL       i32   [] 0 gidx0 16
+          2 CONST         i32   [] 2
+          3 ALU           i32   [1, 2] Mul
+          4 CONST         i32   [] 0
+          5 CONST         i32   [] 4
+          6 LOOP          i32   [4, 5]
+          7 ALU           i32   [6, 3] Add
+          8 LOAD          f32   [0, 7]
+          9 LOAD          f32   [0, 7]
+         10 STORE               [0, 3, 8]
+         11 STORE               [0, 3, 9]
+         12 ALU           i32   [1, 5] Add
+         13 STORE               [0, 12, 8]
+         14 END                 [6]
+         15 ALU           i32   [1, 5] Add
+         16 STORE               [0, 15, 9]
+    ");
+}
+
+#[test]
+fn hoist_invariants_pass() {
+    let mut k = Kernel::default();
+    let (out, buf, gidx) = (k.global("data0"), k.global("data1"), k.special("gidx0"));
+    let acc = k.push(UOps::DEFINE_ACC, Some(float32), &[], vec![Arg::Str("0.0".into())]);
+    let (start, end) = (k.c("0", int32), k.c("4", int32));
+    let outer = k.push(UOps::LOOP, Some(int32), &[&start, &end], vec![]);
+    let inner = k.push(UOps::LOOP, Some(int32), &[&start, &end], vec![]);
+    let row = k.alu(OpType::Binary(Binary::Mul), int32, &[&gidx, &end]);
+    let col = k.alu(OpType::Binary(Binary::Add), int32, &[&row, &outer]);
+    let idx = k.alu(OpType::Binary(Binary::Add), int32, &[&col, &inner]);
+    let x = k.push(UOps::LOAD, Some(float32), &[&buf, &idx], vec![]);
+    let step = k.c("0.5", float32);
+    let sum = k.alu(OpType::Binary(Binary::Add), float32, &[&acc, &step]);
+    let sum = k.alu(OpType::Binary(Binary::Add), float32, &[&sum, &x]);
+    let phi = k.push(UOps::PHI, Some(float32), &[&acc, &sum, &inner], vec![]);
+    k.push(UOps::END, None, &[&inner], vec![]);
+    k.push(UOps::END, None, &[&outer], vec![]);
+    // Nothing leaves an IF, even when it doesn't depend on the condition
+    let cond = k.alu(OpType::Binary(Binary::Cmplt), int32, &[&gidx, &end]);
+    let branch = k.push(UOps::IF, None, &[&cond], vec![]);
+    let two = k.c("2", int32);
+    let dst = k.alu(OpType::Binary(Binary::Mul), int32, &[&gidx, &two]);
+    k.store(&out, &dst, &phi);
+    k.push(UOps::END, None, &[&branch], vec![]);
+    check(hoist_invariants, &k, "
+          0 DEFINE_GLOBAL f32   [] data0
+          1 DEFINE_GLOBAL f32   [] data1
+          2 SPECIAL       i32   [] 0 gidx0 16
+          3 DEFINE_ACC    f32   [] 0.0
+          4 CONST         i32   [] 0
+          5 CONST         i32   [] 4
+          6 ALU           i32   [2, 5] Mul
+          7 CONST         f32   [] 0.5
+          8 LOOP          i32   [4, 5]
+          9 ALU           i32   [6, 8] Add
+         10 LOOP          i32   [4, 5]
+         11 ALU           i32   [9, 10] Add
+         12 LOAD          f32   [1, 11]
+         13 ALU           f32   [3, 7] Add
+         14 ALU           f32   [13, 12] Add
+         15 PHI           f32   [3, 14, 10]
+         16 END                 [10]
+         17 END                 [8]
+         18 ALU           i32   [2, 5] Cmplt
+         19 IF                  [18]
+         20 CONST         i32   [] 2
+         21 ALU           i32   [2, 20] Mul
+         22 STORE               [0, 21, 15]
+         23 END                 [19]
+    ");
+}
+
+#[test]
+fn simplify_uops_pipeline() {
+    // (gidx*1 + 2*3) / 2, every pass but hoisting has something to do
+    let mut k = Kernel::default();
+    let (buf, gidx) = (k.global("data0"), k.special("gidx0"));
+    let (one, two, three) = (k.c("1", int32), k.c("2", int32), k.c("3", int32));
+    let a = k.alu(OpType::Binary(Binary::Mul), int32, &[&gidx, &one]);
+    let b = k.alu(OpType::Binary(Binary::Mul), int32, &[&two, &three]);
+    let sum = k.alu(OpType::Binary(Binary::Add), int32, &[&a, &b]);
+    let idx = k.alu(OpType::Binary(Binary::Div), int32, &[&sum, &two]);
+    let x = k.push(UOps::LOAD, Some(float32), &[&buf, &idx], vec![]);
+    let y = k.push(UOps::LOAD, Some(float32), &[&buf, &sum], vec![]);
+    let s = k.alu(OpType::Binary(Binary::Add), float32, &[&x, &y]);
+    let again = k.alu(OpType::Binary(Binary::Div), int32, &[&sum, &two]);
+    k.store(&buf, &again, &s);
+    check(simplify_uops, &k, "
+          0 DEFINE_GLOBAL f32   [] data0
+          1 SPECIAL       i32   [] 0 gidx0 16
+          2 CONST         i32   [] 6
+          3 ALU           i32   [1, 2] Add
+          4 CONST         i32   [] 1
+          5 ALU           i32   [3, 4] Shr
+          6 LOAD          f32   [0, 5]
+          7 LOAD          f32   [0, 3]
+          8 ALU           f32   [6, 7] Add
+          9 STORE               [0, 5, 8]
+    ");
+}